@@ -0,0 +1,247 @@
+use anyhow::anyhow;
+use consulrs::api::{features::FeaturedEndpoint, kv::responses::ReadKeyResponse, Features};
+use derive_builder::Builder;
+use rustify_derive::Endpoint;
+use std::fmt;
+
+use crate::error::Result;
+
+/// A consul key referenced by a `k8s-consul-mutator.io/key-*` annotation.
+///
+/// The key path may be preceded by `:` separated qualifiers that select the
+/// datacenter, the enterprise admin partition, and the enterprise namespace
+/// that the key is read from, such as `dc/dc1:ns/team-a:app/config`. Keys
+/// without qualifiers are read from the datacenter, partition, and namespace
+/// of the consul agent.
+///
+/// Keys in a named cluster from `CONSUL_CLUSTERS` start with the name of the
+/// cluster, such as `legacy://dc/dc1:app/config`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyAddress {
+    pub cluster: Option<String>,
+    pub datacenter: Option<String>,
+    pub partition: Option<String>,
+    pub namespace: Option<String>,
+    pub path: String,
+}
+
+impl KeyAddress {
+    /// Parses an annotation value. Leading segments that start with a
+    /// qualifier prefix are qualifiers and the rest of the value is the key
+    /// path.
+    pub fn parse(value: &str) -> Result<Self> {
+        let mut address = KeyAddress::default();
+        let mut rest = value;
+
+        if let Some((cluster, path)) = rest.split_once("://") {
+            if !is_cluster(cluster) {
+                return Err(anyhow!("invalid consul cluster: {value}"));
+            }
+            address.cluster = Some(cluster.to_string());
+            rest = path;
+        }
+
+        while let Some((qualifier, path)) = rest.split_once(':') {
+            if let Some(datacenter) = qualifier.strip_prefix("dc/") {
+                if !is_name(datacenter) || address.datacenter.is_some() {
+                    return Err(anyhow!("invalid consul datacenter: {value}"));
+                }
+                address.datacenter = Some(datacenter.to_string());
+            } else if let Some(partition) = qualifier.strip_prefix("partition/") {
+                if !is_name(partition) || address.partition.is_some() {
+                    return Err(anyhow!("invalid consul admin partition: {value}"));
+                }
+                address.partition = Some(partition.to_string());
+            } else if let Some(namespace) = qualifier.strip_prefix("ns/") {
+                if namespace.is_empty() || address.namespace.is_some() {
+                    return Err(anyhow!("invalid consul namespace: {value}"));
+                }
+                address.namespace = Some(namespace.to_string());
+            } else {
+                break;
+            }
+            rest = path;
+        }
+
+        if rest.is_empty() {
+            return Err(anyhow!("consul key path is empty: {value}"));
+        }
+        address.path = rest.to_string();
+        Ok(address)
+    }
+
+    /// Returns the address of another key in the same cluster, datacenter,
+    /// partition, and namespace, such as a key that was returned by a recursive read.
+    pub fn qualify(&self, path: &str) -> String {
+        KeyAddress {
+            path: path.to_string(),
+            ..self.clone()
+        }
+        .to_string()
+    }
+
+    /// Returns a read request for the key in its datacenter, partition, and
+    /// namespace.
+    pub fn read_request(&self) -> ReadKeyRequestBuilder {
+        let mut builder = ReadKeyRequestBuilder::default();
+        builder.key(self.path.as_str());
+        if let Some(datacenter) = self.datacenter.as_deref() {
+            builder.dc(datacenter);
+        }
+        if let Some(partition) = self.partition.as_deref() {
+            builder.partition(partition);
+        }
+        if let Some(namespace) = self.namespace.as_deref() {
+            builder.ns(namespace);
+        }
+        builder
+    }
+}
+
+/// The canonical form of the address, which is how keys are stored in the
+/// key manager. The same path in two clusters or datacenters is two different
+/// keys.
+impl fmt::Display for KeyAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(cluster) = self.cluster.as_deref() {
+            write!(f, "{cluster}://")?;
+        }
+        if let Some(datacenter) = self.datacenter.as_deref() {
+            write!(f, "dc/{datacenter}:")?;
+        }
+        if let Some(partition) = self.partition.as_deref() {
+            write!(f, "partition/{partition}:")?;
+        }
+        if let Some(namespace) = self.namespace.as_deref() {
+            write!(f, "ns/{namespace}:")?;
+        }
+        write!(f, "{}", self.path)
+    }
+}
+
+/// A key read request. The request of the consul client can't select an
+/// admin partition, so this one is used instead.
+#[derive(Builder, Debug, Default, Endpoint)]
+#[endpoint(path = "kv/{self.key}", response = "Vec<ReadKeyResponse>")]
+#[builder(setter(into, strip_option), default)]
+pub struct ReadKeyRequest {
+    #[endpoint(skip)]
+    pub features: Option<Features>,
+    #[endpoint(skip)]
+    pub key: String,
+    #[endpoint(query)]
+    pub dc: Option<String>,
+    #[endpoint(query)]
+    pub partition: Option<String>,
+    #[endpoint(query)]
+    pub ns: Option<String>,
+    #[endpoint(query)]
+    pub recurse: Option<bool>,
+}
+
+impl FeaturedEndpoint for ReadKeyRequest {
+    fn features(&self) -> Option<Features> {
+        self.features.clone()
+    }
+}
+
+fn is_cluster(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+}
+
+fn is_name(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Returns the canonical form of an annotation value.
+pub fn canonical_key(value: &str) -> Result<String> {
+    Ok(KeyAddress::parse(value)?.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustify::Endpoint;
+
+    #[test]
+    fn parse_addresses() {
+        let address = KeyAddress::parse("dc/dc1:ns/team-a:app/config").unwrap();
+        assert_eq!(address.datacenter.as_deref(), Some("dc1"));
+        assert_eq!(address.namespace.as_deref(), Some("team-a"));
+        assert_eq!(address.path, "app/config");
+
+        let address = KeyAddress::parse("app/config").unwrap();
+        assert_eq!(
+            address,
+            KeyAddress {
+                path: "app/config".to_string(),
+                ..Default::default()
+            }
+        );
+
+        // Qualifiers may be given in any order.
+        assert_eq!(
+            canonical_key("ns/team-a:partition/eu:dc/dc1:app/config").unwrap(),
+            "dc/dc1:partition/eu:ns/team-a:app/config"
+        );
+        assert_eq!(
+            KeyAddress::parse("dc/dc1:app/config")
+                .unwrap()
+                .qualify("app/other"),
+            "dc/dc1:app/other"
+        );
+
+        // Segments that aren't qualifiers belong to the path.
+        assert_eq!(
+            KeyAddress::parse("app/config:v2").unwrap().path,
+            "app/config:v2"
+        );
+        assert_eq!(
+            KeyAddress::parse("service:web").unwrap().path,
+            "service:web"
+        );
+        assert_eq!(
+            KeyAddress::parse("dc/dc1:service:web").unwrap().path,
+            "service:web"
+        );
+
+        let address = KeyAddress::parse("legacy://dc/dc1:app/config").unwrap();
+        assert_eq!(address.cluster.as_deref(), Some("legacy"));
+        assert_eq!(address.datacenter.as_deref(), Some("dc1"));
+        assert_eq!(address.qualify("app/"), "legacy://dc/dc1:app/");
+
+        assert!(KeyAddress::parse("dc/dc1:").is_err());
+        assert!(KeyAddress::parse("dc/:app/config").is_err());
+        assert!(KeyAddress::parse("dc/dc1:dc/dc2:app/config").is_err());
+        assert!(KeyAddress::parse("Legacy://app/config").is_err());
+        assert!(KeyAddress::parse("ns/:app/config").is_err());
+        assert!(KeyAddress::parse("partition/:app/config").is_err());
+    }
+
+    #[test]
+    fn read_request_query() {
+        let request = KeyAddress::parse("dc/dc1:partition/eu:ns/team-a:app/config")
+            .unwrap()
+            .read_request()
+            .build()
+            .unwrap();
+        assert_eq!(request.path(), "kv/app/config");
+        let query = request.query().unwrap().unwrap();
+        assert!(query.contains("dc=dc1"));
+        assert!(query.contains("partition=eu"));
+        assert!(query.contains("ns=team-a"));
+
+        let request = KeyAddress::parse("app/config")
+            .unwrap()
+            .read_request()
+            .build()
+            .unwrap();
+        assert_eq!(request.query().unwrap().unwrap_or_default(), "");
+    }
+}
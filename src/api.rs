@@ -1,8 +1,10 @@
 use anyhow::anyhow;
 use axum::{
-    extract::{Json, State},
-    http::StatusCode,
-    response::IntoResponse,
+    body::Body,
+    extract::{Json, Query, State},
+    http::{header, Method, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
     routing::{get, post},
     Router,
 };
@@ -11,16 +13,118 @@ use kube::core::{
     admission::{AdmissionRequest, AdmissionResponse, AdmissionReview},
     DynamicObject, ResourceExt,
 };
+use serde::Deserialize;
 use serde_json::json;
+use std::collections::HashSet;
 use std::error::Error;
 use tower_http::trace::TraceLayer;
-use tracing::warn;
+use tracing::{debug, info, instrument, warn, Span};
 
+use crate::address::{canonical_key, KeyAddress};
+use crate::auth::Authorization;
+use crate::consul::{consistency_annotation, read_checksum, refresh_key, watch_target};
 use crate::error::{ConMutError, Result};
-use crate::state::{AppState, ConsulWatch};
+use crate::state::{AppState, ConsulWatch, DeploymentUpdate};
+use crate::telemetry::consul_key_link;
+
+/// Optional filters that can be applied to the debug routes.
+#[derive(Deserialize, Debug, Default)]
+pub struct DebugFilter {
+    pub namespace: Option<String>,
+    pub deployment: Option<String>,
+    pub consul_key: Option<String>,
+}
+
+impl DebugFilter {
+    fn matches(
+        &self,
+        namespace: Option<&str>,
+        deployment: Option<&str>,
+        consul_key: Option<&str>,
+    ) -> bool {
+        // Filters only apply to the fields that an item has.
+        fn check(filter: &Option<String>, value: Option<&str>) -> bool {
+            match (filter, value) {
+                (Some(expected), Some(value)) => expected == value,
+                _ => true,
+            }
+        }
+        check(&self.namespace, namespace)
+            && check(&self.deployment, deployment)
+            && check(&self.consul_key, consul_key)
+    }
+
+    /// Resolves the filter for items that only have a consul key or only have
+    /// a workload, through the subscriptions that match the filter.
+    async fn resolve(&self, state: &AppState) -> Result<ResolvedDebugFilter<'_>> {
+        let depth = state.settings().consul_watch_prefix_depth as usize;
+        let mut resolved = ResolvedDebugFilter {
+            filter: self,
+            depth,
+            consul_keys: HashSet::new(),
+            workloads: HashSet::new(),
+        };
+        for (subscription, consul_key) in state.key_manager.subscriptions().await? {
+            if !self.matches(
+                Some(&subscription.namespace),
+                Some(&subscription.deployment),
+                Some(&consul_key),
+            ) {
+                continue;
+            }
+            resolved
+                .consul_keys
+                .insert(watch_target(&consul_key, depth));
+            resolved.consul_keys.insert(consul_key);
+            resolved
+                .workloads
+                .insert((subscription.namespace, subscription.deployment));
+        }
+        Ok(resolved)
+    }
+}
+
+/// A `DebugFilter` with the consul keys and workloads of the subscriptions
+/// that match it. Namespace and deployment filters select the consul keys
+/// that the workloads subscribe to, and the consul key filter selects the
+/// workloads that subscribe to the key.
+struct ResolvedDebugFilter<'a> {
+    filter: &'a DebugFilter,
+    depth: usize,
+    consul_keys: HashSet<String>,
+    workloads: HashSet<(String, String)>,
+}
+
+impl ResolvedDebugFilter<'_> {
+    /// Returns true if a consul key, or a prefix that keys are watched
+    /// through, matches the filter.
+    fn matches_consul_key(&self, consul_key: &str) -> bool {
+        if self.filter.namespace.is_none() && self.filter.deployment.is_none() {
+            return match &self.filter.consul_key {
+                Some(expected) => {
+                    expected == consul_key || watch_target(expected, self.depth) == consul_key
+                }
+                None => true,
+            };
+        }
+        self.consul_keys.contains(consul_key)
+    }
+
+    /// Returns true if a workload matches the filter.
+    fn matches_workload(&self, namespace: &str, deployment: &str) -> bool {
+        if self.filter.consul_key.is_none() {
+            return self.filter.matches(Some(namespace), Some(deployment), None);
+        }
+        self.workloads
+            .contains(&(namespace.to_string(), deployment.to_string()))
+    }
+}
 
 async fn handle_index(State(state): State<AppState>) -> impl IntoResponse {
-    Json(json!({"version": state.settings.version}))
+    Json(json!({
+        "version": state.settings().version.clone(),
+        "leader": state.leadership.is_leader(),
+    }))
 }
 
 async fn handle_mutate(
@@ -48,6 +152,10 @@ async fn handle_mutate(
     Ok((StatusCode::OK, Json(real_res.clone()))) as Result<_, ConMutError>
 }
 
+#[instrument(
+    skip_all,
+    fields(namespace = %obj.namespace().unwrap_or_default(), deployment = %obj.name_any())
+)]
 async fn mutate(
     state: &AppState,
     res: AdmissionResponse,
@@ -67,38 +175,96 @@ async fn mutate(
         return Ok(res);
     }
 
+    if !state
+        .scope
+        .matches(&obj.namespace().unwrap_or_default(), obj.labels())
+        .await
+    {
+        return Ok(res);
+    }
+
+    let managed = state
+        .sharding
+        .owns(obj.namespace().unwrap_or_default().as_str());
+
     let mut patches = Vec::new();
 
     for found_key in found_keys {
         let key = found_key.replace("k8s-consul-mutator.io/key-", "");
 
-        let found_key_value = obj.annotations().get(found_key.as_str()).unwrap();
+        let address = match KeyAddress::parse(obj.annotations().get(found_key.as_str()).unwrap())
+            .and_then(|address| {
+                state.consul.get(address.cluster.as_deref())?;
+                Ok(address)
+            }) {
+            Ok(address) => address,
+            Err(err) => {
+                warn!("Error watching key: {err}");
+                continue;
+            }
+        };
+        let found_key_value = address.to_string();
 
-        if let Err(err) = state
-            .key_manager
-            .watch(
-                obj.namespace().unwrap(),
-                obj.name_any().clone(),
-                key.clone(),
-                found_key_value.clone(),
-            )
-            .await
-            .map_err(|err| anyhow!(err.to_string()))
-        {
-            warn!("Error watching key: {err}");
-        }
+        // Subscriptions are only created by the replica that owns the namespace.
+        if managed {
+            if let Err(err) = state
+                .key_manager
+                .watch(
+                    obj.namespace().unwrap(),
+                    obj.name_any().clone(),
+                    key.clone(),
+                    found_key_value.clone(),
+                )
+                .await
+                .map_err(|err| anyhow!(err.to_string()))
+            {
+                warn!("Error watching key: {err}");
+            }
 
-        let now = Utc::now();
+            let consistency =
+                consistency_annotation(obj.annotations(), &key).unwrap_or_else(|err| {
+                    warn!("Error watching key: {err}");
+                    None
+                });
+            if let Err(err) = state
+                .key_manager
+                .set_consistency_mode(
+                    obj.namespace().unwrap(),
+                    obj.name_any().clone(),
+                    key.clone(),
+                    consistency,
+                )
+                .await
+            {
+                warn!("Error watching key: {err}");
+            }
 
-        if let Err(err) = state
-            .consul_manager_tx
-            .send(ConsulWatch::Create(found_key_value.clone(), now))
-            .await
-        {
-            warn!("Error watching key: {err}");
+            let now = Utc::now();
+
+            if let Err(err) = state
+                .consul_manager_tx
+                .send(ConsulWatch::Create(found_key_value.clone(), now))
+                .await
+            {
+                warn!("Error watching key: {err}");
+            }
+            state
+                .span_links
+                .record(consul_key_link(&found_key_value), &Span::current());
         }
 
-        let checksum = state.key_manager.get(found_key_value.clone()).await?;
+        let mut checksum = state.key_manager.get(found_key_value.clone()).await?;
+        if checksum.is_none() && !(managed && state.leadership.is_leader()) {
+            // Key watchers only run on the leader and the replica that owns
+            // the namespace, so other replicas read the key directly.
+            checksum = match read_checksum(state, &found_key_value).await {
+                Ok((_, checksum)) => Some(checksum),
+                Err(err) => {
+                    warn!("Error reading key: {err}");
+                    None
+                }
+            };
+        }
         if checksum.is_some() {
             let checksum_value = checksum.unwrap();
             patches.push(json_patch::PatchOperation::Add(json_patch::AddOperation {
@@ -116,10 +282,632 @@ async fn mutate(
     Ok(res.with_patch(json_patch::Patch(patches))?)
 }
 
-pub fn build_router(shared_state: AppState) -> Router {
+async fn handle_debug_subscriptions(
+    State(state): State<AppState>,
+    Query(filter): Query<DebugFilter>,
+) -> Result<impl IntoResponse, ConMutError> {
+    let mut results = vec![];
+
+    for (subscription, consul_key) in state.key_manager.subscriptions().await? {
+        if !filter.matches(
+            Some(&subscription.namespace),
+            Some(&subscription.deployment),
+            Some(&consul_key),
+        ) {
+            continue;
+        }
+
+        let record = state.key_manager.get_record(consul_key.clone()).await?;
+
+        results.push(json!({
+            "namespace": subscription.namespace,
+            "deployment": subscription.deployment,
+            "config_key": subscription.config_key,
+            "consul_key": consul_key,
+            "checksum": record.as_ref().map(|r| r.checksum.clone()),
+            "changed": record.as_ref().map(|r| r.changed.to_rfc3339()),
+        }));
+    }
+
+    Ok(Json(json!({ "subscriptions": results })))
+}
+
+async fn handle_debug_history(
+    State(state): State<AppState>,
+    Query(filter): Query<DebugFilter>,
+) -> Result<impl IntoResponse, ConMutError> {
+    let mut results = vec![];
+
+    for (consul_key, change) in state.key_manager.history().await? {
+        if !filter.matches(None, None, Some(&consul_key)) {
+            continue;
+        }
+        // A change matches the namespace and deployment filters when one of
+        // its workloads does.
+        if (filter.namespace.is_some() || filter.deployment.is_some())
+            && !change.workloads.iter().any(|workload| {
+                let (namespace, deployment) = workload.split_once('/').unwrap_or(("", workload));
+                filter.matches(Some(namespace), Some(deployment), None)
+            })
+        {
+            continue;
+        }
+
+        results.push(json!({
+            "consul_key": consul_key,
+            "changed": change.changed.to_rfc3339(),
+            "modify_index": change.modify_index,
+            "previous": change.previous,
+            "checksum": change.checksum,
+            "workloads": change.workloads,
+        }));
+    }
+
+    Ok(Json(json!({ "history": results })))
+}
+
+async fn handle_debug_watchers(
+    State(state): State<AppState>,
+    Query(filter): Query<DebugFilter>,
+) -> Result<impl IntoResponse, ConMutError> {
+    let filter = filter.resolve(&state).await?;
+    let mut watchers: Vec<String> = state
+        .work_status
+        .running_watchers()
+        .into_iter()
+        .filter(|consul_key| filter.matches_consul_key(consul_key))
+        .collect();
+    watchers.sort();
+
+    Ok(Json(json!({ "watchers": watchers })))
+}
+
+async fn handle_debug_work(
+    State(state): State<AppState>,
+    Query(filter): Query<DebugFilter>,
+) -> Result<impl IntoResponse, ConMutError> {
+    let filter = filter.resolve(&state).await?;
+    let consul_watches: Vec<serde_json::Value> = state
+        .work_status
+        .pending_watches()
+        .into_iter()
+        .filter_map(|watch| {
+            let (action, consul_key, occurred) = match watch {
+                ConsulWatch::Create(key, occurred) => ("create", key, occurred),
+                ConsulWatch::Destroy(key, occurred) => ("destroy", key, occurred),
+            };
+            if !filter.matches_consul_key(&consul_key) {
+                return None;
+            }
+            Some(json!({
+                "action": action,
+                "consul_key": consul_key,
+                "occurred": occurred.to_rfc3339(),
+            }))
+        })
+        .collect();
+
+    let deployment_updates: Vec<serde_json::Value> = state
+        .work_status
+        .pending_updates()
+        .into_iter()
+        .filter(|update| filter.matches_workload(&update.namespace, &update.deployment))
+        .map(|update| {
+            json!({
+                "namespace": update.namespace,
+                "deployment": update.deployment,
+                "occurred": update.occurred.to_rfc3339(),
+            })
+        })
+        .collect();
+
+    let mut consul_queries_available = 0;
+    let mut cluster_queries_available = serde_json::Map::new();
+    for (cluster, client) in state.consul.all() {
+        match cluster {
+            Some(cluster) => {
+                cluster_queries_available.insert(cluster.to_string(), json!(client.available()));
+            }
+            None => consul_queries_available = client.available(),
+        }
+    }
+
+    Ok(Json(json!({
+        "consul_watches": consul_watches,
+        "deployment_updates": deployment_updates,
+        "consul_queries_available": consul_queries_available,
+        "consul_cluster_queries_available": cluster_queries_available,
+    })))
+}
+
+async fn handle_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.consul.limiter().render_metrics(),
+    )
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RefreshKeyRequest {
+    pub consul_key: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UpdateDeploymentRequest {
+    pub namespace: String,
+    pub deployment: String,
+}
+
+/// Rejects requests that the configured authenticator does not allow.
+async fn require_authorization(
+    State(state): State<AppState>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|value| value.to_string());
+
+    let verb = match *request.method() {
+        Method::GET | Method::HEAD => "get",
+        Method::POST => "create",
+        Method::PUT => "update",
+        Method::PATCH => "patch",
+        Method::DELETE => "delete",
+        _ => "unknown",
+    };
+    let path = request.uri().path().to_string();
+
+    match state
+        .authenticator
+        .authorize(token.as_deref(), &path, verb)
+        .await
+    {
+        Ok(Authorization::Allowed(caller)) => {
+            debug!("api request authorized: {caller} {verb} {path}");
+            next.run(request).await
+        }
+        Ok(Authorization::Unauthenticated) => {
+            (StatusCode::UNAUTHORIZED, "unauthorized").into_response()
+        }
+        Ok(Authorization::Forbidden(reason)) => (StatusCode::FORBIDDEN, reason).into_response(),
+        Err(err) => {
+            warn!("api request authorization error: {err}");
+            (StatusCode::INTERNAL_SERVER_ERROR, "authorization failed").into_response()
+        }
+    }
+}
+
+/// Returns the response of an admin request that this replica can't handle,
+/// so that the caller retries against the right replica.
+fn wrong_replica(status: StatusCode, reason: &str) -> Response {
+    (status, Json(json!({ "error": reason }))).into_response()
+}
+
+async fn handle_admin_refresh_key(
+    State(state): State<AppState>,
+    Json(payload): Json<RefreshKeyRequest>,
+) -> Result<Response, ConMutError> {
+    // Only the leader applies the deployment updates of changed keys.
+    if !state.leadership.is_leader() {
+        return Ok(wrong_replica(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "this replica isn't the leader",
+        ));
+    }
+
+    let consul_key = canonical_key(&payload.consul_key)?;
+    let refreshed = refresh_key(&state, &consul_key).await?;
+
+    info!(
+        "admin refreshed consul key: {} {}",
+        consul_key, refreshed.checksum
+    );
+
+    let subscribers: Vec<serde_json::Value> = refreshed
+        .subscribers
+        .iter()
+        .map(|subscriber| {
+            json!({
+                "namespace": subscriber.namespace,
+                "deployment": subscriber.deployment,
+                "config_key": subscriber.config_key,
+            })
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "consul_key": consul_key,
+        "modify_index": refreshed.modify_index,
+        "checksum": refreshed.checksum,
+        "changed": refreshed.changed,
+        "subscribers": subscribers,
+    }))
+    .into_response())
+}
+
+async fn handle_admin_update_deployment(
+    State(state): State<AppState>,
+    Json(payload): Json<UpdateDeploymentRequest>,
+) -> Result<Response, ConMutError> {
+    if !state.leadership.is_leader() {
+        return Ok(wrong_replica(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "this replica isn't the leader",
+        ));
+    }
+    if !state.sharding.owns(&payload.namespace) {
+        return Ok(wrong_replica(
+            StatusCode::CONFLICT,
+            "this replica doesn't own the namespace",
+        ));
+    }
+
+    // The update is queued as if it happened before the debounce window, so
+    // the updater applies it on its next pass.
+    let now = Utc::now();
+    let debounce = chrono::Duration::from_std(state.settings().update_debounce)
+        .map_err(|err| anyhow!(err.to_string()))?;
+    state
+        .deployment_update_tx
+        .send(DeploymentUpdate {
+            namespace: payload.namespace.clone(),
+            deployment: payload.deployment.clone(),
+            occurred: now - debounce - chrono::Duration::seconds(1),
+            status_only: false,
+        })
+        .await
+        .map_err(|err| anyhow!(err.to_string()))?;
+
+    info!(
+        "admin queued deployment update: {}/{}",
+        payload.namespace, payload.deployment
+    );
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({
+            "namespace": payload.namespace,
+            "deployment": payload.deployment,
+            "queued": now.to_rfc3339(),
+        })),
+    )
+        .into_response())
+}
+
+/// Builds the router for the index and admission routes.
+pub fn build_admission_router(shared_state: AppState) -> Router {
     Router::new()
         .route("/", get(handle_index))
         .route("/mutate", post(handle_mutate))
         .layer(TraceLayer::new_for_http())
         .with_state(shared_state)
 }
+
+/// Builds the router for the debug and admin routes. All routes require
+/// authorization.
+pub fn build_admin_router(shared_state: AppState) -> Router {
+    Router::new()
+        .route("/debug/subscriptions", get(handle_debug_subscriptions))
+        .route("/debug/history", get(handle_debug_history))
+        .route("/debug/watchers", get(handle_debug_watchers))
+        .route("/debug/work", get(handle_debug_work))
+        .route("/metrics", get(handle_metrics))
+        .route("/admin/consul/refresh", post(handle_admin_refresh_key))
+        .route(
+            "/admin/deployments/update",
+            post(handle_admin_update_deployment),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            require_authorization,
+        ))
+        .layer(TraceLayer::new_for_http())
+        .with_state(shared_state)
+}
+
+/// Builds a router with all routes.
+pub fn build_router(shared_state: AppState) -> Router {
+    build_admission_router(shared_state.clone()).merge(build_admin_router(shared_state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SettingsBuilder;
+    use crate::key_manager::ChecksumChange;
+    use crate::state::test_state;
+    use axum_test_helper::TestClient;
+    use serde_json::Value;
+    use std::collections::BTreeMap;
+    use std::net::{SocketAddr, TcpListener};
+
+    /// Serves a router on a local port and returns its address.
+    fn serve(app: Router) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind should succeed");
+        let addr = listener.local_addr().expect("local addr should exist");
+        tokio::spawn(async move {
+            axum::Server::from_tcp(listener)
+                .expect("server should start")
+                .serve(app.into_make_service())
+                .await
+                .expect("server should run");
+        });
+        addr
+    }
+
+    /// Starts a stand-in for consul that serves the `app/config` key.
+    fn consul_stand_in() -> SocketAddr {
+        serve(Router::new().route(
+            "/v1/kv/app/config",
+            get(|| async {
+                (
+                    [("X-Consul-Index", "7")],
+                    Json(json!([{
+                        "Key": "app/config",
+                        "Value": "aGVsbG8=",
+                        "CreateIndex": 1,
+                        "ModifyIndex": 7,
+                        "LockIndex": 0,
+                        "Flags": 0,
+                    }])),
+                )
+            }),
+        ))
+    }
+
+    #[tokio::test]
+    async fn admin_refresh_key() {
+        let addr = consul_stand_in();
+        let settings = SettingsBuilder::default()
+            .api_auth("none")
+            .consul_clusters(vec!["test".to_string()])
+            .consul_cluster_settings(BTreeMap::from([(
+                "consul_cluster_test_http_addr".to_string(),
+                format!("http://{addr}"),
+            )]))
+            .build()
+            .expect("settings should build");
+        let (state, mut updates, _watches) = test_state(settings, None).await;
+        state
+            .key_manager
+            .watch(
+                "demo".to_string(),
+                "app".to_string(),
+                "config".to_string(),
+                "test://app/config".to_string(),
+            )
+            .await
+            .expect("watch should succeed");
+
+        let client = TestClient::new(build_admin_router(state.clone()));
+        let response = client
+            .post("/admin/consul/refresh")
+            .json(&json!({"consul_key": "test://app/config"}))
+            .send()
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body: Value = response.json().await;
+        assert_eq!(body["consul_key"], "test://app/config");
+        assert_eq!(body["modify_index"], 7);
+        assert_eq!(body["changed"], true);
+        assert_eq!(
+            body["subscribers"],
+            json!([{"namespace": "demo", "deployment": "app", "config_key": "config"}])
+        );
+        assert_eq!(
+            state
+                .key_manager
+                .get("test://app/config".to_string())
+                .await
+                .expect("get should succeed"),
+            body["checksum"].as_str().map(str::to_string)
+        );
+
+        let update = updates.try_recv().expect("a deployment update is sent");
+        assert_eq!(update.namespace, "demo");
+        assert_eq!(update.deployment, "app");
+    }
+
+    #[tokio::test]
+    async fn admin_update_deployment() {
+        let settings = SettingsBuilder::default()
+            .api_auth("none")
+            .build()
+            .expect("settings should build");
+        let debounce = chrono::Duration::from_std(settings.update_debounce).unwrap();
+        let (state, mut updates, _watches) = test_state(settings, None).await;
+
+        let client = TestClient::new(build_admin_router(state));
+        let response = client
+            .post("/admin/deployments/update")
+            .json(&json!({"namespace": "demo", "deployment": "app"}))
+            .send()
+            .await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        // The update is already past the debounce window.
+        let update = updates.try_recv().expect("a deployment update is sent");
+        assert_eq!(update.namespace, "demo");
+        assert_eq!(update.deployment, "app");
+        assert!(!update.status_only);
+        assert!(update.occurred < Utc::now() - debounce);
+    }
+
+    #[tokio::test]
+    async fn admin_routes_require_the_right_replica() {
+        let settings = SettingsBuilder::default()
+            .api_auth("none")
+            .leader_election(true)
+            .build()
+            .expect("settings should build");
+        let (state, mut updates, _watches) = test_state(settings, None).await;
+        let client = TestClient::new(build_admin_router(state));
+
+        let response = client
+            .post("/admin/deployments/update")
+            .json(&json!({"namespace": "demo", "deployment": "app"}))
+            .send()
+            .await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let response = client
+            .post("/admin/consul/refresh")
+            .json(&json!({"consul_key": "app/config"}))
+            .send()
+            .await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // A sharded replica without members doesn't own any namespace.
+        let settings = SettingsBuilder::default()
+            .api_auth("none")
+            .sharding(true)
+            .build()
+            .expect("settings should build");
+        let (state, _sharded_updates, _watches) = test_state(settings, None).await;
+        let client = TestClient::new(build_admin_router(state));
+        let response = client
+            .post("/admin/deployments/update")
+            .json(&json!({"namespace": "demo", "deployment": "app"}))
+            .send()
+            .await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        assert!(updates.try_recv().is_err());
+    }
+
+    /// Returns a state with `demo/app` subscribed to `app/config` and
+    /// `demo/api` subscribed to `api/config`, and work pending for both.
+    async fn debug_state() -> AppState {
+        let settings = SettingsBuilder::default()
+            .api_auth("none")
+            .build()
+            .expect("settings should build");
+        let (state, _updates, _watches) = test_state(settings, None).await;
+        let now = Utc::now();
+        for deployment in ["app", "api"] {
+            let consul_key = format!("{deployment}/config");
+            state
+                .key_manager
+                .watch(
+                    "demo".to_string(),
+                    deployment.to_string(),
+                    "config".to_string(),
+                    consul_key.clone(),
+                )
+                .await
+                .expect("watch should succeed");
+            state
+                .key_manager
+                .record_change(
+                    consul_key,
+                    ChecksumChange {
+                        changed: now,
+                        modify_index: 1,
+                        previous: None,
+                        checksum: "abc123".to_string(),
+                        workloads: vec![format!("demo/{deployment}")],
+                    },
+                )
+                .await
+                .expect("record_change should succeed");
+        }
+        state.work_status.set_watches(
+            &HashSet::from(["app/config".to_string(), "api/config".to_string()]),
+            &HashSet::from([
+                ConsulWatch::Create("app/config".to_string(), now),
+                ConsulWatch::Create("api/config".to_string(), now),
+            ]),
+        );
+        state.work_status.set_updates(&HashSet::from([
+            DeploymentUpdate {
+                namespace: "demo".to_string(),
+                deployment: "app".to_string(),
+                occurred: now,
+                status_only: false,
+            },
+            DeploymentUpdate {
+                namespace: "demo".to_string(),
+                deployment: "api".to_string(),
+                occurred: now,
+                status_only: false,
+            },
+        ]));
+        state
+    }
+
+    async fn get_json(client: &TestClient, path: &str) -> Value {
+        let response = client.get(path).send().await;
+        assert_eq!(response.status(), StatusCode::OK);
+        response.json().await
+    }
+
+    #[tokio::test]
+    async fn debug_subscriptions_filters() {
+        let client = TestClient::new(build_admin_router(debug_state().await));
+
+        let body = get_json(&client, "/debug/subscriptions?deployment=app").await;
+        assert_eq!(body["subscriptions"].as_array().unwrap().len(), 1);
+        assert_eq!(body["subscriptions"][0]["consul_key"], "app/config");
+
+        let body = get_json(&client, "/debug/subscriptions?consul_key=api/config").await;
+        assert_eq!(body["subscriptions"].as_array().unwrap().len(), 1);
+        assert_eq!(body["subscriptions"][0]["deployment"], "api");
+    }
+
+    #[tokio::test]
+    async fn debug_history_filters() {
+        let client = TestClient::new(build_admin_router(debug_state().await));
+
+        let body = get_json(&client, "/debug/history?deployment=app").await;
+        assert_eq!(body["history"].as_array().unwrap().len(), 1);
+        assert_eq!(body["history"][0]["consul_key"], "app/config");
+
+        let body = get_json(&client, "/debug/history?consul_key=api/config").await;
+        assert_eq!(body["history"].as_array().unwrap().len(), 1);
+        assert_eq!(body["history"][0]["workloads"], json!(["demo/api"]));
+    }
+
+    #[tokio::test]
+    async fn debug_watchers_filters() {
+        let client = TestClient::new(build_admin_router(debug_state().await));
+
+        let body = get_json(&client, "/debug/watchers").await;
+        assert_eq!(body["watchers"], json!(["api/config", "app/config"]));
+
+        let body = get_json(&client, "/debug/watchers?deployment=app").await;
+        assert_eq!(body["watchers"], json!(["app/config"]));
+
+        let body = get_json(&client, "/debug/watchers?namespace=other").await;
+        assert_eq!(body["watchers"], json!([]));
+
+        let body = get_json(&client, "/debug/watchers?consul_key=api/config").await;
+        assert_eq!(body["watchers"], json!(["api/config"]));
+    }
+
+    #[tokio::test]
+    async fn debug_work_filters() {
+        let client = TestClient::new(build_admin_router(debug_state().await));
+
+        let body = get_json(&client, "/debug/work?deployment=app").await;
+        assert_eq!(
+            body["consul_watches"],
+            json!([{
+                "action": "create",
+                "consul_key": "app/config",
+                "occurred": body["consul_watches"][0]["occurred"],
+            }])
+        );
+        assert_eq!(body["deployment_updates"].as_array().unwrap().len(), 1);
+        assert_eq!(body["deployment_updates"][0]["deployment"], "app");
+
+        let body = get_json(&client, "/debug/work?consul_key=api/config").await;
+        assert_eq!(body["consul_watches"].as_array().unwrap().len(), 1);
+        assert_eq!(body["consul_watches"][0]["consul_key"], "api/config");
+        assert_eq!(body["deployment_updates"].as_array().unwrap().len(), 1);
+        assert_eq!(body["deployment_updates"][0]["deployment"], "api");
+    }
+}
@@ -0,0 +1,336 @@
+use anyhow::anyhow;
+use async_trait::async_trait;
+use k8s_openapi::api::{
+    authentication::v1::{TokenReview, TokenReviewSpec},
+    authorization::v1::{NonResourceAttributes, SubjectAccessReview, SubjectAccessReviewSpec},
+};
+use kube::{
+    api::{Api, PostParams},
+    Client,
+};
+use subtle::ConstantTimeEq;
+
+use crate::error::Result;
+
+/// The outcome of checking a caller against a route.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum Authorization {
+    /// The caller is allowed. The value is the name of the caller.
+    Allowed(String),
+
+    /// The caller could not be identified.
+    Unauthenticated,
+
+    /// The caller was identified but is not allowed. The value is the reason.
+    Forbidden(String),
+}
+
+/// Authenticator is an interface for checking callers of the non-admission
+/// HTTP API.
+#[async_trait]
+pub trait Authenticator: Sync + Send {
+    /// Checks if the bearer token is allowed to perform the verb on the path.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The bearer token of the request, if any.
+    /// * `path` - The path of the request.
+    /// * `verb` - The lowercase kubernetes verb of the request, e.g. `get` or `create`.
+    async fn authorize(&self, token: Option<&str>, path: &str, verb: &str)
+        -> Result<Authorization>;
+}
+
+/// Allows all requests.
+pub struct NullAuthenticator;
+
+#[async_trait]
+impl Authenticator for NullAuthenticator {
+    async fn authorize(
+        &self,
+        _token: Option<&str>,
+        _path: &str,
+        _verb: &str,
+    ) -> Result<Authorization> {
+        Ok(Authorization::Allowed("anonymous".to_string()))
+    }
+}
+
+/// Allows requests with a bearer token that matches a static token. When the
+/// static token is empty, all requests are rejected.
+pub struct StaticTokenAuthenticator {
+    token: String,
+}
+
+#[async_trait]
+impl Authenticator for StaticTokenAuthenticator {
+    async fn authorize(
+        &self,
+        token: Option<&str>,
+        _path: &str,
+        _verb: &str,
+    ) -> Result<Authorization> {
+        if self.token.is_empty() {
+            return Ok(Authorization::Forbidden(
+                "admin routes are disabled".to_string(),
+            ));
+        }
+        match token {
+            // The comparison takes the same time wherever the tokens differ,
+            // so response times don't reveal a prefix of the token.
+            Some(token) if bool::from(token.as_bytes().ct_eq(self.token.as_bytes())) => {
+                Ok(Authorization::Allowed("admin".to_string()))
+            }
+            _ => Ok(Authorization::Unauthenticated),
+        }
+    }
+}
+
+/// Authenticates bearer tokens with a kubernetes TokenReview and authorizes
+/// the authenticated user with a non-resource SubjectAccessReview for the
+/// path and verb of the request.
+pub struct KubernetesAuthenticator {
+    client: Client,
+}
+
+impl KubernetesAuthenticator {
+    pub fn new(client: Client) -> Self {
+        Self { client }
+    }
+}
+
+#[async_trait]
+impl Authenticator for KubernetesAuthenticator {
+    async fn authorize(
+        &self,
+        token: Option<&str>,
+        path: &str,
+        verb: &str,
+    ) -> Result<Authorization> {
+        let token = match token {
+            Some(token) if !token.is_empty() => token,
+            _ => return Ok(Authorization::Unauthenticated),
+        };
+
+        let token_reviews: Api<TokenReview> = Api::all(self.client.clone());
+        let token_review = token_reviews
+            .create(
+                &PostParams::default(),
+                &TokenReview {
+                    spec: TokenReviewSpec {
+                        token: Some(token.to_string()),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        let user = match token_review.status {
+            Some(status) if status.authenticated.unwrap_or(false) => status.user,
+            _ => return Ok(Authorization::Unauthenticated),
+        };
+        let user = match user {
+            Some(user) if user.username.is_some() => user,
+            _ => return Ok(Authorization::Unauthenticated),
+        };
+        let username = user.username.clone().unwrap_or_default();
+
+        let access_reviews: Api<SubjectAccessReview> = Api::all(self.client.clone());
+        let access_review = access_reviews
+            .create(
+                &PostParams::default(),
+                &SubjectAccessReview {
+                    spec: SubjectAccessReviewSpec {
+                        user: user.username,
+                        uid: user.uid,
+                        groups: user.groups,
+                        extra: user.extra,
+                        non_resource_attributes: Some(NonResourceAttributes {
+                            path: Some(path.to_string()),
+                            verb: Some(verb.to_string()),
+                        }),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        match access_review.status {
+            Some(status) if status.allowed => Ok(Authorization::Allowed(username)),
+            Some(status) => Ok(Authorization::Forbidden(
+                status
+                    .reason
+                    .unwrap_or(format!("{username} may not {verb} {path}")),
+            )),
+            None => Ok(Authorization::Forbidden(format!(
+                "{username} may not {verb} {path}"
+            ))),
+        }
+    }
+}
+
+pub async fn get_authenticator(
+    api_auth: &str,
+    admin_token: &str,
+) -> Result<Box<dyn Authenticator>> {
+    match api_auth {
+        "none" => Ok(Box::new(NullAuthenticator) as Box<dyn Authenticator>),
+
+        "token" => Ok(Box::new(StaticTokenAuthenticator {
+            token: admin_token.to_string(),
+        }) as Box<dyn Authenticator>),
+
+        "kubernetes" => {
+            let client = Client::try_default().await?;
+            Ok(Box::new(KubernetesAuthenticator::new(client)) as Box<dyn Authenticator>)
+        }
+
+        _ => Err(anyhow!("unknown api auth type: {api_auth}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::post, Json, Router};
+    use serde_json::{json, Value};
+    use std::net::{SocketAddr, TcpListener};
+
+    async fn token_reviews(Json(review): Json<Value>) -> Json<Value> {
+        let token = review["spec"]["token"].as_str().unwrap_or_default();
+        let status = match token {
+            "alice-token" => {
+                json!({"authenticated": true, "user": {"username": "alice", "groups": ["ops"]}})
+            }
+            "bob-token" => json!({"authenticated": true, "user": {"username": "bob"}}),
+            _ => json!({"authenticated": false}),
+        };
+        Json(json!({
+            "apiVersion": "authentication.k8s.io/v1",
+            "kind": "TokenReview",
+            "metadata": {},
+            "spec": review["spec"],
+            "status": status,
+        }))
+    }
+
+    async fn subject_access_reviews(Json(review): Json<Value>) -> Json<Value> {
+        let allowed = review["spec"]["user"] == "alice"
+            && review["spec"]["nonResourceAttributes"]["path"] == "/debug/work"
+            && review["spec"]["nonResourceAttributes"]["verb"] == "get";
+        Json(json!({
+            "apiVersion": "authorization.k8s.io/v1",
+            "kind": "SubjectAccessReview",
+            "metadata": {},
+            "spec": review["spec"],
+            "status": {"allowed": allowed},
+        }))
+    }
+
+    /// Starts a stand-in for the kubernetes API that only serves token and
+    /// subject access reviews.
+    fn api_stand_in() -> Client {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind should succeed");
+        let addr: SocketAddr = listener.local_addr().expect("local addr should exist");
+
+        let app = Router::new()
+            .route(
+                "/apis/authentication.k8s.io/v1/tokenreviews",
+                post(token_reviews),
+            )
+            .route(
+                "/apis/authorization.k8s.io/v1/subjectaccessreviews",
+                post(subject_access_reviews),
+            );
+
+        tokio::spawn(async move {
+            axum::Server::from_tcp(listener)
+                .expect("server should start")
+                .serve(app.into_make_service())
+                .await
+                .expect("server should run");
+        });
+
+        let config = kube::Config::new(
+            format!("http://{addr}")
+                .parse()
+                .expect("url should be valid"),
+        );
+        Client::try_from(config).expect("client should be created")
+    }
+
+    #[tokio::test]
+    async fn static_token_authenticator() {
+        let authenticator = StaticTokenAuthenticator {
+            token: "secret".to_string(),
+        };
+        assert_eq!(
+            authenticator
+                .authorize(Some("secret"), "/debug/work", "get")
+                .await
+                .expect("authorize should succeed"),
+            Authorization::Allowed("admin".to_string())
+        );
+        assert_eq!(
+            authenticator
+                .authorize(Some("nope"), "/debug/work", "get")
+                .await
+                .expect("authorize should succeed"),
+            Authorization::Unauthenticated
+        );
+        assert_eq!(
+            authenticator
+                .authorize(None, "/debug/work", "get")
+                .await
+                .expect("authorize should succeed"),
+            Authorization::Unauthenticated
+        );
+    }
+
+    #[tokio::test]
+    async fn kubernetes_authenticator() {
+        let authenticator = KubernetesAuthenticator::new(api_stand_in());
+
+        assert_eq!(
+            authenticator
+                .authorize(Some("alice-token"), "/debug/work", "get")
+                .await
+                .expect("authorize should succeed"),
+            Authorization::Allowed("alice".to_string())
+        );
+        assert!(matches!(
+            authenticator
+                .authorize(Some("alice-token"), "/admin/consul/refresh", "create")
+                .await
+                .expect("authorize should succeed"),
+            Authorization::Forbidden(_)
+        ));
+        assert!(matches!(
+            authenticator
+                .authorize(Some("bob-token"), "/debug/work", "get")
+                .await
+                .expect("authorize should succeed"),
+            Authorization::Forbidden(_)
+        ));
+        assert_eq!(
+            authenticator
+                .authorize(Some("mallory-token"), "/debug/work", "get")
+                .await
+                .expect("authorize should succeed"),
+            Authorization::Unauthenticated
+        );
+        assert_eq!(
+            authenticator
+                .authorize(None, "/debug/work", "get")
+                .await
+                .expect("authorize should succeed"),
+            Authorization::Unauthenticated
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_api_auth_type() {
+        assert!(get_authenticator("basic", "").await.is_err());
+    }
+}
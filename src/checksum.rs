@@ -35,6 +35,16 @@ impl Checksummer for Md5Checksummer {
     }
 }
 
+/// Returns the checksum types that `get_checksummer` supports.
+pub fn supported_checksum_types() -> Vec<&'static str> {
+    vec![
+        #[cfg(feature = "sha256")]
+        "sha256",
+        #[cfg(feature = "md5")]
+        "md5",
+    ]
+}
+
 pub fn get_checksummer(checksum_type: &str) -> Box<dyn Checksummer> {
     match checksum_type {
         #[cfg(feature = "sha256")]
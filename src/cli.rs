@@ -0,0 +1,138 @@
+use std::env;
+
+use crate::error::Result;
+use anyhow::anyhow;
+
+const USAGE: &str = "usage: k8s-consul-mutator-rs [--config <path>] [dump-config | manifests [--image <image>] [--ca-bundle <path>]]";
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub enum Command {
+    /// Runs the webhook.
+    Run,
+    /// Prints the effective settings, with secrets redacted, and exits.
+    DumpConfig,
+    /// Prints the kubernetes manifests for the effective settings and exits.
+    Manifests,
+}
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Args {
+    pub command: Command,
+    pub config_file: Option<String>,
+    /// The container image used by `manifests`.
+    pub image: Option<String>,
+    /// The CA bundle file used by `manifests`.
+    pub ca_bundle: Option<String>,
+}
+
+impl Args {
+    /// Parses the process arguments. The configuration file can also be set
+    /// with the `CONFIG_FILE` environment variable.
+    pub fn from_env() -> Result<Self> {
+        Self::parse(env::args().skip(1), env::var("CONFIG_FILE").ok())
+    }
+
+    fn parse(args: impl IntoIterator<Item = String>, config_file: Option<String>) -> Result<Self> {
+        let mut command = Command::Run;
+        let mut config_file = config_file.filter(|value| !value.is_empty());
+        let mut image = None;
+        let mut ca_bundle = None;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-c" | "--config" => {
+                    config_file = Some(
+                        args.next()
+                            .ok_or_else(|| anyhow!("{arg} requires a value\n{USAGE}"))?,
+                    );
+                }
+                "--image" => {
+                    image = Some(
+                        args.next()
+                            .ok_or_else(|| anyhow!("{arg} requires a value\n{USAGE}"))?,
+                    );
+                }
+                "--ca-bundle" => {
+                    ca_bundle = Some(
+                        args.next()
+                            .ok_or_else(|| anyhow!("{arg} requires a value\n{USAGE}"))?,
+                    );
+                }
+                "dump-config" => command = Command::DumpConfig,
+                "manifests" => command = Command::Manifests,
+                "-h" | "--help" => return Err(anyhow!(USAGE)),
+                _ => {
+                    if let Some(value) = arg.strip_prefix("--config=") {
+                        config_file = Some(value.to_string());
+                    } else {
+                        return Err(anyhow!("unknown argument: {arg}\n{USAGE}"));
+                    }
+                }
+            }
+        }
+
+        if command != Command::Manifests && (image.is_some() || ca_bundle.is_some()) {
+            return Err(anyhow!(
+                "--image and --ca-bundle can only be used with manifests\n{USAGE}"
+            ));
+        }
+
+        Ok(Self {
+            command,
+            config_file,
+            image,
+            ca_bundle,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_args() {
+        assert_eq!(
+            Args::parse(args(&[]), None).unwrap(),
+            Args {
+                command: Command::Run,
+                config_file: None,
+                image: None,
+                ca_bundle: None,
+            }
+        );
+        assert_eq!(
+            Args::parse(
+                args(&["--config", "a.toml", "dump-config"]),
+                Some("b.toml".to_string())
+            )
+            .unwrap(),
+            Args {
+                command: Command::DumpConfig,
+                config_file: Some("a.toml".to_string()),
+                image: None,
+                ca_bundle: None,
+            }
+        );
+        assert_eq!(
+            Args::parse(args(&[]), Some("b.yaml".to_string()))
+                .unwrap()
+                .config_file,
+            Some("b.yaml".to_string())
+        );
+        assert!(Args::parse(args(&["--config"]), None).is_err());
+        assert!(Args::parse(args(&["serve"]), None).is_err());
+        assert_eq!(
+            Args::parse(args(&["manifests", "--image", "app:1"]), None)
+                .unwrap()
+                .image,
+            Some("app:1".to_string())
+        );
+        assert!(Args::parse(args(&["--image", "app:1"]), None).is_err());
+    }
+}
@@ -1,10 +1,60 @@
-use std::env;
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fs,
+    path::Path,
+    time::Duration,
+};
 
 use derive_builder::Builder;
+use serde::Serialize;
 
-#[derive(Builder, Clone, Debug)]
+use crate::{
+    checksum::supported_checksum_types,
+    consul::{ConsulEndpoint, CONSUL_CONSISTENCY_MODES},
+    duration::{format_duration, parse_duration, serialize_duration},
+    key_manager::supported_key_manager_types,
+};
+
+/// The value that secrets are replaced with when settings are printed.
+const REDACTED: &str = "REDACTED";
+
+/// The settings of each named consul cluster. The setting of a cluster is
+/// named `consul_cluster_<name>_<setting>`, such as
+/// `CONSUL_CLUSTER_LEGACY_HTTP_ADDR`.
+pub const CONSUL_CLUSTER_SETTINGS: &[&str] = &[
+    "http_addr",
+    "http_token_file",
+    "cacert",
+    "client_cert",
+    "client_key",
+    "tls_server_name",
+];
+
+/// The settings that can be changed while running by sending SIGHUP. Other
+/// settings require a restart.
+pub const RELOADABLE_SETTINGS: &[&str] = &[
+    "update_debounce",
+    "watch_dispatcher_reconcile",
+    "watch_dispatcher_debounce",
+    "check_key_timeout",
+    "check_key_idle",
+    "check_key_error_wait",
+    "consul_consistency_mode",
+    "consul_max_stale",
+    "set_deployment_annotations",
+    "set_deployment_spec_annotations",
+    "set_deployment_timestamp",
+    "set_deployment_spec_timestamp",
+    "audit_log",
+    "excluded_namespaces",
+    "label_selector",
+    "namespace_label_selector",
+];
+
+#[derive(Builder, Clone, Debug, Serialize)]
 #[builder(setter(into, strip_option))]
 pub struct Settings {
+    #[serde(skip_serializing)]
     #[builder(setter(into), default = "self.default_version()")]
     pub version: String,
 
@@ -20,29 +70,79 @@ pub struct Settings {
     #[builder(setter(into), default = "self.default_certificate_key()")]
     pub certificate_key: String,
 
+    #[serde(serialize_with = "serialize_duration")]
+    #[builder(setter(into), default = "self.default_certificate_reload_interval()")]
+    pub certificate_reload_interval: Duration,
+
     #[builder(setter(into), default = "self.default_update_debounce()")]
-    pub update_debounce: u16,
+    #[serde(serialize_with = "serialize_duration")]
+    pub update_debounce: Duration,
 
     #[builder(
         setter(into),
         default = "self.default_watch_dispatcher_first_reconcile()"
     )]
-    pub watch_dispatcher_first_reconcile: u16,
+    #[serde(serialize_with = "serialize_duration")]
+    pub watch_dispatcher_first_reconcile: Duration,
 
     #[builder(setter(into), default = "self.default_watch_dispatcher_reconcile()")]
-    pub watch_dispatcher_reconcile: u16,
+    #[serde(serialize_with = "serialize_duration")]
+    pub watch_dispatcher_reconcile: Duration,
 
     #[builder(setter(into), default = "self.default_watch_dispatcher_debounce()")]
-    pub watch_dispatcher_debounce: u16,
+    #[serde(serialize_with = "serialize_duration")]
+    pub watch_dispatcher_debounce: Duration,
 
     #[builder(setter(into), default = "self.default_check_key_timeout()")]
-    pub check_key_timeout: String,
+    #[serde(serialize_with = "serialize_duration")]
+    pub check_key_timeout: Duration,
 
     #[builder(setter(into), default = "self.default_check_key_idle()")]
-    pub check_key_idle: u16,
+    #[serde(serialize_with = "serialize_duration")]
+    pub check_key_idle: Duration,
 
     #[builder(setter(into), default = "self.default_check_key_error_wait()")]
-    pub check_key_error_wait: u16,
+    #[serde(serialize_with = "serialize_duration")]
+    pub check_key_error_wait: Duration,
+
+    #[builder(setter(into), default = "self.default_consul_consistency_mode()")]
+    pub consul_consistency_mode: String,
+
+    #[builder(setter(into), default = "self.default_consul_max_stale()")]
+    #[serde(serialize_with = "serialize_duration")]
+    pub consul_max_stale: Duration,
+
+    #[builder(setter(into), default = "self.default_consul_max_concurrent_queries()")]
+    pub consul_max_concurrent_queries: u16,
+
+    #[builder(setter(into), default = "self.default_consul_request_rate()")]
+    pub consul_request_rate: u16,
+
+    #[builder(setter(into), default = "self.default_consul_watch_prefix_depth()")]
+    pub consul_watch_prefix_depth: u16,
+
+    #[builder(setter(into), default = "self.default_consul_http_token_file()")]
+    pub consul_http_token_file: String,
+
+    #[builder(setter(into), default = "self.default_consul_cacert()")]
+    pub consul_cacert: String,
+
+    #[builder(setter(into), default = "self.default_consul_client_cert()")]
+    pub consul_client_cert: String,
+
+    #[builder(setter(into), default = "self.default_consul_client_key()")]
+    pub consul_client_key: String,
+
+    #[builder(setter(into), default = "self.default_consul_tls_server_name()")]
+    pub consul_tls_server_name: String,
+
+    #[builder(setter(into), default = "self.default_consul_clusters()")]
+    pub consul_clusters: Vec<String>,
+
+    /// The `CONSUL_CLUSTER_SETTINGS` of the named clusters, by setting name.
+    #[serde(flatten)]
+    #[builder(setter(into), default)]
+    pub consul_cluster_settings: BTreeMap<String, String>,
 
     #[builder(setter(into), default = "self.default_checksum_type()")]
     pub checksum_type: String,
@@ -64,9 +164,313 @@ pub struct Settings {
 
     #[builder(setter(into), default = "self.default_set_deployment_spec_timestamp()")]
     pub set_deployment_spec_timestamp: bool,
+
+    #[builder(setter(into), default = "self.default_audit_log()")]
+    pub audit_log: bool,
+
+    #[builder(setter(into), default = "self.default_admin_token()")]
+    pub admin_token: String,
+
+    #[builder(setter(into), default = "self.default_admin_port()")]
+    pub admin_port: u16,
+
+    #[builder(setter(into), default = "self.default_api_auth()")]
+    pub api_auth: String,
+
+    #[builder(setter(into), default = "self.default_leader_election()")]
+    pub leader_election: bool,
+
+    #[builder(setter(into), default = "self.default_leader_election_namespace()")]
+    pub leader_election_namespace: String,
+
+    #[builder(setter(into), default = "self.default_leader_election_lease_name()")]
+    pub leader_election_lease_name: String,
+
+    #[builder(setter(into), default = "self.default_leader_election_identity()")]
+    pub leader_election_identity: String,
+
+    #[builder(
+        setter(into),
+        default = "self.default_leader_election_lease_duration()"
+    )]
+    #[serde(serialize_with = "serialize_duration")]
+    pub leader_election_lease_duration: Duration,
+
+    #[builder(setter(into), default = "self.default_sharding()")]
+    pub sharding: bool,
+
+    #[builder(setter(into), default = "self.default_shard_namespace()")]
+    pub shard_namespace: String,
+
+    #[builder(setter(into), default = "self.default_shard_group()")]
+    pub shard_group: String,
+
+    #[builder(setter(into), default = "self.default_shard_identity()")]
+    pub shard_identity: String,
+
+    #[builder(setter(into), default = "self.default_shard_lease_duration()")]
+    #[serde(serialize_with = "serialize_duration")]
+    pub shard_lease_duration: Duration,
+
+    #[builder(setter(into), default = "self.default_namespaces()")]
+    pub namespaces: Vec<String>,
+
+    #[builder(setter(into), default = "self.default_excluded_namespaces()")]
+    pub excluded_namespaces: Vec<String>,
+
+    #[builder(setter(into), default = "self.default_label_selector()")]
+    pub label_selector: String,
+
+    #[builder(setter(into), default = "self.default_namespace_label_selector()")]
+    pub namespace_label_selector: String,
+
+    #[builder(setter(into), default = "self.default_self_managed_tls()")]
+    pub self_managed_tls: bool,
+
+    #[builder(setter(into), default = "self.default_webhook_name()")]
+    pub webhook_name: String,
+
+    #[builder(setter(into), default = "self.default_webhook_service_name()")]
+    pub webhook_service_name: String,
+
+    #[builder(setter(into), default = "self.default_webhook_service_namespace()")]
+    pub webhook_service_namespace: String,
+
+    #[builder(setter(into), default = "self.default_webhook_secret_name()")]
+    pub webhook_secret_name: String,
+
+    #[builder(setter(into), default = "self.default_webhook_failure_policy()")]
+    pub webhook_failure_policy: String,
+
+    #[serde(serialize_with = "serialize_duration")]
+    #[builder(setter(into), default = "self.default_webhook_certificate_validity()")]
+    pub webhook_certificate_validity: Duration,
+
+    #[serde(serialize_with = "serialize_duration")]
+    #[builder(
+        setter(into),
+        default = "self.default_webhook_certificate_renew_before()"
+    )]
+    pub webhook_certificate_renew_before: Duration,
+
+    #[builder(setter(into), default = "self.default_log_format()")]
+    pub log_format: String,
+
+    #[builder(setter(into), default = "self.default_otel_exporter_otlp_endpoint()")]
+    pub otel_exporter_otlp_endpoint: String,
+
+    #[builder(setter(into), default = "self.default_otel_service_name()")]
+    pub otel_service_name: String,
 }
 
 impl SettingsBuilder {
+    /// Creates a builder from a configuration source. Every value that is
+    /// present in the source must be valid, and all invalid values are
+    /// reported together. Values that are not present use their defaults.
+    pub fn from_source(source: &ConfigSource) -> Result<Self, Vec<String>> {
+        let (builder, errors) = Self::from_source_with_errors(source);
+        if errors.is_empty() {
+            Ok(builder)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Creates a builder from the valid values of a configuration source,
+    /// with the errors of the invalid values. Invalid values use their
+    /// defaults.
+    pub fn from_source_with_errors(source: &ConfigSource) -> (Self, Vec<String>) {
+        let mut loader = Loader::new(source);
+        let mut builder = SettingsBuilder::default();
+
+        if let Some(value) = loader.u16("port") {
+            builder.port(value);
+        }
+        if let Some(value) = loader.u16("secure_port") {
+            builder.secure_port(value);
+        }
+        if let Some(value) = loader.string("certificate") {
+            builder.certificate(value);
+        }
+        if let Some(value) = loader.string("certificate_key") {
+            builder.certificate_key(value);
+        }
+        if let Some(value) = loader.duration("certificate_reload_interval") {
+            builder.certificate_reload_interval(value);
+        }
+        if let Some(value) = loader.duration("update_debounce") {
+            builder.update_debounce(value);
+        }
+        if let Some(value) = loader.duration("watch_dispatcher_first_reconcile") {
+            builder.watch_dispatcher_first_reconcile(value);
+        }
+        if let Some(value) = loader.duration("watch_dispatcher_reconcile") {
+            builder.watch_dispatcher_reconcile(value);
+        }
+        if let Some(value) = loader.duration("watch_dispatcher_debounce") {
+            builder.watch_dispatcher_debounce(value);
+        }
+        if let Some(value) = loader.duration("check_key_timeout") {
+            builder.check_key_timeout(value);
+        }
+        if let Some(value) = loader.duration("check_key_idle") {
+            builder.check_key_idle(value);
+        }
+        if let Some(value) = loader.duration("check_key_error_wait") {
+            builder.check_key_error_wait(value);
+        }
+        if let Some(value) = loader.one_of("consul_consistency_mode", CONSUL_CONSISTENCY_MODES) {
+            builder.consul_consistency_mode(value);
+        }
+        if let Some(value) = loader.duration("consul_max_stale") {
+            builder.consul_max_stale(value);
+        }
+        if let Some(value) = loader.u16("consul_max_concurrent_queries") {
+            builder.consul_max_concurrent_queries(value);
+        }
+        if let Some(value) = loader.u16("consul_request_rate") {
+            builder.consul_request_rate(value);
+        }
+        if let Some(value) = loader.u16("consul_watch_prefix_depth") {
+            builder.consul_watch_prefix_depth(value);
+        }
+        if let Some(value) = loader.string("consul_http_token_file") {
+            builder.consul_http_token_file(value);
+        }
+        if let Some(value) = loader.string("consul_cacert") {
+            builder.consul_cacert(value);
+        }
+        if let Some(value) = loader.string("consul_client_cert") {
+            builder.consul_client_cert(value);
+        }
+        if let Some(value) = loader.string("consul_client_key") {
+            builder.consul_client_key(value);
+        }
+        if let Some(value) = loader.string("consul_tls_server_name") {
+            builder.consul_tls_server_name(value);
+        }
+        if let Some(value) = loader.list("consul_clusters") {
+            let mut cluster_settings = BTreeMap::new();
+            for cluster in value.iter() {
+                for setting in CONSUL_CLUSTER_SETTINGS {
+                    let name = consul_cluster_setting(cluster, setting);
+                    if let Some(value) = loader.string(&name) {
+                        cluster_settings.insert(name, value);
+                    }
+                }
+            }
+            builder.consul_clusters(value);
+            builder.consul_cluster_settings(cluster_settings);
+        }
+        if let Some(value) = loader.one_of("checksum_type", &supported_checksum_types()) {
+            builder.checksum_type(value);
+        }
+        if let Some(value) = loader.one_of("key_manager_type", &supported_key_manager_types()) {
+            builder.key_manager_type(value);
+        }
+        if let Some(value) = loader.bool("set_deployment_annotations") {
+            builder.set_deployment_annotations(value);
+        }
+        if let Some(value) = loader.bool("set_deployment_spec_annotations") {
+            builder.set_deployment_spec_annotations(value);
+        }
+        if let Some(value) = loader.bool("set_deployment_timestamp") {
+            builder.set_deployment_timestamp(value);
+        }
+        if let Some(value) = loader.bool("set_deployment_spec_timestamp") {
+            builder.set_deployment_spec_timestamp(value);
+        }
+        if let Some(value) = loader.bool("audit_log") {
+            builder.audit_log(value);
+        }
+        if let Some(value) = loader.string("admin_token") {
+            builder.admin_token(value);
+        }
+        if let Some(value) = loader.u16("admin_port") {
+            builder.admin_port(value);
+        }
+        if let Some(value) = loader.one_of("api_auth", &["token", "kubernetes", "none"]) {
+            builder.api_auth(value);
+        }
+        if let Some(value) = loader.bool("leader_election") {
+            builder.leader_election(value);
+        }
+        if let Some(value) = loader.string("leader_election_namespace") {
+            builder.leader_election_namespace(value);
+        }
+        if let Some(value) = loader.string("leader_election_lease_name") {
+            builder.leader_election_lease_name(value);
+        }
+        if let Some(value) = loader.string("leader_election_identity") {
+            builder.leader_election_identity(value);
+        }
+        if let Some(value) = loader.duration("leader_election_lease_duration") {
+            builder.leader_election_lease_duration(value);
+        }
+        if let Some(value) = loader.bool("sharding") {
+            builder.sharding(value);
+        }
+        if let Some(value) = loader.string("shard_namespace") {
+            builder.shard_namespace(value);
+        }
+        if let Some(value) = loader.string("shard_group") {
+            builder.shard_group(value);
+        }
+        if let Some(value) = loader.string("shard_identity") {
+            builder.shard_identity(value);
+        }
+        if let Some(value) = loader.duration("shard_lease_duration") {
+            builder.shard_lease_duration(value);
+        }
+        if let Some(value) = loader.list("namespaces") {
+            builder.namespaces(value);
+        }
+        if let Some(value) = loader.list("excluded_namespaces") {
+            builder.excluded_namespaces(value);
+        }
+        if let Some(value) = loader.string("label_selector") {
+            builder.label_selector(value);
+        }
+        if let Some(value) = loader.string("namespace_label_selector") {
+            builder.namespace_label_selector(value);
+        }
+        if let Some(value) = loader.bool("self_managed_tls") {
+            builder.self_managed_tls(value);
+        }
+        if let Some(value) = loader.string("webhook_name") {
+            builder.webhook_name(value);
+        }
+        if let Some(value) = loader.string("webhook_service_name") {
+            builder.webhook_service_name(value);
+        }
+        if let Some(value) = loader.string("webhook_service_namespace") {
+            builder.webhook_service_namespace(value);
+        }
+        if let Some(value) = loader.string("webhook_secret_name") {
+            builder.webhook_secret_name(value);
+        }
+        if let Some(value) = loader.one_of("webhook_failure_policy", &["ignore", "fail"]) {
+            builder.webhook_failure_policy(value);
+        }
+        if let Some(value) = loader.duration("webhook_certificate_validity") {
+            builder.webhook_certificate_validity(value);
+        }
+        if let Some(value) = loader.duration("webhook_certificate_renew_before") {
+            builder.webhook_certificate_renew_before(value);
+        }
+        if let Some(value) = loader.one_of("log_format", &["text", "json"]) {
+            builder.log_format(value);
+        }
+        if let Some(value) = loader.string("otel_exporter_otlp_endpoint") {
+            builder.otel_exporter_otlp_endpoint(value);
+        }
+        if let Some(value) = loader.string("otel_service_name") {
+            builder.otel_service_name(value);
+        }
+
+        (builder, loader.finish())
+    }
+
     fn default_version(&self) -> String {
         option_env!("GIT_HASH")
             .unwrap_or(env!("CARGO_PKG_VERSION", "develop"))
@@ -74,128 +478,474 @@ impl SettingsBuilder {
     }
 
     fn default_port(&self) -> u16 {
-        env::var("PORT")
-            .unwrap_or("8080".to_string())
-            .parse::<u16>()
-            .unwrap_or(8080)
+        8080
     }
 
     fn default_secure_port(&self) -> u16 {
-        env::var("SECURE_PORT")
-            .unwrap_or("8443".to_string())
-            .parse::<u16>()
-            .unwrap_or(8443)
+        8443
     }
 
-    fn default_update_debounce(&self) -> u16 {
-        env::var("UPDATE_DEBOUNCE")
-            .unwrap_or("60".to_string())
-            .parse::<u16>()
-            .unwrap_or(60)
+    fn default_update_debounce(&self) -> Duration {
+        Duration::from_secs(60)
     }
 
     fn default_certificate(&self) -> String {
-        env::var("CERTIFICATE").unwrap_or("".to_string())
+        "".to_string()
     }
 
     fn default_certificate_key(&self) -> String {
-        env::var("CERTIFICATE_KEY").unwrap_or("".to_string())
+        "".to_string()
+    }
+
+    fn default_certificate_reload_interval(&self) -> Duration {
+        Duration::from_secs(30)
     }
 
-    fn default_watch_dispatcher_first_reconcile(&self) -> u16 {
-        env::var("WATCH_DISPATCHER_FIRST_RECONCILE")
-            .unwrap_or("30".to_string())
-            .parse::<u16>()
-            .unwrap_or(30)
+    fn default_watch_dispatcher_first_reconcile(&self) -> Duration {
+        Duration::from_secs(30)
     }
 
-    fn default_watch_dispatcher_reconcile(&self) -> u16 {
-        env::var("WATCH_DISPATCHER_RECONCILE")
-            .unwrap_or("1800".to_string())
-            .parse::<u16>()
-            .unwrap_or(1800)
+    fn default_watch_dispatcher_reconcile(&self) -> Duration {
+        Duration::from_secs(1800)
     }
 
-    fn default_watch_dispatcher_debounce(&self) -> u16 {
-        env::var("WATCH_DISPATCHER_DEBOUNCE")
-            .unwrap_or("60".to_string())
-            .parse::<u16>()
-            .unwrap_or(60)
+    fn default_watch_dispatcher_debounce(&self) -> Duration {
+        Duration::from_secs(60)
     }
 
-    fn default_check_key_timeout(&self) -> String {
-        env::var("CHECK_KEY_TIMEOUT").unwrap_or("10s".to_string())
+    fn default_check_key_timeout(&self) -> Duration {
+        Duration::from_secs(10)
     }
 
-    fn default_check_key_idle(&self) -> u16 {
-        env::var("CHECK_KEY_IDLE")
-            .unwrap_or("60".to_string())
-            .parse::<u16>()
-            .unwrap_or(60)
+    fn default_check_key_idle(&self) -> Duration {
+        Duration::from_secs(60)
     }
 
-    fn default_check_key_error_wait(&self) -> u16 {
-        env::var("CHECK_KEY_ERROR_WAIT")
-            .unwrap_or("60".to_string())
-            .parse::<u16>()
-            .unwrap_or(60)
+    fn default_check_key_error_wait(&self) -> Duration {
+        Duration::from_secs(60)
     }
 
     fn default_checksum_type(&self) -> String {
-        env::var("CHECKSUM_TYPE")
-            .unwrap_or("md5".to_string())
-            .to_lowercase()
+        "md5".to_string()
+    }
+
+    fn default_consul_consistency_mode(&self) -> String {
+        "default".to_string()
+    }
+
+    fn default_consul_max_stale(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    fn default_consul_max_concurrent_queries(&self) -> u16 {
+        512
+    }
+
+    fn default_consul_request_rate(&self) -> u16 {
+        0
+    }
+
+    fn default_consul_watch_prefix_depth(&self) -> u16 {
+        0
+    }
+
+    fn default_consul_http_token_file(&self) -> String {
+        "".to_string()
+    }
+
+    fn default_consul_cacert(&self) -> String {
+        "".to_string()
+    }
+
+    fn default_consul_client_cert(&self) -> String {
+        "".to_string()
+    }
+
+    fn default_consul_client_key(&self) -> String {
+        "".to_string()
+    }
+
+    fn default_consul_tls_server_name(&self) -> String {
+        "".to_string()
+    }
+
+    fn default_consul_clusters(&self) -> Vec<String> {
+        vec![]
     }
 
     fn default_key_manager_type(&self) -> String {
-        env::var("KEY_MANAGER_TYPE")
-            .unwrap_or("memory".to_string())
-            .to_lowercase()
+        "memory".to_string()
     }
 
     fn default_set_deployment_annotations(&self) -> bool {
-        let value = env::var("SET_DEPLOYMENT_ANNOTATIONS")
-            .unwrap_or("true".into())
-            .to_lowercase();
-        match value.as_str() {
-            "true" => true,
-            "false" => false,
-            _ => true,
-        }
+        true
     }
 
     fn default_set_deployment_spec_annotations(&self) -> bool {
-        let value = env::var("SET_DEPLOYMENT_SPEC_ANNOTATIONS")
-            .unwrap_or("true".into())
-            .to_lowercase();
-        match value.as_str() {
-            "true" => true,
-            "false" => false,
-            _ => true,
-        }
+        true
     }
 
     fn default_set_deployment_timestamp(&self) -> bool {
-        let value = env::var("SET_DEPLOYMENT_TIMESTAMP")
-            .unwrap_or("true".into())
-            .to_lowercase();
-        match value.as_str() {
-            "true" => true,
-            "false" => false,
-            _ => true,
-        }
+        true
     }
 
     fn default_set_deployment_spec_timestamp(&self) -> bool {
-        let value = env::var("SET_DEPLOYMENT_SPEC_TIMESTAMP")
-            .unwrap_or("false".into())
-            .to_lowercase();
-        match value.as_str() {
-            "true" => true,
-            "false" => false,
-            _ => false,
+        false
+    }
+
+    fn default_admin_token(&self) -> String {
+        "".to_string()
+    }
+
+    fn default_admin_port(&self) -> u16 {
+        0
+    }
+
+    fn default_api_auth(&self) -> String {
+        "token".to_string()
+    }
+
+    fn default_audit_log(&self) -> bool {
+        false
+    }
+
+    fn default_leader_election(&self) -> bool {
+        false
+    }
+
+    fn default_sharding(&self) -> bool {
+        false
+    }
+
+    fn default_namespaces(&self) -> Vec<String> {
+        vec![]
+    }
+
+    fn default_excluded_namespaces(&self) -> Vec<String> {
+        vec![]
+    }
+
+    fn default_label_selector(&self) -> String {
+        "".to_string()
+    }
+
+    fn default_namespace_label_selector(&self) -> String {
+        "".to_string()
+    }
+
+    fn default_leader_election_namespace(&self) -> String {
+        service_account_namespace()
+    }
+
+    fn default_leader_election_lease_name(&self) -> String {
+        "k8s-consul-mutator-rs".to_string()
+    }
+
+    fn default_leader_election_identity(&self) -> String {
+        env::var("HOSTNAME").unwrap_or(format!("k8s-consul-mutator-rs-{}", std::process::id()))
+    }
+
+    fn default_leader_election_lease_duration(&self) -> Duration {
+        Duration::from_secs(15)
+    }
+
+    fn default_shard_namespace(&self) -> String {
+        service_account_namespace()
+    }
+
+    fn default_shard_group(&self) -> String {
+        "k8s-consul-mutator-rs".to_string()
+    }
+
+    fn default_shard_identity(&self) -> String {
+        env::var("HOSTNAME").unwrap_or(format!("k8s-consul-mutator-rs-{}", std::process::id()))
+    }
+
+    fn default_shard_lease_duration(&self) -> Duration {
+        Duration::from_secs(15)
+    }
+
+    fn default_self_managed_tls(&self) -> bool {
+        false
+    }
+
+    fn default_webhook_name(&self) -> String {
+        "k8s-consul-mutator-rs".to_string()
+    }
+
+    fn default_webhook_service_name(&self) -> String {
+        "k8s-consul-mutator-rs".to_string()
+    }
+
+    fn default_webhook_service_namespace(&self) -> String {
+        service_account_namespace()
+    }
+
+    fn default_webhook_secret_name(&self) -> String {
+        "k8s-consul-mutator-rs-tls".to_string()
+    }
+
+    fn default_webhook_failure_policy(&self) -> String {
+        "ignore".to_string()
+    }
+
+    fn default_webhook_certificate_validity(&self) -> Duration {
+        Duration::from_secs(365 * 24 * 60 * 60)
+    }
+
+    fn default_webhook_certificate_renew_before(&self) -> Duration {
+        Duration::from_secs(30 * 24 * 60 * 60)
+    }
+
+    fn default_log_format(&self) -> String {
+        "text".to_string()
+    }
+
+    fn default_otel_exporter_otlp_endpoint(&self) -> String {
+        "".to_string()
+    }
+
+    fn default_otel_service_name(&self) -> String {
+        "k8s-consul-mutator-rs".to_string()
+    }
+}
+
+/// Returns the namespace of the service account of the pod, or `default` when
+/// not running in a pod.
+fn service_account_namespace() -> String {
+    fs::read_to_string("/var/run/secrets/kubernetes.io/serviceaccount/namespace")
+        .map(|value| value.trim().to_string())
+        .unwrap_or("default".to_string())
+}
+
+/// The sources of configuration values. Each setting can be set in the
+/// configuration file by its name, e.g. `update_debounce`, or with an
+/// environment variable of the uppercase name, e.g. `UPDATE_DEBOUNCE`.
+/// Environment variables override values from the configuration file.
+#[derive(Default, Debug, Clone)]
+pub struct ConfigSource {
+    file: HashMap<String, String>,
+    env: HashMap<String, String>,
+}
+
+impl ConfigSource {
+    /// Creates a source from the environment and an optional configuration
+    /// file. Files ending in `.yaml` or `.yml` are parsed as YAML and all
+    /// other files are parsed as TOML.
+    pub fn load(config_file: Option<&str>) -> Result<Self, Vec<String>> {
+        let file = match config_file {
+            Some(path) => {
+                let content = fs::read_to_string(path)
+                    .map_err(|err| vec![format!("{path}: unable to read file: {err}")])?;
+                Self::parse_file(path, &content)?
+            }
+            None => HashMap::new(),
+        };
+
+        Ok(Self {
+            file,
+            env: env::vars().collect(),
+        })
+    }
+
+    fn parse_file(path: &str, content: &str) -> Result<HashMap<String, String>, Vec<String>> {
+        let is_yaml = matches!(
+            Path::new(path).extension().and_then(|e| e.to_str()),
+            Some("yaml") | Some("yml")
+        );
+
+        let value: serde_json::Value = if is_yaml {
+            serde_yaml::from_str(content).map_err(|err| vec![format!("{path}: {err}")])?
+        } else {
+            toml::from_str(content).map_err(|err| vec![format!("{path}: {err}")])?
+        };
+
+        let table = match value {
+            serde_json::Value::Object(table) => table,
+            serde_json::Value::Null => return Ok(HashMap::new()),
+            _ => return Err(vec![format!("{path}: expected a table of settings")]),
+        };
+
+        let mut results = HashMap::new();
+        let mut errors = vec![];
+        for (key, value) in table {
+            let value = match value {
+                serde_json::Value::Null => continue,
+                serde_json::Value::String(value) => value,
+                serde_json::Value::Bool(value) => value.to_string(),
+                serde_json::Value::Number(value) => value.to_string(),
+                serde_json::Value::Array(values) => {
+                    let mut items = vec![];
+                    for value in values {
+                        match value {
+                            serde_json::Value::String(value) => items.push(value),
+                            _ => errors.push(format!("{key}: expected a list of strings")),
+                        }
+                    }
+                    items.join(",")
+                }
+                serde_json::Value::Object(_) => {
+                    errors.push(format!("{key}: expected a value, found a table"));
+                    continue;
+                }
+            };
+            results.insert(key, value);
+        }
+
+        if errors.is_empty() {
+            Ok(results)
+        } else {
+            Err(errors)
         }
     }
+
+    fn get(&self, name: &str) -> Option<String> {
+        self.env
+            .get(&name.to_uppercase())
+            .or_else(|| self.file.get(name))
+            .cloned()
+    }
+}
+
+/// Reads values from a configuration source and collects every error that is
+/// encountered.
+struct Loader<'a> {
+    source: &'a ConfigSource,
+    known: Vec<String>,
+    errors: Vec<String>,
+}
+
+impl<'a> Loader<'a> {
+    fn new(source: &'a ConfigSource) -> Self {
+        Self {
+            source,
+            known: vec![],
+            errors: vec![],
+        }
+    }
+
+    fn raw(&mut self, name: &str) -> Option<String> {
+        self.known.push(name.to_string());
+        self.source.get(name)
+    }
+
+    fn error(&mut self, name: &str, value: &str, expected: &str) {
+        self.errors.push(format!(
+            "{}: invalid value {:?}: expected {}",
+            name.to_uppercase(),
+            value,
+            expected
+        ));
+    }
+
+    fn string(&mut self, name: &str) -> Option<String> {
+        self.raw(name)
+    }
+
+    fn u16(&mut self, name: &str) -> Option<u16> {
+        let value = self.raw(name)?;
+        match value.trim().parse::<u16>() {
+            Ok(parsed) => Some(parsed),
+            Err(_) => {
+                self.error(name, &value, "a number between 0 and 65535");
+                None
+            }
+        }
+    }
+
+    fn bool(&mut self, name: &str) -> Option<bool> {
+        let value = self.raw(name)?;
+        match value.trim().to_lowercase().as_str() {
+            "true" => Some(true),
+            "false" => Some(false),
+            _ => {
+                self.error(name, &value, "true or false");
+                None
+            }
+        }
+    }
+
+    fn duration(&mut self, name: &str) -> Option<Duration> {
+        let value = self.raw(name)?;
+        match parse_duration(&value) {
+            Ok(parsed) => Some(parsed),
+            Err(_) => {
+                self.error(name, &value, "a duration such as 90s, 5m, or 1h30m");
+                None
+            }
+        }
+    }
+
+    fn list(&mut self, name: &str) -> Option<Vec<String>> {
+        self.raw(name).map(|value| split_list(&value))
+    }
+
+    fn one_of(&mut self, name: &str, allowed: &[&str]) -> Option<String> {
+        let value = self.raw(name)?;
+        let normalized = value.trim().to_lowercase();
+        if allowed.contains(&normalized.as_str()) {
+            Some(normalized)
+        } else {
+            self.error(name, &value, &format!("one of {}", allowed.join(", ")));
+            None
+        }
+    }
+
+    /// Returns all errors, including unknown settings in the configuration
+    /// file.
+    fn finish(mut self) -> Vec<String> {
+        let mut unknown: Vec<&String> = self
+            .source
+            .file
+            .keys()
+            .filter(|key| !self.known.contains(key))
+            .collect();
+        unknown.sort();
+        for key in unknown {
+            self.errors.push(format!("{key}: unknown setting"));
+        }
+
+        self.errors
+    }
+}
+
+/// Returns the name of a setting of a named consul cluster.
+pub fn consul_cluster_setting(cluster: &str, setting: &str) -> String {
+    format!("consul_cluster_{cluster}_{setting}")
+}
+
+/// Splits a comma separated list, ignoring empty values.
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+/// Loads settings from the environment and an optional configuration file and
+/// validates them. All problems are returned together.
+pub fn load_settings(config_file: Option<&str>) -> Result<Settings, Vec<String>> {
+    let source = ConfigSource::load(config_file)?;
+    settings_from_source(&source)
+}
+
+/// Builds and validates settings from a configuration source. Invalid values
+/// are replaced with their defaults so that the remaining values can still be
+/// validated, and the errors of both steps are returned together.
+fn settings_from_source(source: &ConfigSource) -> Result<Settings, Vec<String>> {
+    let (builder, mut errors) = SettingsBuilder::from_source_with_errors(source);
+    let settings = builder.build().map_err(|err| {
+        errors.push(err.to_string());
+        errors.clone()
+    })?;
+
+    errors.extend(settings.validate());
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    Ok(settings)
 }
 
 impl Settings {
@@ -204,6 +954,472 @@ impl Settings {
     }
 
     pub fn is_secure_enabled(&self) -> bool {
-        self.secure_port != 0 && !self.certificate.is_empty() && !self.certificate_key.is_empty()
+        self.secure_port != 0 && self.has_certificate()
+    }
+
+    pub fn is_admin_listener_enabled(&self) -> bool {
+        self.admin_port != 0
+    }
+
+    /// Returns true if a certificate is available, either from files or
+    /// because it is self-managed.
+    pub fn has_certificate(&self) -> bool {
+        self.self_managed_tls || self.has_certificate_files()
+    }
+
+    pub fn consul_endpoint(&self) -> ConsulEndpoint {
+        let optional = |value: &String| (!value.is_empty()).then(|| value.clone());
+        ConsulEndpoint {
+            address: None,
+            token_file: optional(&self.consul_http_token_file),
+            ca_cert: optional(&self.consul_cacert),
+            client_cert: optional(&self.consul_client_cert),
+            client_key: optional(&self.consul_client_key),
+            tls_server_name: optional(&self.consul_tls_server_name),
+            from_environment: true,
+        }
+    }
+
+    /// Returns the endpoint of a named consul cluster. Unlike the default
+    /// cluster, named clusters don't use the `CONSUL_HTTP_*` environment
+    /// variables.
+    pub fn consul_cluster_endpoint(&self, cluster: &str) -> ConsulEndpoint {
+        let optional = |setting: &str| {
+            self.consul_cluster_settings
+                .get(&consul_cluster_setting(cluster, setting))
+                .filter(|value| !value.is_empty())
+                .cloned()
+        };
+        ConsulEndpoint {
+            address: optional("http_addr"),
+            token_file: optional("http_token_file"),
+            ca_cert: optional("cacert"),
+            client_cert: optional("client_cert"),
+            client_key: optional("client_key"),
+            tls_server_name: optional("tls_server_name"),
+            from_environment: false,
+        }
+    }
+
+    pub fn has_certificate_files(&self) -> bool {
+        !self.certificate.is_empty() && !self.certificate_key.is_empty()
+    }
+
+    /// Checks the settings against each other. Returns a list of problems.
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = vec![];
+
+        if !self.is_secure_enabled() && !self.is_insecure_enabled() {
+            errors.push(
+                "one or both of PORT and SECURE_PORT must be set to a non-zero value".to_string(),
+            );
+        }
+
+        if self.certificate.is_empty() != self.certificate_key.is_empty() {
+            errors.push("CERTIFICATE and CERTIFICATE_KEY must be set together".to_string());
+        }
+
+        if self.self_managed_tls && self.has_certificate_files() {
+            errors.push(
+                "CERTIFICATE and CERTIFICATE_KEY can't be set when SELF_MANAGED_TLS is true"
+                    .to_string(),
+            );
+        }
+
+        if self.webhook_certificate_renew_before >= self.webhook_certificate_validity {
+            errors.push(
+                "WEBHOOK_CERTIFICATE_RENEW_BEFORE must be less than WEBHOOK_CERTIFICATE_VALIDITY"
+                    .to_string(),
+            );
+        }
+
+        if self.consul_client_cert.is_empty() != self.consul_client_key.is_empty() {
+            errors
+                .push("CONSUL_CLIENT_CERT and CONSUL_CLIENT_KEY must be set together".to_string());
+        }
+
+        for cluster in self.consul_clusters.iter() {
+            if cluster.is_empty()
+                || !cluster
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+            {
+                errors.push(format!(
+                    "CONSUL_CLUSTERS: {cluster:?} must only contain lowercase letters and digits"
+                ));
+                continue;
+            }
+            let endpoint = self.consul_cluster_endpoint(cluster);
+            if endpoint.address.is_none() {
+                errors.push(format!(
+                    "{} must be set",
+                    consul_cluster_setting(cluster, "http_addr").to_uppercase()
+                ));
+            }
+            if endpoint.client_cert.is_some() != endpoint.client_key.is_some() {
+                errors.push(format!(
+                    "{} and {} must be set together",
+                    consul_cluster_setting(cluster, "client_cert").to_uppercase(),
+                    consul_cluster_setting(cluster, "client_key").to_uppercase()
+                ));
+            }
+        }
+
+        if self.consul_max_concurrent_queries == 0 {
+            errors.push("CONSUL_MAX_CONCURRENT_QUERIES must be greater than 0".to_string());
+        }
+
+        if self.leader_election && self.sharding {
+            errors.push("only one of LEADER_ELECTION and SHARDING can be set to true".to_string());
+        }
+
+        for (name, value, min, max) in self.duration_bounds() {
+            if value < min || value > max {
+                errors.push(format!(
+                    "{name}: {} must be between {} and {}",
+                    format_duration(&value),
+                    format_duration(&min),
+                    format_duration(&max)
+                ));
+            }
+        }
+
+        if let Err(err) = crate::scope::LabelSelector::parse(&self.label_selector) {
+            errors.push(format!("LABEL_SELECTOR: {err}"));
+        }
+
+        if let Err(err) = crate::scope::LabelSelector::parse(&self.namespace_label_selector) {
+            errors.push(format!("NAMESPACE_LABEL_SELECTOR: {err}"));
+        }
+
+        errors
+    }
+
+    /// Returns each timing setting with its allowed range.
+    fn duration_bounds(&self) -> Vec<(&'static str, Duration, Duration, Duration)> {
+        const HOUR: Duration = Duration::from_secs(60 * 60);
+        const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+        vec![
+            (
+                "CERTIFICATE_RELOAD_INTERVAL",
+                self.certificate_reload_interval,
+                Duration::ZERO,
+                HOUR,
+            ),
+            (
+                "UPDATE_DEBOUNCE",
+                self.update_debounce,
+                Duration::ZERO,
+                HOUR,
+            ),
+            (
+                "WATCH_DISPATCHER_FIRST_RECONCILE",
+                self.watch_dispatcher_first_reconcile,
+                Duration::ZERO,
+                DAY,
+            ),
+            (
+                "WATCH_DISPATCHER_RECONCILE",
+                self.watch_dispatcher_reconcile,
+                Duration::from_secs(10),
+                DAY,
+            ),
+            (
+                "WATCH_DISPATCHER_DEBOUNCE",
+                self.watch_dispatcher_debounce,
+                Duration::ZERO,
+                HOUR,
+            ),
+            // Consul caps blocking queries at 10 minutes.
+            (
+                "CHECK_KEY_TIMEOUT",
+                self.check_key_timeout,
+                Duration::from_secs(1),
+                Duration::from_secs(10 * 60),
+            ),
+            ("CHECK_KEY_IDLE", self.check_key_idle, Duration::ZERO, DAY),
+            (
+                "CHECK_KEY_ERROR_WAIT",
+                self.check_key_error_wait,
+                Duration::from_secs(1),
+                HOUR,
+            ),
+            (
+                "WEBHOOK_CERTIFICATE_VALIDITY",
+                self.webhook_certificate_validity,
+                HOUR,
+                DAY * 3650,
+            ),
+            (
+                "WEBHOOK_CERTIFICATE_RENEW_BEFORE",
+                self.webhook_certificate_renew_before,
+                Duration::from_secs(60),
+                DAY * 3650,
+            ),
+            // Leases are renewed every third of their duration, in whole seconds.
+            (
+                "LEADER_ELECTION_LEASE_DURATION",
+                self.leader_election_lease_duration,
+                Duration::from_secs(3),
+                HOUR,
+            ),
+            (
+                "SHARD_LEASE_DURATION",
+                self.shard_lease_duration,
+                Duration::from_secs(3),
+                HOUR,
+            ),
+        ]
+    }
+
+    /// Returns the names of the settings that are different in `other`.
+    pub fn changed(&self, other: &Settings) -> Vec<String> {
+        match (serde_json::to_value(self), serde_json::to_value(other)) {
+            (Ok(serde_json::Value::Object(current)), Ok(serde_json::Value::Object(other))) => {
+                let mut changed: Vec<String> = current
+                    .iter()
+                    .filter(|(name, value)| other.get(name.as_str()) != Some(value))
+                    .map(|(name, _)| name.clone())
+                    .collect();
+                changed.sort();
+                changed
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Returns a copy of the settings with the values of `RELOADABLE_SETTINGS`
+    /// taken from `loaded`.
+    pub fn with_reloadable(&self, loaded: &Settings) -> Settings {
+        Settings {
+            update_debounce: loaded.update_debounce,
+            watch_dispatcher_reconcile: loaded.watch_dispatcher_reconcile,
+            watch_dispatcher_debounce: loaded.watch_dispatcher_debounce,
+            check_key_timeout: loaded.check_key_timeout,
+            check_key_idle: loaded.check_key_idle,
+            check_key_error_wait: loaded.check_key_error_wait,
+            consul_consistency_mode: loaded.consul_consistency_mode.clone(),
+            consul_max_stale: loaded.consul_max_stale,
+            set_deployment_annotations: loaded.set_deployment_annotations,
+            set_deployment_spec_annotations: loaded.set_deployment_spec_annotations,
+            set_deployment_timestamp: loaded.set_deployment_timestamp,
+            set_deployment_spec_timestamp: loaded.set_deployment_spec_timestamp,
+            audit_log: loaded.audit_log,
+            excluded_namespaces: loaded.excluded_namespaces.clone(),
+            label_selector: loaded.label_selector.clone(),
+            namespace_label_selector: loaded.namespace_label_selector.clone(),
+            ..self.clone()
+        }
+    }
+
+    /// Returns a copy of the settings with secrets replaced.
+    pub fn redacted(&self) -> Settings {
+        let mut settings = self.clone();
+        if !settings.admin_token.is_empty() {
+            settings.admin_token = REDACTED.to_string();
+        }
+        settings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(file: &[(&str, &str)], env: &[(&str, &str)]) -> ConfigSource {
+        ConfigSource {
+            file: file
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            env: env
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn settings_from_source() {
+        let settings = SettingsBuilder::from_source(&source(
+            &[("port", "9090"), ("update_debounce", "30")],
+            &[
+                ("UPDATE_DEBOUNCE", "45"),
+                ("CHECK_KEY_TIMEOUT", "1m30s"),
+                ("NAMESPACES", "a, b,"),
+            ],
+        ))
+        .expect("source should be valid")
+        .build()
+        .expect("settings should build");
+
+        assert_eq!(settings.port, 9090);
+        assert_eq!(settings.update_debounce, Duration::from_secs(45));
+        assert_eq!(settings.check_key_timeout, Duration::from_secs(90));
+        assert_eq!(settings.namespaces, vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(settings.secure_port, 8443);
+        assert!(settings.set_deployment_annotations);
+    }
+
+    #[test]
+    fn settings_from_source_reports_all_errors() {
+        let errors = SettingsBuilder::from_source(&source(
+            &[("bogus", "1")],
+            &[("PORT", "abc"), ("SET_DEPLOYMENT_ANNOTATIONS", "yes")],
+        ))
+        .expect_err("source should be invalid");
+
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].starts_with("PORT:"));
+        assert!(errors[1].starts_with("SET_DEPLOYMENT_ANNOTATIONS:"));
+        assert_eq!(errors[2], "bogus: unknown setting");
+    }
+
+    #[test]
+    fn settings_report_parse_and_validation_errors() {
+        let errors = settings_from_source(&source(
+            &[],
+            &[("PORT", "abc"), ("CONSUL_MAX_CONCURRENT_QUERIES", "0")],
+        ))
+        .expect_err("source should be invalid");
+
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("PORT:"));
+        assert_eq!(
+            errors[1],
+            "CONSUL_MAX_CONCURRENT_QUERIES must be greater than 0"
+        );
+    }
+
+    #[test]
+    fn config_file_formats() {
+        let toml = ConfigSource::parse_file(
+            "config.toml",
+            "port = 9090\nsharding = true\nnamespaces = [\"a\", \"b\"]\n",
+        )
+        .expect("toml should parse");
+        let yaml = ConfigSource::parse_file(
+            "config.yaml",
+            "port: 9090\nsharding: true\nnamespaces:\n  - a\n  - b\n",
+        )
+        .expect("yaml should parse");
+
+        assert_eq!(toml, yaml);
+        assert_eq!(toml.get("namespaces"), Some(&"a,b".to_string()));
+    }
+
+    #[test]
+    fn settings_duration_bounds() {
+        let settings = SettingsBuilder::default()
+            .check_key_timeout(Duration::from_secs(15 * 60))
+            .check_key_error_wait(Duration::ZERO)
+            .build()
+            .expect("settings should build");
+
+        let errors = settings.validate();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(
+            errors[0],
+            "CHECK_KEY_TIMEOUT: 15m must be between 1s and 10m"
+        );
+        assert!(errors[1].starts_with("CHECK_KEY_ERROR_WAIT:"));
+        assert!(SettingsBuilder::default()
+            .build()
+            .unwrap()
+            .validate()
+            .is_empty());
+    }
+
+    #[test]
+    fn reloadable_settings() {
+        let current = SettingsBuilder::default().build().unwrap();
+        let loaded = SettingsBuilder::default()
+            .port(9090u16)
+            .update_debounce(Duration::from_secs(5))
+            .label_selector("app=web")
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            current.changed(&loaded),
+            vec!["label_selector", "port", "update_debounce"]
+        );
+
+        let reloaded = current.with_reloadable(&loaded);
+        assert_eq!(
+            current.changed(&reloaded),
+            vec!["label_selector", "update_debounce"]
+        );
+        assert_eq!(reloaded.changed(&loaded), vec!["port"]);
+    }
+
+    #[test]
+    fn settings_consul_endpoint() {
+        let settings = SettingsBuilder::default()
+            .consul_cacert("/etc/consul/ca.pem")
+            .consul_client_cert("/etc/consul/client.pem")
+            .consul_tls_server_name("server.dc1.consul")
+            .build()
+            .expect("settings should build");
+
+        assert_eq!(
+            settings.validate(),
+            vec!["CONSUL_CLIENT_CERT and CONSUL_CLIENT_KEY must be set together".to_string()]
+        );
+        let endpoint = settings.consul_endpoint();
+        assert_eq!(endpoint.ca_cert.as_deref(), Some("/etc/consul/ca.pem"));
+        assert_eq!(endpoint.client_key, None);
+        assert_eq!(endpoint.token_file, None);
+        assert_eq!(
+            endpoint.tls_server_name.as_deref(),
+            Some("server.dc1.consul")
+        );
+    }
+
+    #[test]
+    fn settings_consul_clusters() {
+        let settings = SettingsBuilder::from_source(&source(
+            &[
+                ("consul_clusters", "legacy,next"),
+                ("consul_cluster_legacy_http_addr", "https://legacy:8501"),
+                (
+                    "consul_cluster_legacy_client_cert",
+                    "/etc/legacy/client.pem",
+                ),
+            ],
+            &[("CONSUL_CLUSTER_LEGACY_HTTP_TOKEN_FILE", "/etc/legacy/token")],
+        ))
+        .expect("source should be valid")
+        .build()
+        .expect("settings should build");
+
+        let legacy = settings.consul_cluster_endpoint("legacy");
+        assert_eq!(legacy.address.as_deref(), Some("https://legacy:8501"));
+        assert_eq!(legacy.token_file.as_deref(), Some("/etc/legacy/token"));
+        assert!(!legacy.from_environment);
+
+        assert_eq!(
+            settings.validate(),
+            vec![
+                "CONSUL_CLUSTER_LEGACY_CLIENT_CERT and CONSUL_CLUSTER_LEGACY_CLIENT_KEY must be set together".to_string(),
+                "CONSUL_CLUSTER_NEXT_HTTP_ADDR must be set".to_string(),
+            ]
+        );
+
+        // Settings of clusters that aren't listed are unknown.
+        assert!(SettingsBuilder::from_source(&source(
+            &[("consul_cluster_legacy_http_addr", "https://legacy:8501")],
+            &[],
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn redacted_settings() {
+        let settings = SettingsBuilder::default()
+            .admin_token("secret")
+            .build()
+            .expect("settings should build");
+        assert_eq!(settings.redacted().admin_token, REDACTED);
     }
 }
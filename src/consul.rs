@@ -1,19 +1,773 @@
+use anyhow::anyhow;
+use backoff::{backoff::Backoff, ExponentialBackoff, ExponentialBackoffBuilder};
 use chrono::{DateTime, Duration, Utc};
 use consulrs::{
-    api::{features::Blocking, kv::requests::ReadKeyRequest, Features},
-    client::{ConsulClient, ConsulClientSettings},
-    kv,
+    api::{
+        exec_with_result,
+        features::{Blocking, ConsistencyMode},
+        kv::responses::ReadKeyResponse,
+        ApiResponse, Features,
+    },
+    client::{ConsulClient, ConsulClientSettings, ConsulClientSettingsBuilder},
+    error::ClientError,
 };
+use kube::runtime::events::EventType;
+use parking_lot::{Mutex, RwLock};
+use rustify::clients::reqwest::Client as HTTPClient;
 use std::error::Error;
-use std::{collections::HashSet, convert::TryInto};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::TryInto,
+    fs,
+    net::SocketAddr,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
+use crate::address::KeyAddress;
+use crate::config::Settings;
+use crate::duration::format_duration;
+use crate::error::Result;
+use crate::events::{REASON_KEY_MISSING, REASON_KEY_UNREADABLE};
+use crate::key_manager::{ChecksumChange, KeyStatus, Subscription};
 use crate::state::{AppState, ConsulWatch, DeploymentUpdate};
+use crate::telemetry::{consul_key_link, deployment_link};
 use tokio::{
-    sync::mpsc::Receiver,
+    sync::{mpsc::Receiver, Notify, Semaphore, SemaphorePermit},
     time::{sleep, Instant},
 };
 use tokio_tasker::Stopper;
-use tracing::{debug, error, info, trace, warn};
+use tracing::{debug, error, info, info_span, instrument, trace, warn, Instrument, Span};
+
+/// The consul client of a cluster, shared by every key watcher and consul read
+/// of the cluster. The number of outstanding requests, most of which are
+/// blocking queries, is limited, which also bounds the number of connections
+/// that are kept open to consul.
+///
+/// When the ACL token is read from a file, the client is replaced whenever
+/// the token in the file changes. Queries that are already running keep the
+/// client they started with.
+pub struct SharedConsulClient {
+    client: RwLock<Arc<ConsulClient>>,
+    endpoint: ConsulEndpoint,
+    token: Mutex<Option<String>>,
+    queries: Semaphore,
+    limiter: Arc<RequestLimiter>,
+}
+
+/// How a consul cluster is reached. When `from_environment` is set, values
+/// that aren't set fall back to the `CONSUL_HTTP_*` environment variables that
+/// the consul client reads.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConsulEndpoint {
+    pub address: Option<String>,
+    pub token_file: Option<String>,
+    pub ca_cert: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub tls_server_name: Option<String>,
+    pub from_environment: bool,
+}
+
+/// The consul clients of the default cluster and of each named cluster in
+/// `CONSUL_CLUSTERS`. Each cluster has its own query limit, and all clusters
+/// share the request rate limit.
+pub struct ConsulClients {
+    default: SharedConsulClient,
+    named: HashMap<String, SharedConsulClient>,
+    limiter: Arc<RequestLimiter>,
+}
+
+impl ConsulClients {
+    pub fn new(settings: &Settings) -> Result<Self> {
+        let max_concurrent_queries = settings.consul_max_concurrent_queries as usize;
+        let limiter = Arc::new(RequestLimiter::new(settings.consul_request_rate));
+        let mut named = HashMap::new();
+        for cluster in settings.consul_clusters.iter() {
+            let client = SharedConsulClient::new(
+                settings.consul_cluster_endpoint(cluster),
+                max_concurrent_queries,
+                limiter.clone(),
+            )
+            .map_err(|err| anyhow!("consul cluster {cluster}: {err}"))?;
+            named.insert(cluster.clone(), client);
+        }
+        Ok(Self {
+            default: SharedConsulClient::new(
+                settings.consul_endpoint(),
+                max_concurrent_queries,
+                limiter.clone(),
+            )?,
+            named,
+            limiter,
+        })
+    }
+
+    pub fn limiter(&self) -> &RequestLimiter {
+        &self.limiter
+    }
+
+    /// Returns the client of a named cluster, or of the default cluster.
+    pub fn get(&self, cluster: Option<&str>) -> Result<&SharedConsulClient> {
+        match cluster {
+            Some(cluster) => self
+                .named
+                .get(cluster)
+                .ok_or_else(|| anyhow!("unknown consul cluster: {cluster}")),
+            None => Ok(&self.default),
+        }
+    }
+
+    pub fn has_token_files(&self) -> bool {
+        self.all()
+            .iter()
+            .any(|(_, client)| client.token_file().is_some())
+    }
+
+    /// Returns the client of every cluster. The default cluster has no name.
+    pub fn all(&self) -> Vec<(Option<&str>, &SharedConsulClient)> {
+        let mut clients = vec![(None, &self.default)];
+        clients.extend(
+            self.named
+                .iter()
+                .map(|(cluster, client)| (Some(cluster.as_str()), client)),
+        );
+        clients
+    }
+}
+
+/// A consul client that may be used for one request.
+pub struct ConsulQuery<'a> {
+    client: Arc<ConsulClient>,
+    limiter: &'a RequestLimiter,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl SharedConsulClient {
+    /// Creates the client, failing when the token, CA, or client certificate
+    /// files can't be read.
+    pub fn new(
+        endpoint: ConsulEndpoint,
+        max_concurrent_queries: usize,
+        limiter: Arc<RequestLimiter>,
+    ) -> Result<Self> {
+        let token = match endpoint.token_file.as_deref() {
+            Some(path) => Some(read_token(path)?),
+            None => None,
+        };
+        Ok(Self {
+            client: RwLock::new(Arc::new(build_client(&endpoint, token.as_deref())?)),
+            endpoint,
+            token: Mutex::new(token),
+            queries: Semaphore::new(max_concurrent_queries),
+            limiter,
+        })
+    }
+
+    /// Waits until fewer than the maximum number of requests are outstanding
+    /// and the request rate allows another request. The request slot is
+    /// released when the query is dropped.
+    pub async fn acquire(&self) -> ConsulQuery<'_> {
+        let permit = self
+            .queries
+            .acquire()
+            .await
+            .expect("consul query semaphore is never closed");
+        self.limiter.wait().await;
+        ConsulQuery {
+            client: self.client.read().clone(),
+            limiter: &self.limiter,
+            _permit: permit,
+        }
+    }
+
+    /// Returns the number of requests that can start without waiting.
+    pub fn available(&self) -> usize {
+        self.queries.available_permits()
+    }
+
+    pub fn token_file(&self) -> Option<&str> {
+        self.endpoint.token_file.as_deref()
+    }
+
+    /// Reads the token file again and replaces the client when the token
+    /// changed. Returns true when the client was replaced.
+    pub fn reload_token(&self) -> Result<bool> {
+        let path = match self.endpoint.token_file.as_deref() {
+            Some(path) => path,
+            None => return Ok(false),
+        };
+        let token = read_token(path)?;
+
+        let mut current = self.token.lock();
+        if current.as_deref() == Some(token.as_str()) {
+            return Ok(false);
+        }
+        *self.client.write() = Arc::new(build_client(&self.endpoint, Some(&token))?);
+        *current = Some(token);
+        Ok(true)
+    }
+}
+
+/// Creates a consul client for the endpoint. A token that was read from a file
+/// takes precedence over `CONSUL_HTTP_TOKEN`.
+fn build_client(endpoint: &ConsulEndpoint, token: Option<&str>) -> Result<ConsulClient> {
+    let mut builder = ConsulClientSettingsBuilder::default();
+    if !endpoint.from_environment {
+        builder.token("");
+        builder.ca_certs(vec![]);
+        builder.client_cert(None);
+        builder.client_key(None);
+    }
+    if let Some(address) = endpoint.address.as_deref() {
+        builder.address(address);
+    }
+    if let Some(token) = token {
+        builder.token(token);
+    }
+    if let Some(ca_cert) = endpoint.ca_cert.as_deref() {
+        check_file("CONSUL_CACERT", ca_cert)?;
+        builder.ca_certs(vec![ca_cert.to_string()]);
+    }
+    if let (Some(client_cert), Some(client_key)) = (
+        endpoint.client_cert.as_deref(),
+        endpoint.client_key.as_deref(),
+    ) {
+        check_file("CONSUL_CLIENT_CERT", client_cert)?;
+        check_file("CONSUL_CLIENT_KEY", client_key)?;
+        builder.client_cert(Some(client_cert.to_string()));
+        builder.client_key(Some(client_key.to_string()));
+    }
+    let mut client = ConsulClient::new(builder.build()?)?;
+    if let Some(server_name) = endpoint.tls_server_name.as_deref() {
+        client.http = server_name_client(&client.settings, server_name)?;
+    }
+    Ok(client)
+}
+
+/// Builds an HTTP client that connects to the consul address but verifies the
+/// server certificate against `server_name`, which is also sent with SNI. The
+/// CA and client certificates are loaded the same way that the consul client
+/// loads them.
+fn server_name_client(settings: &ConsulClientSettings, server_name: &str) -> Result<HTTPClient> {
+    let (address, resolved) = server_name_address(&settings.address, server_name)?;
+
+    let mut builder = reqwest::ClientBuilder::new()
+        .danger_accept_invalid_certs(!settings.verify)
+        .resolve(server_name, resolved);
+    for path in settings.ca_certs.iter() {
+        builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&fs::read(path)?)?);
+    }
+    if let (Some(cert), Some(key)) = (&settings.client_cert, &settings.client_key) {
+        let pem = format!("{}{}", fs::read_to_string(cert)?, fs::read_to_string(key)?);
+        builder = builder.identity(reqwest::Identity::from_pem(pem.as_bytes())?);
+    }
+
+    Ok(HTTPClient::new(&address, builder.build()?))
+}
+
+/// Returns the consul address with its host replaced by `server_name`, and the
+/// socket address that the host of the address resolves to, which requests to
+/// the server name are sent to.
+fn server_name_address(address: &str, server_name: &str) -> Result<(String, SocketAddr)> {
+    let mut url =
+        reqwest::Url::parse(address).map_err(|err| anyhow!("consul address: {address}: {err}"))?;
+    let resolved = url
+        .socket_addrs(|| None)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("consul address doesn't resolve: {address}"))?;
+    url.set_host(Some(server_name))
+        .map_err(|err| anyhow!("CONSUL_TLS_SERVER_NAME: {server_name}: {err}"))?;
+
+    // The consul client appends paths to the address as it was configured.
+    let mut rewritten = url.to_string();
+    if !address.ends_with('/') {
+        rewritten = rewritten.trim_end_matches('/').to_string();
+    }
+    Ok((rewritten, resolved))
+}
+
+/// Fails with the name of the setting when a file can't be read, which is
+/// clearer than the TLS error the consul client would return.
+fn check_file(name: &str, path: &str) -> Result<()> {
+    fs::File::open(path)
+        .map(|_| ())
+        .map_err(|err| anyhow!("{name}: {path}: {err}"))
+}
+
+fn read_token(path: &str) -> Result<String> {
+    let token = fs::read_to_string(path)
+        .map_err(|err| anyhow!("CONSUL_HTTP_TOKEN_FILE: {path}: {err}"))?
+        .trim()
+        .to_string();
+    if token.is_empty() {
+        return Err(anyhow!("consul token file is empty: {path}"));
+    }
+    Ok(token)
+}
+
+impl ConsulQuery<'_> {
+    /// Records the outcome of the request with the rate limiter.
+    fn record<T>(
+        &self,
+        started: Instant,
+        blocking: bool,
+        result: &std::result::Result<T, ClientError>,
+    ) {
+        self.limiter.record(started.elapsed(), blocking, result);
+    }
+}
+
+/// The lowest rate that the request rate is lowered to while consul is
+/// overloaded, in requests per second.
+const MIN_REQUEST_RATE: f64 = 1.0;
+
+/// The weight of the latest request in the error rate and latency averages.
+const AVERAGE_WEIGHT: f64 = 0.05;
+
+/// A token bucket that limits the rate at which consul requests start to
+/// `CONSUL_REQUEST_RATE`, with bursts of up to one second of requests. While
+/// consul answers with 429 or 5xx responses, the rate is halved, at most once
+/// a second, and each successful request restores a hundredth of the limit.
+///
+/// A limit of zero doesn't limit requests until consul is overloaded. Requests
+/// are then limited to half of the rate that they were started at, which is
+/// restored the same way, and the limit is lifted once it is fully restored.
+pub struct RequestLimiter {
+    max_rate: f64,
+    state: Mutex<LimiterState>,
+    metrics: RequestMetrics,
+}
+
+struct LimiterState {
+    /// The current rate, or 0 when requests aren't limited.
+    rate: f64,
+    /// The rate that successful requests restore the current rate to.
+    ceiling: f64,
+    tokens: f64,
+    updated: Instant,
+    lowered: Option<Instant>,
+    error_rate: f64,
+    latency: f64,
+    window_started: Instant,
+    window_requests: u64,
+    observed_rate: f64,
+}
+
+impl LimiterState {
+    /// Counts a request that started, and measures the rate at which
+    /// requests start once a second.
+    fn observe(&mut self, now: Instant) {
+        self.window_requests += 1;
+        let elapsed = now.saturating_duration_since(self.window_started);
+        if elapsed >= std::time::Duration::from_secs(1) {
+            self.observed_rate = self.window_requests as f64 / elapsed.as_secs_f64();
+            self.window_started = now;
+            self.window_requests = 0;
+        }
+    }
+
+    /// Returns the rate at which requests started recently, which is the
+    /// higher of the last measured rate and the rate of the current second.
+    fn observed_rate(&self, now: Instant) -> f64 {
+        let elapsed = now
+            .saturating_duration_since(self.window_started)
+            .as_secs_f64()
+            .max(1.0);
+        (self.window_requests as f64 / elapsed).max(self.observed_rate)
+    }
+}
+
+/// Counters of consul requests, rendered by `RequestLimiter::render_metrics`.
+#[derive(Default)]
+struct RequestMetrics {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    overloaded: AtomicU64,
+    delayed: AtomicU64,
+    delay_micros: AtomicU64,
+}
+
+impl RequestLimiter {
+    pub fn new(max_rate: u16) -> Self {
+        let max_rate = max_rate as f64;
+        let now = Instant::now();
+        Self {
+            max_rate,
+            state: Mutex::new(LimiterState {
+                rate: max_rate,
+                ceiling: max_rate,
+                tokens: max_rate,
+                updated: now,
+                lowered: None,
+                error_rate: 0.0,
+                latency: 0.0,
+                window_started: now,
+                window_requests: 0,
+                observed_rate: 0.0,
+            }),
+            metrics: RequestMetrics::default(),
+        }
+    }
+
+    /// Waits until the request rate allows another request.
+    async fn wait(&self) {
+        if let Some(delay) = self.reserve(Instant::now()) {
+            self.metrics.delayed.fetch_add(1, Ordering::Relaxed);
+            self.metrics
+                .delay_micros
+                .fetch_add(delay.as_micros() as u64, Ordering::Relaxed);
+            sleep(delay).await;
+        }
+    }
+
+    /// Takes a token from the bucket and returns how long to wait until the
+    /// token is available. Tokens are handed out in order, so the bucket can
+    /// go below zero while requests wait.
+    fn reserve(&self, now: Instant) -> Option<std::time::Duration> {
+        let mut state = self.state.lock();
+        state.observe(now);
+        if state.rate == 0.0 {
+            return None;
+        }
+        let elapsed = now.saturating_duration_since(state.updated).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * state.rate).min(state.rate);
+        state.updated = now;
+        state.tokens -= 1.0;
+        if state.tokens >= 0.0 {
+            None
+        } else {
+            Some(std::time::Duration::from_secs_f64(
+                -state.tokens / state.rate,
+            ))
+        }
+    }
+
+    /// Records the outcome of a request. Missing keys aren't failures.
+    fn record<T>(
+        &self,
+        elapsed: std::time::Duration,
+        blocking: bool,
+        result: &std::result::Result<T, ClientError>,
+    ) {
+        let (failed, overloaded) = match result {
+            Ok(_) => (false, false),
+            Err(ClientError::APIError { code: 404, .. }) => (false, false),
+            Err(ClientError::APIError { code, .. }) => (true, *code == 429 || *code >= 500),
+            Err(_) => (true, false),
+        };
+        self.record_outcome(elapsed, blocking, failed, overloaded);
+    }
+
+    /// The latency of blocking queries is left out of the average because
+    /// they wait for changes.
+    fn record_outcome(
+        &self,
+        elapsed: std::time::Duration,
+        blocking: bool,
+        failed: bool,
+        overloaded: bool,
+    ) {
+        self.metrics.requests.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            self.metrics.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        if overloaded {
+            self.metrics.overloaded.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let now = Instant::now();
+        let mut state = self.state.lock();
+        state.error_rate += AVERAGE_WEIGHT * (if failed { 1.0 } else { 0.0 } - state.error_rate);
+        if !blocking {
+            state.latency += AVERAGE_WEIGHT * (elapsed.as_secs_f64() - state.latency);
+        }
+
+        if overloaded {
+            let recently_lowered = state.lowered.map_or(false, |lowered| {
+                now.saturating_duration_since(lowered) < std::time::Duration::from_secs(1)
+            });
+            if !recently_lowered {
+                if state.rate == 0.0 {
+                    state.ceiling = state.observed_rate(now).max(MIN_REQUEST_RATE);
+                    state.rate = state.ceiling;
+                    state.tokens = 0.0;
+                    state.updated = now;
+                }
+                state.rate = (state.rate / 2.0).max(MIN_REQUEST_RATE);
+                state.lowered = Some(now);
+                warn!(
+                    "consul request limiter lowered the request rate: {:.1}/s",
+                    state.rate
+                );
+            }
+        } else if !failed && state.rate > 0.0 {
+            state.rate = (state.rate + state.ceiling / 100.0).min(state.ceiling);
+            if self.max_rate == 0.0 && state.rate >= state.ceiling {
+                state.rate = 0.0;
+                info!("consul request limiter lifted the request rate limit");
+            }
+        }
+    }
+
+    /// Renders the request metrics in the Prometheus text format.
+    pub fn render_metrics(&self) -> String {
+        let (rate, error_rate, latency) = {
+            let state = self.state.lock();
+            (state.rate, state.error_rate, state.latency)
+        };
+        let counter = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        let metrics = [
+            (
+                "consul_requests_total",
+                "counter",
+                "Consul requests that completed.",
+                counter(&self.metrics.requests) as f64,
+            ),
+            (
+                "consul_request_errors_total",
+                "counter",
+                "Consul requests that failed.",
+                counter(&self.metrics.errors) as f64,
+            ),
+            (
+                "consul_requests_overloaded_total",
+                "counter",
+                "Consul requests that failed with a 429 or 5xx response.",
+                counter(&self.metrics.overloaded) as f64,
+            ),
+            (
+                "consul_requests_delayed_total",
+                "counter",
+                "Consul requests that waited for the request rate limit.",
+                counter(&self.metrics.delayed) as f64,
+            ),
+            (
+                "consul_request_delay_seconds_total",
+                "counter",
+                "Time that consul requests waited for the request rate limit.",
+                counter(&self.metrics.delay_micros) as f64 / 1_000_000.0,
+            ),
+            (
+                "consul_request_rate_limit",
+                "gauge",
+                "The current consul request rate limit, or 0 when unlimited.",
+                rate,
+            ),
+            (
+                "consul_request_error_ratio",
+                "gauge",
+                "The moving average of the share of failed consul requests.",
+                error_rate,
+            ),
+            (
+                "consul_request_latency_seconds",
+                "gauge",
+                "The moving average of the latency of non-blocking consul requests.",
+                latency,
+            ),
+        ];
+
+        let mut rendered = String::new();
+        for (name, kind, help, value) in metrics {
+            let name = format!("k8s_consul_mutator_{name}");
+            rendered.push_str(&format!("# HELP {name} {help}\n"));
+            rendered.push_str(&format!("# TYPE {name} {kind}\n"));
+            rendered.push_str(&format!("{name} {value}\n"));
+        }
+        rendered
+    }
+}
+
+impl Deref for ConsulQuery<'_> {
+    type Target = ConsulClient;
+
+    fn deref(&self) -> &Self::Target {
+        &self.client
+    }
+}
+
+/// The wait between failed consul reads of a watcher. Waits start at one
+/// second and double, with jitter, up to `CHECK_KEY_ERROR_WAIT`, so that
+/// watchers don't retry in lockstep after a consul outage.
+struct ErrorBackoff {
+    backoff: ExponentialBackoff,
+}
+
+impl ErrorBackoff {
+    fn new(max_wait: std::time::Duration) -> Self {
+        Self {
+            backoff: ExponentialBackoffBuilder::new()
+                .with_initial_interval(max_wait.min(std::time::Duration::from_secs(1)))
+                .with_max_interval(max_wait)
+                .with_multiplier(2.0)
+                .with_randomization_factor(0.5)
+                .with_max_elapsed_time(None)
+                .build(),
+        }
+    }
+
+    /// Returns the next wait. A changed maximum wait restarts the backoff.
+    fn next_wait(&mut self, max_wait: std::time::Duration) -> std::time::Duration {
+        if self.backoff.max_interval != max_wait {
+            *self = Self::new(max_wait);
+        }
+        // Jitter is applied after the interval is capped, so it can exceed
+        // the maximum.
+        self.backoff
+            .next_backoff()
+            .unwrap_or(max_wait)
+            .min(max_wait)
+    }
+
+    /// Sleeps for the next wait, returning early when the stopper is stopped.
+    async fn wait(&mut self, max_wait: std::time::Duration, stopper: &Stopper) {
+        let wait = self.next_wait(max_wait);
+        tokio::select! {
+            _ = sleep(wait) => {},
+            _ = stopper.clone() => {},
+        }
+    }
+
+    fn reset(&mut self) {
+        self.backoff.reset();
+    }
+}
+
+/// The consistency modes of consul reads. `default` reads may be served by a
+/// leader that has just lost leadership, `stale` reads may be served by any
+/// server, and `consistent` reads are confirmed with a quorum.
+pub const CONSUL_CONSISTENCY_MODES: &[&str] = &["default", "stale", "consistent"];
+
+/// The prefix of the annotations that set the consistency mode of the key in
+/// the `k8s-consul-mutator.io/key-*` annotation with the same suffix.
+pub const CONSISTENCY_ANNOTATION_PREFIX: &str = "k8s-consul-mutator.io/consistency-";
+
+/// Returns the consistency mode that the annotations of a workload ask the key
+/// of a config key to be read with, if any.
+pub fn consistency_annotation(
+    annotations: &BTreeMap<String, String>,
+    config_key: &str,
+) -> Result<Option<String>> {
+    match annotations.get(&format!("{CONSISTENCY_ANNOTATION_PREFIX}{config_key}")) {
+        Some(mode) if CONSUL_CONSISTENCY_MODES.contains(&mode.as_str()) => Ok(Some(mode.clone())),
+        Some(mode) => Err(anyhow!(
+            "invalid consul consistency mode: {config_key}: {mode}"
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Returns the consistency mode that keys are read with. Subscriptions to the
+/// same key may ask for different modes, so the strongest one is used:
+/// `consistent`, then `default`, then `stale`. Subscriptions without a mode
+/// use `CONSUL_CONSISTENCY_MODE`.
+async fn consistency_mode(app_state: &AppState, consul_keys: &[String], default: &str) -> String {
+    let strength = |mode: &str| match mode {
+        "stale" => 0,
+        "consistent" => 2,
+        _ => 1,
+    };
+
+    let mut modes = vec![];
+    for consul_key in consul_keys {
+        match app_state
+            .key_manager
+            .consistency_modes(consul_key.clone())
+            .await
+        {
+            Ok(found) => modes.extend(found),
+            Err(err) => warn!(consul_key = %consul_key, "consul key watcher error: {err}"),
+        }
+    }
+
+    modes
+        .into_iter()
+        .map(|mode| mode.unwrap_or_else(|| default.to_string()))
+        .max_by_key(|mode| strength(mode))
+        .unwrap_or_else(|| default.to_string())
+}
+
+/// Reads a key, or every key under a prefix, in a consistency mode. When a
+/// stale read was served by a server that hasn't heard from the leader within
+/// `CONSUL_MAX_STALE`, the key is read again consistently without blocking.
+async fn read_keys(
+    query: &ConsulQuery<'_>,
+    address: &KeyAddress,
+    recurse: bool,
+    blocking: Option<Blocking>,
+    mode: &str,
+    settings: &Settings,
+) -> std::result::Result<ApiResponse<Vec<ReadKeyResponse>>, ClientError> {
+    let response = read_keys_once(query, address, recurse, blocking, mode).await?;
+
+    if mode == "stale" && is_too_stale(response.last_contact.as_deref(), settings.consul_max_stale)
+    {
+        debug!(
+            "consul read is too stale: {address}: last contact {}ms",
+            response.last_contact.as_deref().unwrap_or_default()
+        );
+        query.limiter.wait().await;
+        return read_keys_once(query, address, recurse, None, "consistent").await;
+    }
+
+    Ok(response)
+}
+
+async fn read_keys_once(
+    query: &ConsulQuery<'_>,
+    address: &KeyAddress,
+    recurse: bool,
+    blocking: Option<Blocking>,
+    mode: &str,
+) -> std::result::Result<ApiResponse<Vec<ReadKeyResponse>>, ClientError> {
+    let is_blocking = blocking.is_some();
+    let mut features = Features::builder();
+    if let Some(blocking) = blocking {
+        features.blocking(blocking);
+    }
+    match mode {
+        "stale" => {
+            features.consistency(ConsistencyMode::Stale);
+        }
+        "consistent" => {
+            features.consistency(ConsistencyMode::Consistent);
+        }
+        _ => {}
+    }
+
+    let mut request = address.read_request();
+    request.features(features.build().unwrap());
+    if recurse {
+        request.recurse(true);
+    }
+    let request = request.build().unwrap();
+
+    let started = Instant::now();
+    let result = exec_with_result(&**query, request).await;
+    query.record(started, is_blocking, &result);
+    result
+}
+
+/// Returns true when the `X-Consul-LastContact` milliseconds of a response are
+/// more than the maximum staleness. A zero maximum disables the check.
+fn is_too_stale(last_contact: Option<&str>, max_stale: std::time::Duration) -> bool {
+    if max_stale.is_zero() {
+        return false;
+    }
+    match last_contact.and_then(|value| value.parse::<u128>().ok()) {
+        Some(last_contact) => last_contact > max_stale.as_millis(),
+        None => false,
+    }
+}
+
+/// The log target of audit log lines.
+const AUDIT_TARGET: &str = "k8s_consul_mutator_rs::audit";
 
 /// This function is used to watch for changes to a consul key. When key values
 /// do change, a checksum is generated, the key manager is updated, and a
@@ -22,32 +776,49 @@ use tracing::{debug, error, info, trace, warn};
 /// When the key manager has no subscribers, the watcher will idle for a period
 /// of time. If during that idle period there are still no subscribers, the
 /// function will notify the consul manager and exit.
-pub async fn check_key(
-    consul_config: ConsulClientSettings,
-    consul_key: String,
-    timeout: String,
-    stopper: Stopper,
-    app_state: AppState,
-) {
-    info!("consul key watcher started: {consul_key}");
+pub async fn check_key(consul_key: String, stopper: Stopper, app_state: AppState) {
+    info!(consul_key = %consul_key, "consul key watcher started");
 
-    let consul_client_maybe = ConsulClient::new(consul_config);
-    if let Err(ref err) = consul_client_maybe {
-        warn!("consul key watcher error: {consul_key}: {err}");
-        return;
-    }
-    let consul_client = consul_client_maybe.unwrap();
+    let (address, consul) = match resolve(&app_state, &consul_key) {
+        Ok(resolved) => resolved,
+        Err(err) => {
+            error!(consul_key = %consul_key, "consul key watcher error: {err}");
+            return;
+        }
+    };
 
-    let mut key_index = 0;
+    let mut backoff = ErrorBackoff::new(app_state.settings().check_key_error_wait);
+    // The index of the next blocking query and the modify index of the last
+    // value that was read. These differ when other keys change.
+    let mut query_index = 0;
+    let mut modify_index: Option<u64> = None;
     let mut stop_countdown: Option<DateTime<Utc>> = None;
-
-    let idle_duration = chrono::Duration::seconds(app_state.settings.check_key_idle as i64);
-    let error_wait_duration =
-        chrono::Duration::seconds(app_state.settings.check_key_error_wait as i64);
+    // The reason of the last event that was recorded for the key, so that an
+    // event is only recorded when the key starts failing in a different way.
+    let mut reported: Option<&'static str> = None;
 
     while !stopper.is_stopped() {
         let now = Utc::now();
 
+        let settings = app_state.settings();
+        let idle_duration = chrono::Duration::from_std(settings.check_key_idle).unwrap();
+        let error_wait_duration = settings.check_key_error_wait;
+        let timeout = format_duration(&settings.check_key_timeout);
+
+        if !app_state.leadership.is_leader() {
+            warn!(consul_key = %consul_key, "consul key watcher stopping because leadership was lost");
+
+            if let Err(err) = app_state
+                .consul_manager_tx
+                .send(ConsulWatch::Destroy(consul_key.clone(), now))
+                .await
+            {
+                error!(consul_key = %consul_key, "consul key watcher error: {err}");
+            }
+
+            break;
+        }
+
         if app_state
             .key_manager
             .consul_key_subscriber_count(consul_key.clone())
@@ -56,17 +827,17 @@ pub async fn check_key(
             == 0
         {
             if stop_countdown.is_none() {
-                warn!("consul key watcher preparing to stop:{consul_key}");
+                warn!(consul_key = %consul_key, "consul key watcher preparing to stop");
                 stop_countdown = Some(Utc::now() + idle_duration);
             } else if Utc::now() > stop_countdown.unwrap() {
-                warn!("consul key watcher stopping because of inactivity: {consul_key}");
+                warn!(consul_key = %consul_key, "consul key watcher stopping because of inactivity");
 
                 if let Err(err) = app_state
                     .consul_manager_tx
                     .send(ConsulWatch::Destroy(consul_key.clone(), now))
                     .await
                 {
-                    error!("consul key watcher error: {consul_key}: {err}");
+                    error!(consul_key = %consul_key, "consul key watcher error: {err}");
                 }
 
                 break;
@@ -75,22 +846,45 @@ pub async fn check_key(
             stop_countdown = None;
         }
 
-        let wait_res = kv::read(
-            &consul_client,
-            &consul_key,
-            Some(
-                ReadKeyRequest::builder().features(
-                    Features::builder()
-                        .blocking(Blocking {
-                            index: key_index,
-                            wait: Some(timeout.clone()),
-                        })
-                        .build()
-                        .unwrap(),
-                ),
-            ),
+        // Each read has its own span because the watcher runs for as long as
+        // the key has subscribers. The first read follows from the admission
+        // request that subscribed to the key.
+        let read_span = info_span!(
+            "consul_read",
+            consul_key = %consul_key,
+            index = query_index,
+            checksum = tracing::field::Empty,
+        );
+        app_state
+            .span_links
+            .follow(&read_span, &consul_key_link(&consul_key));
+
+        let mode = consistency_mode(
+            &app_state,
+            std::slice::from_ref(&consul_key),
+            &settings.consul_consistency_mode,
+        )
+        .await;
+
+        let query = tokio::select! {
+            query = consul.acquire() => query,
+            _ = stopper.clone() => break,
+        };
+
+        let wait_res = read_keys(
+            &query,
+            &address,
+            false,
+            Some(Blocking {
+                index: query_index,
+                wait: Some(timeout),
+            }),
+            &mode,
+            &settings,
         )
+        .instrument(read_span.clone())
         .await;
+        drop(query);
 
         if stopper.is_stopped() {
             break;
@@ -99,35 +893,79 @@ pub async fn check_key(
         if let Err(err) = wait_res {
             if let Some(source) = err.source() {
                 error!(
-                    "consul key watcher error: {consul_key}: {:?} - {:?}",
-                    err, source
+                    consul_key = %consul_key,
+                    "consul key watcher error: {:?} - {:?}", err, source
                 );
             } else {
-                error!("consul key watcher error: {consul_key}: {:?}", err);
+                error!(consul_key = %consul_key, "consul key watcher error: {:?}", err);
             }
-            sleep(error_wait_duration.to_std().unwrap()).await;
+            reload_denied_token(consul, &err);
+            let (found, reason, problem) = read_failure(&err);
+            key_failed(
+                &app_state,
+                &consul_key,
+                found,
+                reason,
+                &problem,
+                &mut reported,
+                now,
+            )
+            .await;
+            backoff.wait(error_wait_duration, &stopper).await;
             continue;
         }
 
         let mut wait_success = wait_res.unwrap();
 
         if wait_success.response.is_empty() {
-            warn!("watch {consul_key} error: no keys returned from consul for key");
-            sleep(error_wait_duration.to_std().unwrap()).await;
+            warn!(consul_key = %consul_key, "consul key watcher error: no keys returned from consul for key");
+            key_failed(
+                &app_state,
+                &consul_key,
+                Some(false),
+                REASON_KEY_MISSING,
+                "does not exist",
+                &mut reported,
+                now,
+            )
+            .await;
+            backoff.wait(error_wait_duration, &stopper).await;
             continue;
         }
 
         let kv = wait_success.response.pop().unwrap();
-        if kv.modify_index == key_index {
-            trace!("consul key watcher error: {consul_key}: modify index is the same as last time {key_index}");
+        query_index = next_index(
+            query_index,
+            response_index(wait_success.index.as_deref()).unwrap_or(kv.modify_index),
+        );
+
+        if modify_index == Some(kv.modify_index) {
+            trace!(
+                consul_key = %consul_key,
+                "consul key watcher error: modify index is the same as last time {}",
+                kv.modify_index
+            );
+            backoff.reset();
+            reported = None;
+            key_unchanged(&app_state, &consul_key, kv.modify_index, now).await;
             continue;
         }
 
-        key_index = kv.modify_index;
+        modify_index = Some(kv.modify_index);
 
         if kv.value.is_none() {
-            warn!("consul key watcher error: {consul_key}: value option is none");
-            sleep(error_wait_duration.to_std().unwrap()).await;
+            warn!(consul_key = %consul_key, "consul key watcher error: value option is none");
+            key_failed(
+                &app_state,
+                &consul_key,
+                Some(false),
+                REASON_KEY_MISSING,
+                "has no value",
+                &mut reported,
+                now,
+            )
+            .await;
+            backoff.wait(error_wait_duration, &stopper).await;
             continue;
         }
 
@@ -135,45 +973,718 @@ pub async fn check_key(
             break;
         }
 
-        let key_content = kv.value.unwrap().try_into().unwrap_or(Vec::new());
-        let digest = app_state.checksummer.checksum(key_content);
+        reported = None;
+        backoff.reset();
 
-        debug!("consul key watcher checksum: {consul_key} {digest}");
-        if let Err(err) = app_state.key_manager.set(consul_key.clone(), digest).await {
-            warn!("consul key watcher error: {consul_key}: {err}");
-            continue;
+        let key_content = kv.value.unwrap().try_into().unwrap_or(Vec::new());
+        if let Err(err) = key_read(&app_state, &consul_key, kv.modify_index, key_content, now)
+            .instrument(read_span)
+            .await
+        {
+            warn!(consul_key = %consul_key, "consul key watcher error: {err}");
         }
+    }
+    info!(consul_key = %consul_key, "consul key watcher stopped");
+}
 
-        let subscribers_res = app_state
-            .key_manager
-            .subscriptions_for_consul_key(consul_key.clone())
-            .await;
-        if let Err(err) = subscribers_res {
-            warn!("consul key watcher error: {consul_key}: {err}");
-            continue;
+/// This function is used to watch every subscribed consul key under a prefix
+/// with one recursive blocking query. The modify index of each key is compared
+/// with the previous read, and only keys that changed are checksummed and
+/// published to their subscribers.
+///
+/// Like `check_key`, the watcher idles out when none of the keys under the
+/// prefix have subscribers.
+///
+/// The dispatcher notifies `wake` when a key under the prefix is subscribed to
+/// for the first time. The blocking query is then cancelled and the prefix is
+/// read right away, so that the new key doesn't wait for the query to return.
+pub async fn check_prefix(
+    prefix: String,
+    stopper: Stopper,
+    app_state: AppState,
+    wake: Arc<Notify>,
+) {
+    info!(consul_prefix = %prefix, "consul prefix watcher started");
+
+    let (address, consul) = match resolve(&app_state, &prefix) {
+        Ok(resolved) => resolved,
+        Err(err) => {
+            error!(consul_prefix = %prefix, "consul prefix watcher error: {err}");
+            return;
         }
-        let subscribers = subscribers_res.unwrap();
+    };
 
-        for subscriber in subscribers {
-            info!(
-                "consul key watcher notifying: {consul_key} {:?}",
-                subscriber
-            );
+    let mut backoff = ErrorBackoff::new(app_state.settings().check_key_error_wait);
+    let mut prefix_index = 0;
+    let mut read_now = false;
+    let mut key_indexes: HashMap<String, u64> = HashMap::new();
+    let mut reported: HashMap<String, Option<&'static str>> = HashMap::new();
+    let mut stop_countdown: Option<DateTime<Utc>> = None;
+
+    while !stopper.is_stopped() {
+        let now = Utc::now();
+
+        let settings = app_state.settings();
+        let idle_duration = chrono::Duration::from_std(settings.check_key_idle).unwrap();
+        let error_wait_duration = settings.check_key_error_wait;
+        let timeout = format_duration(&settings.check_key_timeout);
+        let depth = settings.consul_watch_prefix_depth as usize;
+
+        if !app_state.leadership.is_leader() {
+            warn!(consul_prefix = %prefix, "consul prefix watcher stopping because leadership was lost");
 
             if let Err(err) = app_state
-                .deployment_update_tx
-                .send(DeploymentUpdate {
-                    namespace: subscriber.namespace.clone(),
-                    deployment: subscriber.deployment.clone(),
-                    occurred: now,
-                })
+                .consul_manager_tx
+                .send(ConsulWatch::Destroy(prefix.clone(), now))
                 .await
             {
-                warn!("consul key watcher error: {consul_key}: {err}");
+                error!(consul_prefix = %prefix, "consul prefix watcher error: {err}");
             }
+
+            break;
         }
+
+        let watched: Vec<String> = match app_state.key_manager.consul_keys().await {
+            Ok(consul_keys) => consul_keys
+                .into_iter()
+                .filter(|consul_key| watch_target(consul_key, depth) == prefix)
+                .collect(),
+            Err(err) => {
+                warn!(consul_prefix = %prefix, "consul prefix watcher error: {err}");
+                vec![]
+            }
+        };
+
+        if watched.is_empty() {
+            if stop_countdown.is_none() {
+                warn!(consul_prefix = %prefix, "consul prefix watcher preparing to stop");
+                stop_countdown = Some(Utc::now() + idle_duration);
+            } else if Utc::now() > stop_countdown.unwrap() {
+                warn!(consul_prefix = %prefix, "consul prefix watcher stopping because of inactivity");
+
+                if let Err(err) = app_state
+                    .consul_manager_tx
+                    .send(ConsulWatch::Destroy(prefix.clone(), now))
+                    .await
+                {
+                    error!(consul_prefix = %prefix, "consul prefix watcher error: {err}");
+                }
+
+                break;
+            }
+        } else {
+            stop_countdown = None;
+        }
+
+        // Keys that are no longer watched are forgotten so that they are
+        // published again if they are subscribed to again.
+        key_indexes.retain(|consul_key, _| watched.contains(consul_key));
+        reported.retain(|consul_key, _| watched.contains(consul_key));
+
+        let read_span = info_span!("consul_read", consul_prefix = %prefix, index = prefix_index);
+        for consul_key in watched.iter() {
+            app_state
+                .span_links
+                .follow(&read_span, &consul_key_link(consul_key));
+        }
+
+        let mode = consistency_mode(&app_state, &watched, &settings.consul_consistency_mode).await;
+
+        let query = tokio::select! {
+            query = consul.acquire() => query,
+            _ = stopper.clone() => break,
+        };
+
+        // An index of 0 doesn't block.
+        let index = if read_now { 0 } else { prefix_index };
+        read_now = false;
+
+        let wait_res = tokio::select! {
+            res = read_keys(
+                &query,
+                &address,
+                true,
+                Some(Blocking {
+                    index,
+                    wait: Some(timeout),
+                }),
+                &mode,
+                &settings,
+            )
+            .instrument(read_span.clone()) => res,
+            _ = wake.notified() => {
+                debug!(consul_prefix = %prefix, "consul prefix watcher woken for a new key");
+                read_now = true;
+                continue;
+            },
+        };
+        drop(query);
+
+        if stopper.is_stopped() {
+            break;
+        }
+
+        let wait_success = match wait_res {
+            Ok(wait_success) => {
+                backoff.reset();
+                wait_success
+            }
+            Err(err) => {
+                error!(consul_prefix = %prefix, "consul prefix watcher error: {:?}", err);
+                reload_denied_token(consul, &err);
+                let (found, reason, problem) = read_failure(&err);
+                for consul_key in watched.iter() {
+                    // As with keys missing from a response, keys under a
+                    // deleted prefix are published again when they return.
+                    if found == Some(false) {
+                        key_indexes.remove(consul_key);
+                    }
+                    key_failed(
+                        &app_state,
+                        consul_key,
+                        found,
+                        reason,
+                        &problem,
+                        reported.entry(consul_key.clone()).or_default(),
+                        now,
+                    )
+                    .await;
+                }
+                backoff.wait(error_wait_duration, &stopper).await;
+                continue;
+            }
+        };
+
+        let mut pairs: HashMap<String, _> = wait_success
+            .response
+            .into_iter()
+            .map(|kv| (address.qualify(&kv.key), kv))
+            .collect();
+
+        // The index of the query covers deleted keys, which the modify
+        // indexes of the remaining keys don't.
+        let index = response_index(wait_success.index.as_deref())
+            .or_else(|| pairs.values().map(|kv| kv.modify_index).max())
+            .unwrap_or(0);
+        prefix_index = next_index(prefix_index, index);
+
+        for consul_key in watched {
+            let kv = match pairs.remove(&consul_key) {
+                Some(kv) => kv,
+                None => {
+                    key_indexes.remove(&consul_key);
+                    key_failed(
+                        &app_state,
+                        &consul_key,
+                        Some(false),
+                        REASON_KEY_MISSING,
+                        "does not exist",
+                        reported.entry(consul_key.clone()).or_default(),
+                        now,
+                    )
+                    .await;
+                    continue;
+                }
+            };
+
+            if key_indexes.get(&consul_key) == Some(&kv.modify_index) {
+                reported.remove(&consul_key);
+                key_unchanged(&app_state, &consul_key, kv.modify_index, now).await;
+                continue;
+            }
+            key_indexes.insert(consul_key.clone(), kv.modify_index);
+
+            let key_content = match kv.value {
+                Some(value) => value.try_into().unwrap_or(Vec::new()),
+                None => {
+                    key_failed(
+                        &app_state,
+                        &consul_key,
+                        Some(false),
+                        REASON_KEY_MISSING,
+                        "has no value",
+                        reported.entry(consul_key.clone()).or_default(),
+                        now,
+                    )
+                    .await;
+                    continue;
+                }
+            };
+            reported.remove(&consul_key);
+
+            let key_span = info_span!(
+                parent: &read_span,
+                "consul_key",
+                consul_key = %consul_key,
+                index = kv.modify_index,
+                checksum = tracing::field::Empty,
+            );
+            if let Err(err) = key_read(&app_state, &consul_key, kv.modify_index, key_content, now)
+                .instrument(key_span)
+                .await
+            {
+                warn!(
+                    consul_prefix = %prefix,
+                    consul_key = %consul_key,
+                    "consul prefix watcher error: {err}"
+                );
+            }
+        }
+    }
+    info!(consul_prefix = %prefix, "consul prefix watcher stopped");
+}
+
+/// Records a successful read of a consul key and publishes its checksum. The
+/// checksum is recorded on the current span.
+async fn key_read(
+    app_state: &AppState,
+    consul_key: &str,
+    modify_index: u64,
+    content: Vec<u8>,
+    occurred: DateTime<Utc>,
+) -> Result<()> {
+    let status_changed = app_state
+        .key_manager
+        .set_status(
+            consul_key.to_string(),
+            KeyStatus::found(modify_index, occurred),
+        )
+        .await?;
+
+    let digest = app_state.checksummer.checksum(content);
+
+    debug!(consul_key = %consul_key, checksum = %digest, "consul key watcher checksum");
+    Span::current().record("checksum", digest.as_str());
+
+    // A changed checksum dispatches deployment updates, which also write the
+    // status. When only the status changed, such as when a key recovers from
+    // a failed read with the same content, a status update is dispatched.
+    let checksum_changed = app_state
+        .key_manager
+        .get(consul_key.to_string())
+        .await?
+        .as_ref()
+        != Some(&digest);
+    publish_checksum(app_state, consul_key, modify_index, digest, occurred).await?;
+    if status_changed && !checksum_changed {
+        publish_status(app_state, consul_key, occurred).await?;
+    }
+
+    Ok(())
+}
+
+/// Records a read of a consul key whose modify index didn't change. When the
+/// status changed, such as when the key was failing before, a status update
+/// is dispatched.
+async fn key_unchanged(
+    app_state: &AppState,
+    consul_key: &str,
+    modify_index: u64,
+    occurred: DateTime<Utc>,
+) {
+    let result = match app_state
+        .key_manager
+        .set_status(
+            consul_key.to_string(),
+            KeyStatus::found(modify_index, occurred),
+        )
+        .await
+    {
+        Ok(true) => publish_status(app_state, consul_key, occurred).await,
+        Ok(false) => Ok(()),
+        Err(err) => Err(err),
+    };
+    if let Err(err) = result {
+        warn!(consul_key = %consul_key, "consul key watcher error: {err}");
+    }
+}
+
+/// Records a failed read of a consul key. The status of the key is updated
+/// and, unless the key was already failing for the same reason, an event is
+/// recorded on the deployments that subscribe to it.
+async fn key_failed(
+    app_state: &AppState,
+    consul_key: &str,
+    found: Option<bool>,
+    reason: &'static str,
+    problem: &str,
+    reported: &mut Option<&'static str>,
+    occurred: DateTime<Utc>,
+) {
+    publish_failure(app_state, consul_key, found, problem.to_string(), occurred).await;
+    if *reported != Some(reason) {
+        *reported = Some(reason);
+        record_key_event(app_state, consul_key, reason, problem).await;
+    }
+}
+
+/// Returns whether a key was found, the event reason, and the problem that is
+/// reported for a failed read. Consul responds to reads of keys that don't
+/// exist with a 404, which is a missing key rather than an unreadable one.
+fn read_failure(err: &ClientError) -> (Option<bool>, &'static str, String) {
+    match err {
+        ClientError::APIError { code: 404, .. } => (
+            Some(false),
+            REASON_KEY_MISSING,
+            "does not exist".to_string(),
+        ),
+        _ => (
+            None,
+            REASON_KEY_UNREADABLE,
+            format!("could not be read: {err}"),
+        ),
+    }
+}
+
+/// Returns the address of a consul key and the client of its cluster.
+fn resolve<'a>(
+    app_state: &'a AppState,
+    consul_key: &str,
+) -> Result<(KeyAddress, &'a SharedConsulClient)> {
+    let address = KeyAddress::parse(consul_key)?;
+    let consul = app_state.consul.get(address.cluster.as_deref())?;
+    Ok((address, consul))
+}
+
+/// Reads the consul token file again when consul denied a read, which usually
+/// means that the token was rotated. The next read uses the new token.
+fn reload_denied_token(consul: &SharedConsulClient, err: &ClientError) {
+    if !matches!(err, ClientError::APIError { code: 403, .. }) {
+        return;
+    }
+    match consul.reload_token() {
+        Ok(true) => info!("consul token reloaded after a denied read"),
+        Ok(false) => {}
+        Err(err) => error!("consul token reload error: {err}"),
+    }
+}
+
+/// Stores the sync status of a consul key after a failed read. When the status
+/// changed, a status update is dispatched for each subscriber of the key so
+/// that broken references are visible on the deployments.
+async fn publish_failure(
+    app_state: &AppState,
+    consul_key: &str,
+    found: Option<bool>,
+    error: String,
+    occurred: DateTime<Utc>,
+) {
+    let changed = match app_state
+        .key_manager
+        .get_status(consul_key.to_string())
+        .await
+    {
+        Ok(previous) => {
+            let status = KeyStatus::failed(previous, found, error, occurred);
+            app_state
+                .key_manager
+                .set_status(consul_key.to_string(), status)
+                .await
+        }
+        Err(err) => Err(err),
+    };
+
+    let result = match changed {
+        Ok(true) => publish_status(app_state, consul_key, occurred).await,
+        Ok(false) => Ok(()),
+        Err(err) => Err(err),
+    };
+    if let Err(err) = result {
+        warn!(consul_key = %consul_key, "consul key watcher error: {err}");
+    }
+}
+
+/// Dispatches a status update for each subscriber of a consul key whose
+/// status changed.
+async fn publish_status(
+    app_state: &AppState,
+    consul_key: &str,
+    occurred: DateTime<Utc>,
+) -> Result<()> {
+    let subscribers = app_state
+        .key_manager
+        .subscriptions_for_consul_key(consul_key.to_string())
+        .await?;
+
+    for subscriber in subscribers {
+        app_state
+            .deployment_update_tx
+            .send(DeploymentUpdate {
+                namespace: subscriber.namespace,
+                deployment: subscriber.deployment,
+                occurred,
+                status_only: true,
+            })
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Records a warning event on each deployment that subscribes to a consul key
+/// that is missing or can't be read. The note includes the last known checksum
+/// of the key, which deployments keep using until the key is fixed.
+async fn record_key_event(app_state: &AppState, consul_key: &str, reason: &str, problem: &str) {
+    let previous = app_state
+        .key_manager
+        .get(consul_key.to_string())
+        .await
+        .ok()
+        .flatten();
+
+    let subscribers = match app_state
+        .key_manager
+        .subscriptions_for_consul_key(consul_key.to_string())
+        .await
+    {
+        Ok(subscribers) => subscribers,
+        Err(err) => {
+            warn!(
+                consul_key = %consul_key,
+                "consul key watcher error: failed to record events: {err}"
+            );
+            return;
+        }
+    };
+
+    for subscriber in subscribers {
+        let note = format!(
+            "consul key {consul_key} ({}) {problem}, keeping checksum {}",
+            subscriber.config_key,
+            previous.as_deref().unwrap_or("none"),
+        );
+        if let Err(err) = app_state
+            .events
+            .publish_by_name(
+                &subscriber.namespace,
+                &subscriber.deployment,
+                EventType::Warning,
+                reason,
+                "WatchKey",
+                note,
+            )
+            .await
+        {
+            warn!(
+                consul_key = %consul_key,
+                namespace = %subscriber.namespace,
+                deployment = %subscriber.deployment,
+                "consul key watcher error: failed to record event: {err}"
+            );
+        }
+    }
+}
+
+/// Stores the checksum of a consul key. When the checksum changed, a
+/// deployment update task is dispatched for each subscriber of the key and the
+/// change is recorded in the checksum history and, if enabled, the audit log.
+pub async fn publish_checksum(
+    app_state: &AppState,
+    consul_key: &str,
+    modify_index: u64,
+    digest: String,
+    occurred: DateTime<Utc>,
+) -> Result<Vec<Subscription>> {
+    let previous = app_state.key_manager.get(consul_key.to_string()).await?;
+
+    app_state
+        .key_manager
+        .set(consul_key.to_string(), digest.clone())
+        .await?;
+
+    let subscribers = app_state
+        .key_manager
+        .subscriptions_for_consul_key(consul_key.to_string())
+        .await?;
+
+    // A changed modify index with the same content doesn't roll out
+    // deployments.
+    if previous.as_ref() == Some(&digest) {
+        debug!(consul_key = %consul_key, checksum = %digest, "consul key watcher checksum unchanged");
+        return Ok(subscribers);
+    }
+
+    for subscriber in subscribers.iter() {
+        info!(
+            consul_key = %consul_key,
+            namespace = %subscriber.namespace,
+            deployment = %subscriber.deployment,
+            checksum = %digest,
+            "consul key watcher notifying"
+        );
+        app_state.span_links.record(
+            deployment_link(&subscriber.namespace, &subscriber.deployment),
+            &Span::current(),
+        );
+
+        if let Err(err) = app_state
+            .deployment_update_tx
+            .send(DeploymentUpdate {
+                namespace: subscriber.namespace.clone(),
+                deployment: subscriber.deployment.clone(),
+                occurred,
+                status_only: false,
+            })
+            .await
+        {
+            warn!(consul_key = %consul_key, "consul key watcher error: {err}");
+        }
+    }
+
+    let change = ChecksumChange {
+        changed: occurred,
+        modify_index,
+        previous,
+        checksum: digest,
+        workloads: subscribers
+            .iter()
+            .map(|subscriber| format!("{}/{}", subscriber.namespace, subscriber.deployment))
+            .collect(),
+    };
+    if app_state.settings().audit_log {
+        info!(
+            target: AUDIT_TARGET,
+            consul_key,
+            modify_index,
+            previous = change.previous.as_deref().unwrap_or(""),
+            checksum = change.checksum.as_str(),
+            workloads = change.workloads.join(",").as_str(),
+            "consul key checksum changed"
+        );
+    }
+    app_state
+        .key_manager
+        .record_change(consul_key.to_string(), change)
+        .await?;
+
+    Ok(subscribers)
+}
+
+/// Reads a consul key without a blocking query and returns its modify index
+/// and checksum. The key manager is not updated.
+#[instrument(skip_all, fields(consul_key = %consul_key))]
+pub async fn read_checksum(app_state: &AppState, consul_key: &str) -> Result<(u64, String)> {
+    let (address, consul) = resolve(app_state, consul_key)?;
+    let settings = app_state.settings();
+    let mode = consistency_mode(
+        app_state,
+        &[consul_key.to_string()],
+        &settings.consul_consistency_mode,
+    )
+    .await;
+    let query = consul.acquire().await;
+    let mut read_res = read_keys(&query, &address, false, None, &mode, &settings).await?;
+    drop(query);
+
+    let kv = read_res
+        .response
+        .pop()
+        .ok_or_else(|| anyhow!("no keys returned from consul for key"))?;
+    let value = kv.value.ok_or_else(|| anyhow!("value option is none"))?;
+
+    let key_content = value.try_into().unwrap_or(Vec::new());
+    Ok((kv.modify_index, app_state.checksummer.checksum(key_content)))
+}
+
+/// The result of a forced consul key refresh.
+#[derive(Debug, Clone)]
+pub struct RefreshedKey {
+    pub modify_index: u64,
+    pub checksum: String,
+    pub changed: bool,
+    pub subscribers: Vec<Subscription>,
+}
+
+/// Reads a consul key without a blocking query and publishes its checksum if
+/// it has changed. This is used to force a refresh outside of the key watcher.
+pub async fn refresh_key(app_state: &AppState, consul_key: &str) -> Result<RefreshedKey> {
+    let (modify_index, digest) = read_checksum(app_state, consul_key).await?;
+
+    let previous = app_state.key_manager.get(consul_key.to_string()).await?;
+    let changed = previous.as_ref() != Some(&digest);
+
+    let subscribers = if changed {
+        info!(consul_key = %consul_key, checksum = %digest, "consul key refresh changed checksum");
+        publish_checksum(
+            app_state,
+            consul_key,
+            modify_index,
+            digest.clone(),
+            Utc::now(),
+        )
+        .await?
+    } else {
+        app_state
+            .key_manager
+            .subscriptions_for_consul_key(consul_key.to_string())
+            .await?
+    };
+
+    Ok(RefreshedKey {
+        modify_index,
+        checksum: digest,
+        changed,
+        subscribers,
+    })
+}
+
+/// Parses the `X-Consul-Index` header of a response.
+fn response_index(index: Option<&str>) -> Option<u64> {
+    index.and_then(|index| index.parse::<u64>().ok())
+}
+
+/// Returns the index of the next blocking query from the previous index and
+/// the index of a response. Following consul's rules, the index is reset to 0
+/// when it goes backwards, as it does after a snapshot restore, and is
+/// otherwise at least 1.
+fn next_index(previous: u64, returned: u64) -> u64 {
+    if returned < previous {
+        0
+    } else {
+        returned.max(1)
+    }
+}
+
+/// Returns what a consul key is watched through: the prefix made of its first
+/// `depth` path segments, or the key itself when prefix watching is disabled or
+/// the key isn't nested that deep. The prefix keeps the datacenter and
+/// namespace of the key.
+pub fn watch_target(consul_key: &str, depth: usize) -> String {
+    if depth == 0 {
+        return consul_key.to_string();
+    }
+    let address = match KeyAddress::parse(consul_key) {
+        Ok(address) => address,
+        Err(_) => return consul_key.to_string(),
+    };
+    let segments: Vec<&str> = address.path.split('/').collect();
+    if segments.len() <= depth {
+        return consul_key.to_string();
+    }
+    address.qualify(&format!("{}/", segments[..depth].join("/")))
+}
+
+/// Spawns the watcher of a target returned by `watch_target`.
+fn spawn_watcher(app_state: &AppState, consul_key: &str, target: &str) -> Option<Arc<Notify>> {
+    let tasker = app_state.tasker.clone();
+    let task_state = app_state.clone();
+    let target = target.to_string();
+
+    if target == consul_key {
+        app_state.tasker.spawn(async move {
+            check_key(target, tasker.stopper(), task_state).await;
+            tasker.finish();
+        });
+        None
+    } else {
+        let wake = Arc::new(Notify::new());
+        let prefix_wake = wake.clone();
+        app_state.tasker.spawn(async move {
+            check_prefix(target, tasker.stopper(), task_state, prefix_wake).await;
+            tasker.finish();
+        });
+        Some(wake)
     }
-    info!("consul key watcher stopped: {consul_key}");
 }
 
 /// The consul dispatcher is responsible for managing the consul watches.
@@ -194,17 +1705,15 @@ pub async fn watch_dispatcher(
 
     let mut work: HashSet<ConsulWatch> = HashSet::new();
     let mut running_watchers: HashSet<String> = HashSet::new();
+    // Wakes the running prefix watchers, by prefix.
+    let mut prefix_wakers: HashMap<String, Arc<Notify>> = HashMap::new();
 
     let mut last_reconcile: Option<DateTime<Utc>> = None;
+    let mut was_leader = app_state.leadership.is_leader();
 
-    let debounce_duration =
-        chrono::Duration::seconds(app_state.settings.watch_dispatcher_debounce as i64);
     let first_reconcile_duration =
-        chrono::Duration::seconds(app_state.settings.watch_dispatcher_first_reconcile as i64);
-    let reconcile_duration =
-        chrono::Duration::seconds(app_state.settings.watch_dispatcher_reconcile as i64);
-
-    let check_key_timeout = app_state.settings.check_key_timeout.clone();
+        chrono::Duration::from_std(app_state.settings().watch_dispatcher_first_reconcile).unwrap();
+    let prefix_depth = app_state.settings().consul_watch_prefix_depth as usize;
 
     while !stopper.is_stopped() {
         tokio::select! {
@@ -241,10 +1750,37 @@ pub async fn watch_dispatcher(
         }
         let now = Utc::now();
 
+        // Only the leader runs consul key watchers. Pending create work is
+        // kept so that it is processed if this replica becomes the leader.
+        let is_leader = app_state.leadership.is_leader();
+        if is_leader && !was_leader {
+            info!("consul dispatcher became leader, reconciling");
+            last_reconcile = Some(now);
+        }
+        was_leader = is_leader;
+
+        if !is_leader {
+            // Watchers stop themselves and send destroy work when leadership
+            // is lost.
+            work.retain(|k| match k {
+                ConsulWatch::Create(_, _) => true,
+                ConsulWatch::Destroy(consul_key, _) => {
+                    running_watchers.remove(consul_key);
+                    prefix_wakers.remove(consul_key);
+                    false
+                }
+            });
+            app_state.work_status.set_watches(&running_watchers, &work);
+            continue;
+        }
+
         if last_reconcile.is_none() {
             last_reconcile = Some(Utc::now() + first_reconcile_duration);
             debug!("consul dispatcher reconciling in 30 seconds");
         } else if now > last_reconcile.unwrap() && work.is_empty() {
+            let reconcile_duration =
+                chrono::Duration::from_std(app_state.settings().watch_dispatcher_reconcile)
+                    .unwrap();
             last_reconcile = Some(Utc::now() + reconcile_duration);
 
             let consul_keys = app_state.key_manager.consul_keys().await;
@@ -253,30 +1789,20 @@ pub async fn watch_dispatcher(
                 continue;
             }
             for consul_key in consul_keys.unwrap() {
-                if !running_watchers.contains(&consul_key) {
-                    let tasker2 = app_state.tasker.clone();
-                    let task_shared_state = app_state.clone();
-                    let consul_config = app_state.consul_settings.clone();
-                    let inner_consul_key = consul_key.clone();
-                    let inner_check_key_timeout = check_key_timeout.clone();
-
-                    app_state.tasker.spawn(async move {
-                        check_key(
-                            consul_config,
-                            inner_consul_key,
-                            inner_check_key_timeout,
-                            tasker2.stopper(),
-                            task_shared_state,
-                        )
-                        .await;
-                        tasker2.finish();
-                    });
-                    running_watchers.insert(consul_key);
+                let target = watch_target(&consul_key, prefix_depth);
+                if !running_watchers.contains(&target) {
+                    if let Some(wake) = spawn_watcher(&app_state, &consul_key, &target) {
+                        prefix_wakers.insert(target.clone(), wake);
+                    }
+                    running_watchers.insert(target);
                 }
             }
+            app_state.work_status.set_watches(&running_watchers, &work);
             continue;
         }
 
+        let debounce_duration =
+            chrono::Duration::from_std(app_state.settings().watch_dispatcher_debounce).unwrap();
         let debounce_gap = now - debounce_duration;
 
         let mut drained: Vec<ConsulWatch> = vec![];
@@ -284,32 +1810,25 @@ pub async fn watch_dispatcher(
             match v {
                 ConsulWatch::Create(consul_key, occurred) => {
                     if occurred < &debounce_gap {
-                        if !running_watchers.contains(consul_key) {
-                            let tasker2 = app_state.tasker.clone();
-                            let task_shared_state = app_state.clone();
-                            let consul_config = app_state.consul_settings.clone();
-                            let inner_consul_key = consul_key.clone();
-                            let inner_check_key_timeout = check_key_timeout.clone();
-                            app_state.tasker.spawn(async move {
-                                check_key(
-                                    consul_config,
-                                    inner_consul_key,
-                                    inner_check_key_timeout,
-                                    tasker2.stopper(),
-                                    task_shared_state,
-                                )
-                                .await;
-                                tasker2.finish();
-                            });
+                        let target = watch_target(consul_key, prefix_depth);
+                        if !running_watchers.contains(&target) {
+                            if let Some(wake) = spawn_watcher(&app_state, consul_key, &target) {
+                                prefix_wakers.insert(target.clone(), wake);
+                            }
+                        } else if let Some(wake) = prefix_wakers.get(&target) {
+                            // The running prefix watcher reads the new key now
+                            // instead of when its blocking query returns.
+                            wake.notify_one();
                         }
 
-                        running_watchers.insert(consul_key.clone());
+                        running_watchers.insert(target);
                         drained.push(v.clone());
                     }
                 }
                 ConsulWatch::Destroy(consul_key, _) => {
                     drained.push(v.clone());
                     running_watchers.remove(consul_key);
+                    prefix_wakers.remove(consul_key);
                 }
             }
         }
@@ -318,6 +1837,332 @@ pub async fn watch_dispatcher(
             debug!("consul dispatcher processing {:?}", element);
             work.remove(&element);
         }
+
+        app_state.work_status.set_watches(&running_watchers, &work);
     }
     info!("consul dispatcher stopped");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_backoff() {
+        let max_wait = std::time::Duration::from_secs(8);
+        let mut backoff = ErrorBackoff::new(max_wait);
+
+        let first = backoff.next_wait(max_wait);
+        assert!(first <= std::time::Duration::from_millis(1500));
+
+        for _ in 0..20 {
+            assert!(backoff.next_wait(max_wait) <= max_wait);
+        }
+
+        backoff.reset();
+        assert!(backoff.next_wait(max_wait) <= std::time::Duration::from_millis(1500));
+
+        // A shorter maximum applies immediately.
+        let max_wait = std::time::Duration::from_millis(500);
+        assert!(backoff.next_wait(max_wait) <= max_wait);
+    }
+
+    #[test]
+    fn blocking_query_index() {
+        assert_eq!(next_index(0, 10), 10);
+        assert_eq!(next_index(10, 12), 12);
+        assert_eq!(next_index(10, 10), 10);
+        // The index is reset when it goes backwards.
+        assert_eq!(next_index(10, 4), 0);
+        // The index is at least 1.
+        assert_eq!(next_index(0, 0), 1);
+
+        assert_eq!(response_index(Some("42")), Some(42));
+        assert_eq!(response_index(Some("")), None);
+        assert_eq!(response_index(None), None);
+    }
+
+    #[test]
+    fn request_limiter() {
+        let limiter = RequestLimiter::new(2);
+        let now = Instant::now();
+
+        // The bucket starts full and then hands out tokens in order.
+        assert_eq!(limiter.reserve(now), None);
+        assert_eq!(limiter.reserve(now), None);
+        assert_eq!(
+            limiter.reserve(now),
+            Some(std::time::Duration::from_millis(500))
+        );
+        assert_eq!(
+            limiter.reserve(now),
+            Some(std::time::Duration::from_millis(1000))
+        );
+
+        limiter.record_outcome(std::time::Duration::ZERO, false, true, true);
+        assert_eq!(limiter.state.lock().rate, 1.0);
+        // The rate is lowered at most once a second.
+        limiter.record_outcome(std::time::Duration::ZERO, false, true, true);
+        assert_eq!(limiter.state.lock().rate, 1.0);
+
+        limiter.record_outcome(std::time::Duration::ZERO, true, false, false);
+        assert_eq!(limiter.state.lock().rate, 1.02);
+
+        let metrics = limiter.render_metrics();
+        assert!(metrics.contains("k8s_consul_mutator_consul_requests_total 3\n"));
+        assert!(metrics.contains("k8s_consul_mutator_consul_requests_overloaded_total 2\n"));
+
+        assert_eq!(RequestLimiter::new(0).reserve(now), None);
+    }
+
+    #[test]
+    fn unlimited_request_limiter_sheds_load() {
+        let limiter = RequestLimiter::new(0);
+        let now = Instant::now();
+        for _ in 0..10 {
+            assert_eq!(limiter.reserve(now), None);
+        }
+
+        // Overloaded requests are limited to half of the observed rate.
+        limiter.record_outcome(std::time::Duration::ZERO, false, true, true);
+        assert_eq!(limiter.state.lock().rate, 5.0);
+        assert!(limiter.reserve(Instant::now()).is_some());
+
+        // The limit is lifted once successful requests restore the rate.
+        for _ in 0..60 {
+            limiter.record_outcome(std::time::Duration::ZERO, false, false, false);
+        }
+        assert_eq!(limiter.state.lock().rate, 0.0);
+        assert_eq!(limiter.reserve(Instant::now()), None);
+    }
+
+    #[test]
+    fn stale_reads() {
+        let max_stale = std::time::Duration::from_secs(5);
+        assert!(is_too_stale(Some("5001"), max_stale));
+        assert!(!is_too_stale(Some("5000"), max_stale));
+        assert!(!is_too_stale(None, max_stale));
+        assert!(!is_too_stale(Some("5001"), std::time::Duration::ZERO));
+    }
+
+    #[test]
+    fn watch_targets() {
+        assert_eq!(watch_target("apps/web/config", 0), "apps/web/config");
+        assert_eq!(watch_target("apps/web/config", 1), "apps/");
+        assert_eq!(watch_target("apps/web/config", 2), "apps/web/");
+        // Keys that aren't nested deep enough are watched alone.
+        assert_eq!(watch_target("apps/web/config", 3), "apps/web/config");
+        assert_eq!(watch_target("config", 1), "config");
+        assert_eq!(
+            watch_target("dc/dc1:partition/eu:ns/team-a:apps/web/config", 1),
+            "dc/dc1:partition/eu:ns/team-a:apps/"
+        );
+    }
+
+    #[test]
+    fn token_file() {
+        let path = std::env::temp_dir().join(format!("consul-token-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        fs::write(path, "68a1a640-96d9-4c33-8074-5b49378aa881\n").unwrap();
+        assert_eq!(
+            read_token(path).unwrap(),
+            "68a1a640-96d9-4c33-8074-5b49378aa881"
+        );
+
+        let endpoint = ConsulEndpoint {
+            token_file: Some(path.to_string()),
+            ..Default::default()
+        };
+        let client =
+            SharedConsulClient::new(endpoint, 1, Arc::new(RequestLimiter::new(0))).unwrap();
+        assert!(!client.reload_token().unwrap());
+
+        fs::write(path, "1ca3c4a2-8f8b-4bb4-9a4f-0c2d3e7e1c55").unwrap();
+        assert!(client.reload_token().unwrap());
+        assert!(!client.reload_token().unwrap());
+
+        fs::write(path, " \n").unwrap();
+        assert!(client.reload_token().is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn recovered_key_dispatches_status_update() {
+        let settings = crate::config::SettingsBuilder::default()
+            .api_auth("none")
+            .build()
+            .unwrap();
+        let (app_state, mut updates, _watches) = crate::state::test_state(settings, None).await;
+        app_state
+            .key_manager
+            .watch(
+                "demo".to_string(),
+                "app".to_string(),
+                "config".to_string(),
+                "app/config".to_string(),
+            )
+            .await
+            .unwrap();
+        let now = Utc::now();
+
+        key_unchanged(&app_state, "app/config", 7, now).await;
+        assert!(updates.try_recv().unwrap().status_only);
+
+        // Reads that don't change the status don't dispatch updates.
+        key_unchanged(&app_state, "app/config", 7, now).await;
+        assert!(updates.try_recv().is_err());
+
+        // A key that was unreadable and is read again with the same modify
+        // index is visible on the deployment again.
+        publish_failure(
+            &app_state,
+            "app/config",
+            None,
+            "could not be read".to_string(),
+            now,
+        )
+        .await;
+        assert!(updates.try_recv().unwrap().status_only);
+        key_unchanged(&app_state, "app/config", 7, now).await;
+        assert!(updates.try_recv().unwrap().status_only);
+    }
+
+    /// Collects the logs written by a test.
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn structured_log_fields() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let _guard = tracing::subscriber::set_default(
+            tracing_subscriber::registry()
+                .with(crate::telemetry::json_layer(move || writer.clone())),
+        );
+
+        let settings = crate::config::SettingsBuilder::default()
+            .api_auth("none")
+            .build()
+            .unwrap();
+        let (app_state, _updates, _watches) = crate::state::test_state(settings, None).await;
+        app_state
+            .key_manager
+            .watch(
+                "demo".to_string(),
+                "app".to_string(),
+                "config".to_string(),
+                "app/config".to_string(),
+            )
+            .await
+            .unwrap();
+        publish_checksum(&app_state, "app/config", 7, "md5-a".to_string(), Utc::now())
+            .await
+            .unwrap();
+
+        let logs = String::from_utf8(logs.0.lock().clone()).unwrap();
+        let line: serde_json::Value = logs
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .find(|line| line["message"] == "consul key watcher notifying")
+            .expect("the notification should be logged");
+        assert_eq!(line["consul_key"], "app/config");
+        assert_eq!(line["namespace"], "demo");
+        assert_eq!(line["deployment"], "app");
+        assert_eq!(line["checksum"], "md5-a");
+    }
+
+    #[test]
+    fn tls_server_name_address() {
+        let (address, resolved) =
+            server_name_address("https://127.0.0.1:8501", "server.dc1.consul").unwrap();
+        assert_eq!(address, "https://server.dc1.consul:8501");
+        assert_eq!(resolved, "127.0.0.1:8501".parse().unwrap());
+
+        let (address, resolved) =
+            server_name_address("https://[::1]", "server.dc1.consul").unwrap();
+        assert_eq!(address, "https://server.dc1.consul");
+        assert_eq!(resolved, "[::1]:443".parse().unwrap());
+
+        assert!(server_name_address("127.0.0.1:8501", "server.dc1.consul").is_err());
+    }
+
+    #[tokio::test]
+    async fn strongest_consistency_mode() {
+        let settings = crate::config::SettingsBuilder::default()
+            .api_auth("none")
+            .build()
+            .unwrap();
+        let (app_state, _updates, _watches) = crate::state::test_state(settings, None).await;
+        let keys = vec!["app/config".to_string()];
+        assert_eq!(consistency_mode(&app_state, &keys, "stale").await, "stale");
+
+        for (deployment, mode) in [("app", Some("stale")), ("api", None)] {
+            app_state
+                .key_manager
+                .watch(
+                    "demo".to_string(),
+                    deployment.to_string(),
+                    "config".to_string(),
+                    "app/config".to_string(),
+                )
+                .await
+                .unwrap();
+            app_state
+                .key_manager
+                .set_consistency_mode(
+                    "demo".to_string(),
+                    deployment.to_string(),
+                    "config".to_string(),
+                    mode.map(|mode| mode.to_string()),
+                )
+                .await
+                .unwrap();
+        }
+
+        // The subscription without a mode uses the default mode.
+        assert_eq!(
+            consistency_mode(&app_state, &keys, "default").await,
+            "default"
+        );
+        assert_eq!(consistency_mode(&app_state, &keys, "stale").await, "stale");
+        assert_eq!(
+            consistency_mode(&app_state, &keys, "consistent").await,
+            "consistent"
+        );
+
+        let annotations = BTreeMap::from([
+            (
+                "k8s-consul-mutator.io/consistency-config".to_string(),
+                "stale".to_string(),
+            ),
+            (
+                "k8s-consul-mutator.io/consistency-other".to_string(),
+                "fast".to_string(),
+            ),
+        ]);
+        assert_eq!(
+            consistency_annotation(&annotations, "config").unwrap(),
+            Some("stale".to_string())
+        );
+        assert!(consistency_annotation(&annotations, "other").is_err());
+        assert_eq!(
+            consistency_annotation(&annotations, "missing").unwrap(),
+            None
+        );
+    }
+}
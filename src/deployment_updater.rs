@@ -1,8 +1,10 @@
-use chrono::Utc;
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
 use k8s_openapi::api::apps::v1::Deployment;
 use kube::{
     api::{Api, Patch, PatchParams},
-    Client,
+    runtime::events::EventType,
+    Client, ResourceExt,
 };
 use serde_json::json;
 use std::{
@@ -14,9 +16,245 @@ use tokio::{
     time::{self, Instant},
 };
 use tokio_tasker::Stopper;
-use tracing::{debug, error, info, trace};
+use tracing::{debug, error, info, instrument, trace, warn, Span};
 
+use crate::error::Result;
+use crate::events::REASON_CHECKSUM_CHANGED;
 use crate::state::{AppState, DeploymentUpdate};
+use crate::telemetry::deployment_link;
+
+/// The annotation that holds the sync status of the consul keys of a
+/// deployment.
+pub const STATUS_ANNOTATION: &str = "k8s-consul-mutator.io/status";
+
+/// Patches the checksum and timestamp annotations of a deployment using the
+/// checksums known to the key manager. Returns the annotations that were set
+/// on the deployment.
+#[instrument(skip_all, fields(namespace = %namespace, deployment = %deployment))]
+pub async fn update_deployment(
+    app_state: &AppState,
+    client: &Client,
+    namespace: &str,
+    deployment: &str,
+    now: DateTime<Utc>,
+) -> Result<HashMap<String, String>> {
+    app_state
+        .span_links
+        .follow(&Span::current(), &deployment_link(namespace, deployment));
+
+    let deployment_client: Api<Deployment> = Api::namespaced(client.clone(), namespace);
+
+    let current = match deployment_client.get_opt(deployment).await? {
+        Some(current) => current,
+        None => return Err(anyhow!("deployment not found {namespace}/{deployment}")),
+    };
+
+    let annotations = app_state
+        .key_manager
+        .deployment_annotations(namespace.to_string(), deployment.to_string())
+        .await?;
+
+    let settings = app_state.settings();
+
+    let mut deployment_annotations: HashMap<String, String> = HashMap::new();
+    let mut deployment_spec_annotations: HashMap<String, String> = HashMap::new();
+
+    if settings.set_deployment_annotations {
+        for (k, v) in annotations.iter() {
+            deployment_annotations.insert(format!("k8s-consul-mutator.io/checksum-{k}"), v.clone());
+        }
+    }
+    deployment_annotations.insert(
+        STATUS_ANNOTATION.to_string(),
+        status_annotation(app_state, namespace, deployment).await?,
+    );
+    if settings.set_deployment_timestamp {
+        deployment_annotations.insert(
+            "k8s-consul-mutator.io/last-updated".to_string(),
+            now.to_rfc3339(),
+        );
+    }
+
+    if settings.set_deployment_spec_annotations {
+        for (k, v) in annotations.iter() {
+            deployment_spec_annotations
+                .insert(format!("k8s-consul-mutator.io/checksum-{k}"), v.clone());
+        }
+    }
+    if settings.set_deployment_spec_timestamp {
+        deployment_spec_annotations.insert(
+            "k8s-consul-mutator.io/last-updated".to_string(),
+            now.to_rfc3339(),
+        );
+    }
+
+    let body = json!({
+        "apiVersion": "apps/v1",
+        "kind": "Deployment",
+        "metadata": {
+            "name": deployment,
+            "annotations": deployment_annotations,
+        },
+        "spec": {
+            "template": {
+                "metadata": {
+                    "annotations": deployment_spec_annotations,
+                }
+            }
+        }
+    });
+
+    deployment_client
+        .patch(
+            deployment,
+            &PatchParams::apply("k8s-consul-mutator"),
+            &Patch::Merge(&body),
+        )
+        .await?;
+
+    record_checksum_events(app_state, &current, &annotations).await;
+
+    Ok(annotations)
+}
+
+/// Patches only the status annotation of a deployment. This doesn't change
+/// the pod template, so the deployment isn't rolled out.
+#[instrument(skip_all, fields(namespace = %namespace, deployment = %deployment))]
+pub async fn update_deployment_status(
+    app_state: &AppState,
+    client: &Client,
+    namespace: &str,
+    deployment: &str,
+) -> Result<()> {
+    let deployment_client: Api<Deployment> = Api::namespaced(client.clone(), namespace);
+
+    if deployment_client.get_opt(deployment).await?.is_none() {
+        return Err(anyhow!("deployment not found {namespace}/{deployment}"));
+    }
+
+    let body = json!({
+        "metadata": {
+            "annotations": {
+                STATUS_ANNOTATION: status_annotation(app_state, namespace, deployment).await?,
+            },
+        },
+    });
+
+    deployment_client
+        .patch(
+            deployment,
+            &PatchParams::apply("k8s-consul-mutator"),
+            &Patch::Merge(&body),
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// Returns the status annotation of a deployment: a JSON object with the sync
+/// status of the consul key of each config key.
+async fn status_annotation(
+    app_state: &AppState,
+    namespace: &str,
+    deployment: &str,
+) -> Result<String> {
+    let mut statuses = serde_json::Map::new();
+
+    for (subscription, consul_key) in app_state.key_manager.subscriptions().await? {
+        if subscription.namespace != namespace || subscription.deployment != deployment {
+            continue;
+        }
+        let status = app_state.key_manager.get_status(consul_key.clone()).await?;
+        statuses.insert(
+            subscription.config_key,
+            json!({
+                "consulKey": consul_key,
+                "found": status.as_ref().map(|status| status.found).unwrap_or(false),
+                "modifyIndex": status.as_ref().and_then(|status| status.modify_index),
+                "lastRead": status.as_ref().map(|status| status.last_read.to_rfc3339()),
+                "lastError": status.and_then(|status| status.last_error),
+            }),
+        );
+    }
+
+    Ok(serde_json::Value::Object(statuses).to_string())
+}
+
+/// Records an event on the deployment for each consul key whose checksum is
+/// different from the checksum that was previously set on the deployment.
+async fn record_checksum_events(
+    app_state: &AppState,
+    deployment: &Deployment,
+    annotations: &HashMap<String, String>,
+) {
+    let namespace = deployment.namespace().unwrap_or_default();
+    let name = deployment.name_any();
+
+    let subscriptions = match app_state.key_manager.subscriptions().await {
+        Ok(subscriptions) => subscriptions,
+        Err(err) => {
+            warn!("update worker error: failed to record events: {err}");
+            return;
+        }
+    };
+
+    for (subscription, consul_key) in subscriptions {
+        if subscription.namespace != namespace || subscription.deployment != name {
+            continue;
+        }
+        let checksum = match annotations.get(&subscription.config_key) {
+            Some(checksum) => checksum,
+            None => continue,
+        };
+        let previous = previous_checksum(deployment, &subscription.config_key);
+        if previous.as_ref() == Some(checksum) {
+            continue;
+        }
+
+        let note = format!(
+            "consul key {consul_key} ({}) changed checksum from {} to {checksum}",
+            subscription.config_key,
+            previous.as_deref().unwrap_or("none"),
+        );
+        if let Err(err) = app_state
+            .events
+            .publish(
+                deployment,
+                EventType::Normal,
+                REASON_CHECKSUM_CHANGED,
+                "Update",
+                note,
+            )
+            .await
+        {
+            warn!(
+                consul_key = %consul_key,
+                namespace = %namespace,
+                deployment = %name,
+                checksum = %checksum,
+                "update worker error: failed to record event: {err}"
+            );
+        }
+    }
+}
+
+/// Returns the checksum of a config key that is set on the deployment, from
+/// either the deployment or the pod template annotations.
+fn previous_checksum(deployment: &Deployment, config_key: &str) -> Option<String> {
+    let annotation = format!("k8s-consul-mutator.io/checksum-{config_key}");
+    deployment
+        .annotations()
+        .get(&annotation)
+        .or_else(|| {
+            deployment
+                .spec
+                .as_ref()
+                .and_then(|spec| spec.template.metadata.as_ref())
+                .and_then(|metadata| metadata.annotations.as_ref())
+                .and_then(|annotations| annotations.get(&annotation))
+        })
+        .cloned()
+}
 
 /// This is the main loop that publishes checksum changes to deployment
 /// resources in Kubernetes. It receives updates from the deployment watcher
@@ -26,10 +264,7 @@ pub async fn deployment_update_loop(
     stopper: Stopper,
     rx: &mut Receiver<DeploymentUpdate>,
 ) {
-    let client = Client::try_default()
-        .await
-        .map_err(anyhow::Error::msg)
-        .unwrap();
+    let client = app_state.kube_client().await.unwrap();
 
     let sleep = time::sleep(Duration::from_secs(1));
     tokio::pin!(sleep);
@@ -38,15 +273,23 @@ pub async fn deployment_update_loop(
 
     info!("update worker starting");
 
-    let debounce_duration = chrono::Duration::seconds(app_state.settings.update_debounce as i64);
-
     while !stopper.is_stopped() {
         tokio::select! {
             biased;
             r = rx.recv() => {
-                let val = r.unwrap();
-                debug!("update worker got value: {:?}", val);
-                work.retain(|k| k.namespace != val.namespace && k.deployment != val.deployment);
+                let mut val = r.unwrap();
+                debug!(
+                    namespace = %val.namespace,
+                    deployment = %val.deployment,
+                    status_only = val.status_only,
+                    "update worker got value"
+                );
+                // A pending full update also writes the status.
+                val.status_only = val.status_only
+                    && !work.iter().any(|k| {
+                        k.namespace == val.namespace && k.deployment == val.deployment && !k.status_only
+                    });
+                work.retain(|k| k.namespace != val.namespace || k.deployment != val.deployment);
                 work.insert(val);
             }
             () = &mut sleep => {
@@ -59,95 +302,49 @@ pub async fn deployment_update_loop(
             break;
         }
 
+        // Only the leader applies deployment updates. The leader's key
+        // watchers will dispatch their own updates.
+        if !app_state.leadership.is_leader() {
+            if !work.is_empty() {
+                debug!(
+                    "update worker is not the leader, dropping {} updates",
+                    work.len()
+                );
+                work.clear();
+                app_state.work_status.set_updates(&work);
+            }
+            continue;
+        }
+
         let now = Utc::now();
+        let debounce_duration =
+            chrono::Duration::from_std(app_state.settings().update_debounce).unwrap();
 
         let mut drained: Vec<DeploymentUpdate> = vec![];
         for v in work.iter() {
+            if !app_state.sharding.owns(&v.namespace) {
+                debug!(
+                    namespace = %v.namespace,
+                    deployment = %v.deployment,
+                    "update worker dropping update for unowned namespace"
+                );
+                drained.push(v.clone());
+                continue;
+            }
             if v.occurred < now - debounce_duration {
-                let deployment_client: Api<Deployment> =
-                    Api::namespaced(client.clone(), &v.namespace.clone());
-
-                let deployment_res = deployment_client.get_opt(&v.deployment).await;
-                if let Ok(deployment_opt) = deployment_res {
-                    if deployment_opt.is_some() {
-                        let annotations_res = app_state
-                            .key_manager
-                            .deployment_annotations(v.namespace.clone(), v.deployment.clone())
-                            .await;
-
-                        if let Ok(annotations) = annotations_res {
-                            let mut deployment_annotations: HashMap<String, String> =
-                                HashMap::new();
-                            let mut deployment_spec_annotations: HashMap<String, String> =
-                                HashMap::new();
-
-                            if app_state.settings.set_deployment_annotations {
-                                for (k, v) in annotations.iter() {
-                                    deployment_annotations.insert(
-                                        format!("k8s-consul-mutator.io/checksum-{k}"),
-                                        v.clone(),
-                                    );
-                                }
-                            }
-                            if app_state.settings.set_deployment_timestamp {
-                                deployment_annotations.insert(
-                                    "k8s-consul-mutator.io/last-updated".to_string(),
-                                    now.to_rfc3339(),
-                                );
-                            }
-
-                            if app_state.settings.set_deployment_spec_annotations {
-                                for (k, v) in annotations.iter() {
-                                    deployment_spec_annotations.insert(
-                                        format!("k8s-consul-mutator.io/checksum-{k}"),
-                                        v.clone(),
-                                    );
-                                }
-                            }
-                            if app_state.settings.set_deployment_spec_timestamp {
-                                deployment_spec_annotations.insert(
-                                    "k8s-consul-mutator.io/last-updated".to_string(),
-                                    now.to_rfc3339(),
-                                );
-                            }
-
-                            let body = json!({
-                                "apiVersion": "apps/v1",
-                                "kind": "Deployment",
-                                "metadata": {
-                                    "name": v.deployment.clone(),
-                                    "annotations": deployment_annotations,
-                                },
-                                "spec": {
-                                    "template": {
-                                        "metadata": {
-                                            "annotations": deployment_spec_annotations,
-                                        }
-                                    }
-                                }
-                            });
-
-                            let patch_res = deployment_client
-                                .patch(
-                                    &v.deployment,
-                                    &PatchParams::apply("k8s-consul-mutator"),
-                                    &Patch::Merge(&body),
-                                )
-                                .await;
-                            if let Err(err) = patch_res {
-                                error!("update worker error: {err}");
-                            }
-                        } else if let Err(err) = annotations_res {
-                            error!("update worker error: {err}");
-                        }
-                    } else if deployment_opt.is_none() {
-                        error!(
-                            "update worker error: deployment not found {}/{}",
-                            v.namespace, v.deployment
-                        );
-                    }
-                } else if let Err(err) = deployment_res {
-                    error!("update worker error: {err}");
+                let updated = if v.status_only {
+                    update_deployment_status(&app_state, &client, &v.namespace, &v.deployment).await
+                } else {
+                    update_deployment(&app_state, &client, &v.namespace, &v.deployment, now)
+                        .await
+                        .map(|_| ())
+                };
+                if let Err(err) = updated {
+                    error!(
+                        namespace = %v.namespace,
+                        deployment = %v.deployment,
+                        "update worker error: {err}"
+                    );
                 }
                 drained.push(v.clone());
             }
@@ -158,9 +355,15 @@ pub async fn deployment_update_loop(
         }
 
         for element in drained {
-            debug!("update worker processing {:?}", element);
+            debug!(
+                namespace = %element.namespace,
+                deployment = %element.deployment,
+                "update worker processing"
+            );
             work.remove(&element);
         }
+
+        app_state.work_status.set_updates(&work);
     }
     info!("update worker stopped");
 }
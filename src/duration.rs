@@ -0,0 +1,128 @@
+use anyhow::anyhow;
+use serde::Serializer;
+use std::time::Duration;
+
+use crate::error::Result;
+
+/// Parses a duration such as `90s`, `5m`, `1h30m`, or `250ms`. The supported
+/// units are `ms`, `s`, `m`, `h`, and `d`. A bare number is a number of
+/// seconds, which is how timing settings were originally configured.
+pub fn parse_duration(value: &str) -> Result<Duration> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err(anyhow!("empty duration"));
+    }
+
+    if value.chars().all(|c| c.is_ascii_digit()) {
+        return Ok(Duration::from_secs(value.parse()?));
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if digits == 0 {
+            return Err(anyhow!("invalid duration: {value}"));
+        }
+        let amount: u64 = rest[..digits]
+            .parse()
+            .map_err(|_| anyhow!("invalid duration: {value}"))?;
+        rest = &rest[digits..];
+
+        let units = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let unit = match &rest[..units] {
+            "ms" => Duration::from_millis(1),
+            "s" => Duration::from_secs(1),
+            "m" => Duration::from_secs(60),
+            "h" => Duration::from_secs(60 * 60),
+            "d" => Duration::from_secs(24 * 60 * 60),
+            "" => return Err(anyhow!("missing unit in duration: {value}")),
+            unit => return Err(anyhow!("unknown unit {unit:?} in duration: {value}")),
+        };
+        rest = &rest[units..];
+
+        let amount = u32::try_from(amount)
+            .ok()
+            .and_then(|amount| unit.checked_mul(amount))
+            .ok_or_else(|| anyhow!("duration is too large: {value}"))?;
+        total = total
+            .checked_add(amount)
+            .ok_or_else(|| anyhow!("duration is too large: {value}"))?;
+    }
+
+    Ok(total)
+}
+
+/// Formats a duration in the form that `parse_duration` accepts, e.g. `1h30m`.
+/// The output is also a valid Go duration, which is what consul expects for
+/// blocking query wait times.
+pub fn format_duration(duration: &Duration) -> String {
+    if duration.is_zero() {
+        return "0s".to_string();
+    }
+
+    let mut result = String::new();
+    let mut secs = duration.as_secs();
+    for (unit, size) in [("h", 60 * 60), ("m", 60), ("s", 1)] {
+        if secs >= size {
+            result.push_str(&format!("{}{unit}", secs / size));
+            secs %= size;
+        }
+    }
+    let millis = duration.subsec_millis();
+    if millis > 0 {
+        result.push_str(&format!("{millis}ms"));
+    }
+    result
+}
+
+/// Serializes a duration with `format_duration`.
+pub fn serialize_duration<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format_duration(duration))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("5m").unwrap(), Duration::from_secs(300));
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(5400));
+        assert_eq!(parse_duration("1d").unwrap(), Duration::from_secs(86400));
+        assert_eq!(
+            parse_duration("1s500ms").unwrap(),
+            Duration::from_millis(1500)
+        );
+        assert_eq!(parse_duration("70000").unwrap(), Duration::from_secs(70000));
+
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("5 m").is_err());
+        assert!(parse_duration("10x").is_err());
+        assert!(parse_duration("1h30").is_err());
+        assert!(parse_duration("-5s").is_err());
+    }
+
+    #[test]
+    fn format_durations() {
+        for value in ["0s", "90ms", "45s", "1m30s", "1h30m", "26h", "2m0s500ms"] {
+            let duration = parse_duration(value).unwrap();
+            assert_eq!(
+                parse_duration(&format_duration(&duration)).unwrap(),
+                duration
+            );
+        }
+        assert_eq!(format_duration(&Duration::from_secs(5400)), "1h30m");
+        assert_eq!(format_duration(&Duration::from_millis(1500)), "1s500ms");
+    }
+}
@@ -0,0 +1,99 @@
+use k8s_openapi::api::{apps::v1::Deployment, core::v1::ObjectReference};
+use kube::{
+    api::Api,
+    runtime::events::{Event, EventType, Recorder, Reporter},
+    Client, Resource,
+};
+use tokio::sync::OnceCell;
+
+use crate::error::Result;
+
+/// The reason of the event recorded when a deployment is updated because the
+/// checksum of a consul key changed.
+pub const REASON_CHECKSUM_CHANGED: &str = "ConsulKeyChanged";
+
+/// The reason of the event recorded when a consul key doesn't exist or has no
+/// value.
+pub const REASON_KEY_MISSING: &str = "ConsulKeyMissing";
+
+/// The reason of the event recorded when a consul key can't be read.
+pub const REASON_KEY_UNREADABLE: &str = "ConsulKeyUnreadable";
+
+/// Records kubernetes events on managed deployments so that changes and
+/// problems show up in `kubectl describe deployment`.
+pub struct Events {
+    reporter: Reporter,
+    client: OnceCell<Client>,
+}
+
+impl Events {
+    pub fn new(identity: &str) -> Self {
+        Self {
+            reporter: Reporter {
+                controller: "k8s-consul-mutator".to_string(),
+                instance: Some(identity.to_string()).filter(|identity| !identity.is_empty()),
+            },
+            client: OnceCell::new(),
+        }
+    }
+
+    /// Records an event on a deployment that has already been read.
+    pub async fn publish(
+        &self,
+        deployment: &Deployment,
+        type_: EventType,
+        reason: &str,
+        action: &str,
+        note: String,
+    ) -> Result<()> {
+        self.publish_reference(deployment.object_ref(&()), type_, reason, action, note)
+            .await
+    }
+
+    /// Records an event on a deployment by name. The deployment is read to
+    /// get its uid, which kubectl uses to find its events.
+    pub async fn publish_by_name(
+        &self,
+        namespace: &str,
+        deployment: &str,
+        type_: EventType,
+        reason: &str,
+        action: &str,
+        note: String,
+    ) -> Result<()> {
+        let client = self.client().await?;
+        let api: Api<Deployment> = Api::namespaced(client, namespace);
+        let deployment = api.get(deployment).await?;
+        self.publish(&deployment, type_, reason, action, note).await
+    }
+
+    async fn publish_reference(
+        &self,
+        reference: ObjectReference,
+        type_: EventType,
+        reason: &str,
+        action: &str,
+        note: String,
+    ) -> Result<()> {
+        let client = self.client().await?;
+        let recorder = Recorder::new(client, self.reporter.clone(), reference);
+        recorder
+            .publish(Event {
+                type_,
+                reason: reason.to_string(),
+                note: Some(note),
+                action: action.to_string(),
+                secondary: None,
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn client(&self) -> Result<Client> {
+        Ok(self
+            .client
+            .get_or_try_init(Client::try_default)
+            .await?
+            .clone())
+    }
+}
@@ -1,20 +1,31 @@
-use futures::prelude::*;
-use k8s_openapi::api::apps::v1::Deployment;
+use futures::{prelude::*, stream};
+use k8s_openapi::api::{apps::v1::Deployment, core::v1::Namespace};
 use kube::{
     api::{Api, ResourceExt},
     runtime, Client,
 };
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+use tokio::{task::JoinHandle, time::sleep};
 use tokio_tasker::Stopper;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
+use crate::address::canonical_key;
+use crate::consul::consistency_annotation;
+use crate::scope::ScopeRules;
 use crate::state::AppState;
 
+/// How long a namespace deployment watcher waits before retrying after an
+/// error.
+const NAMESPACE_WATCH_RETRY: Duration = Duration::from_secs(5);
+
 #[derive(Hash, Eq, PartialEq, Debug, Clone)]
 pub struct FullSubscription {
     pub namespace: String,
     pub deployment: String,
     pub config_key: String,
     pub consul_key: String,
+    pub consistency: Option<String>,
 }
 
 /// This is the main loop that watches for deployment events in Kubernetes.
@@ -24,70 +35,288 @@ pub struct FullSubscription {
 /// This does not notify the key manager of consul key subscription changes,
 /// but relies on the reconcile step to eventually create subscriptions that
 /// don't exist.
+///
+/// When sharding is enabled, only the namespaces owned by this replica are
+/// watched, see `watch_owned_namespaces`. The watcher is restarted when the
+/// scope is reloaded.
 pub async fn deployment_watch(app_state: AppState, stopper: Stopper) -> Result<(), anyhow::Error> {
     let client = Client::try_default().await.map_err(anyhow::Error::msg)?;
-    let api = Api::<Deployment>::all(client.clone());
+    let mut rules_rx = app_state.scope.subscribe();
 
     info!("kubernetes deployment watcher started");
 
-    let deployment_watcher = runtime::watcher::watcher(api, runtime::watcher::Config::default())
-        .try_for_each(|event| async {
-            match event {
-                kube::runtime::watcher::Event::Deleted(d) => {
-                    // TODO: Don't unwatch deployments that aren't annotated.
-                    let namespace = d.namespace();
-                    let name = d.name_any();
-                    if namespace.is_some() && !name.is_empty() {
-                        if let Err(err) = app_state
-                            .key_manager
-                            .unwatch_deployment(namespace.clone().unwrap(), name)
-                            .await
-                        {
-                            error!(
-                            "kubernetes deployment watcher error: failed to unwatch deployment: {}",
-                            err
-                        );
-                        }
-                    }
+    loop {
+        let rules = app_state.scope.rules();
+        let watcher_config = deployment_watcher_config(&rules);
+
+        let deployment_watcher = async {
+            if app_state.sharding.is_enabled() {
+                watch_owned_namespaces(&app_state, &client, &rules, &watcher_config).await
+            } else if rules.namespaces.is_empty() {
+                watch_deployments(&app_state, vec![Api::all(client.clone())], &watcher_config).await
+            } else {
+                // When namespaces are listed, only those namespaces are
+                // watched so that namespaced roles can be used instead of a
+                // cluster role.
+                let apis = rules
+                    .namespaces
+                    .iter()
+                    .map(|namespace| Api::namespaced(client.clone(), namespace))
+                    .collect();
+                watch_deployments(&app_state, apis, &watcher_config).await
+            }
+        };
+
+        tokio::select! {
+            res = deployment_watcher => {
+                if let Err(e) = res {
+                    error!("kubernetes deployment watcher error: {}", e);
                 }
-                kube::runtime::watcher::Event::Applied(d) => {
-                    // TODO: Look for annotation removal and unwatch accordingly.
-                    let subscriptions = subscriptions_from_deployment(&d).await;
-                    for sub in subscriptions {
-                        if let Err(err) = app_state
-                            .key_manager
-                            .watch(
-                                sub.namespace,
-                                sub.deployment,
-                                sub.config_key,
-                                sub.consul_key.clone(),
-                            )
-                            .await
-                        {
-                            error!(
-                            "kubernetes deployment watcher error: failed to watch deployment: {}",
-                            err
-                        );
-                        }
-                    }
+                break;
+            },
+            res = rules_rx.changed() => {
+                if res.is_err() {
+                    break;
                 }
-                _ => {}
-            }
-            Ok(())
-        });
+                info!("kubernetes deployment watcher restarting because scope changed");
+            },
+            _ = stopper.clone() => {
+                break;
+            },
+        };
+    }
+
+    info!("kubernetes deployment watcher stopped");
+
+    Ok(())
+}
+
+fn deployment_watcher_config(rules: &ScopeRules) -> runtime::watcher::Config {
+    let mut watcher_config = runtime::watcher::Config::default();
+    if let Some(selector) = &rules.label_selector {
+        watcher_config = watcher_config.labels(selector.as_str());
+    }
+    if !rules.excluded_namespaces.is_empty() {
+        let fields: Vec<String> = rules
+            .excluded_namespaces
+            .iter()
+            .map(|namespace| format!("metadata.namespace!={namespace}"))
+            .collect();
+        watcher_config = watcher_config.fields(&fields.join(","));
+    }
+    watcher_config
+}
 
-    tokio::select! {
-        res = deployment_watcher => {
-            if let Err(e) = res {
-                error!("kubernetes deployment watcher error: {}", e);
+/// Applies the deployment events of the APIs until one of the watchers fails.
+async fn watch_deployments(
+    app_state: &AppState,
+    apis: Vec<Api<Deployment>>,
+    watcher_config: &runtime::watcher::Config,
+) -> Result<(), anyhow::Error> {
+    stream::select_all(
+        apis.into_iter()
+            .map(|api| runtime::watcher::watcher(api, watcher_config.clone()).boxed()),
+    )
+    .try_for_each(|event| async {
+        match event {
+            runtime::watcher::Event::Deleted(d) => {
+                delete_deployment(app_state, &d).await;
+            }
+            runtime::watcher::Event::Applied(d) => {
+                apply_deployment(app_state, &d).await;
             }
-        },
-        _ = stopper => { },
+            runtime::watcher::Event::Restarted(deployments) => {
+                for d in deployments.iter() {
+                    apply_deployment(app_state, d).await;
+                }
+            }
+        }
+        Ok(())
+    })
+    .await?;
+    Ok(())
+}
+
+/// Watches the deployments of each namespace that this replica owns, with one
+/// watcher per namespace. When the shard membership changes, only the
+/// watchers of namespaces that moved are started or stopped, so deployments
+/// that stay on this replica aren't listed again.
+///
+/// The candidate namespaces are `NAMESPACES` when it is set, and otherwise
+/// every namespace in the cluster, which are watched to find new namespaces.
+async fn watch_owned_namespaces(
+    app_state: &AppState,
+    client: &Client,
+    rules: &ScopeRules,
+    watcher_config: &runtime::watcher::Config,
+) -> Result<(), anyhow::Error> {
+    let mut members_rx = app_state.sharding.subscribe();
+    let mut watchers = NamespaceWatchers::default();
+
+    let mut namespace_events = if rules.namespaces.is_empty() {
+        runtime::watcher::watcher(
+            Api::<Namespace>::all(client.clone()),
+            runtime::watcher::Config::default(),
+        )
+        .boxed()
+    } else {
+        stream::pending().boxed()
     };
+    let mut namespaces: BTreeSet<String> = rules.namespaces.iter().cloned().collect();
 
-    info!("kubernetes deployment watcher stopped");
+    loop {
+        let owned: BTreeSet<String> = namespaces
+            .iter()
+            .filter(|namespace| !rules.excluded_namespaces.contains(namespace))
+            .filter(|namespace| app_state.sharding.owns(namespace))
+            .cloned()
+            .collect();
 
-    Ok(())
+        watchers.0.retain(|namespace, watcher| {
+            if owned.contains(namespace) {
+                return true;
+            }
+            info!("kubernetes deployment watcher released namespace: {namespace}");
+            watcher.abort();
+            false
+        });
+        for namespace in owned {
+            if watchers.0.contains_key(&namespace) {
+                continue;
+            }
+            info!("kubernetes deployment watcher acquired namespace: {namespace}");
+            let watcher = tokio::spawn(watch_namespace(
+                app_state.clone(),
+                Api::namespaced(client.clone(), &namespace),
+                namespace.clone(),
+                watcher_config.clone(),
+            ));
+            watchers.0.insert(namespace, watcher);
+        }
+
+        tokio::select! {
+            event = namespace_events.next() => match event {
+                Some(Ok(runtime::watcher::Event::Applied(namespace))) => {
+                    namespaces.insert(namespace.name_any());
+                }
+                Some(Ok(runtime::watcher::Event::Deleted(namespace))) => {
+                    namespaces.remove(&namespace.name_any());
+                }
+                Some(Ok(runtime::watcher::Event::Restarted(listed))) => {
+                    namespaces = listed.iter().map(|namespace| namespace.name_any()).collect();
+                }
+                // The namespace watcher recovers when it is polled again, so
+                // errors don't stop the watchers of owned namespaces.
+                Some(Err(err)) => {
+                    error!("kubernetes deployment watcher error: namespaces: {err}");
+                    sleep(NAMESPACE_WATCH_RETRY).await;
+                }
+                None => return Ok(()),
+            },
+            res = members_rx.changed() => {
+                if res.is_err() {
+                    return Ok(());
+                }
+            },
+        }
+    }
+}
+
+/// The deployment watchers of owned namespaces. The watchers are aborted when
+/// this is dropped, such as when the deployment watcher restarts.
+#[derive(Default)]
+struct NamespaceWatchers(HashMap<String, JoinHandle<()>>);
+
+impl Drop for NamespaceWatchers {
+    fn drop(&mut self) {
+        for watcher in self.0.values() {
+            watcher.abort();
+        }
+    }
+}
+
+/// Watches the deployments of one namespace until the task is aborted.
+/// Errors are logged and the watcher retries after `NAMESPACE_WATCH_RETRY`.
+async fn watch_namespace(
+    app_state: AppState,
+    api: Api<Deployment>,
+    namespace: String,
+    watcher_config: runtime::watcher::Config,
+) {
+    loop {
+        if let Err(err) = watch_deployments(&app_state, vec![api.clone()], &watcher_config).await {
+            error!("kubernetes deployment watcher error: {namespace}: {err}");
+        }
+        sleep(NAMESPACE_WATCH_RETRY).await;
+    }
+}
+
+async fn delete_deployment(app_state: &AppState, deployment: &Deployment) {
+    // TODO: Don't unwatch deployments that aren't annotated.
+    let namespace = deployment.namespace();
+    let name = deployment.name_any();
+    if namespace.is_some() && !name.is_empty() {
+        if let Err(err) = app_state
+            .key_manager
+            .unwatch_deployment(namespace.clone().unwrap(), name)
+            .await
+        {
+            error!(
+                "kubernetes deployment watcher error: failed to unwatch deployment: {}",
+                err
+            );
+        }
+    }
+}
+
+async fn apply_deployment(app_state: &AppState, deployment: &Deployment) {
+    let namespace = deployment.namespace().unwrap_or_default();
+    if !app_state.sharding.owns(&namespace) {
+        return;
+    }
+    if !app_state
+        .scope
+        .matches(&namespace, deployment.labels())
+        .await
+    {
+        return;
+    }
+
+    // TODO: Look for annotation removal and unwatch accordingly.
+    let subscriptions = subscriptions_from_deployment(deployment).await;
+    for sub in subscriptions {
+        if let Err(err) = app_state
+            .key_manager
+            .watch(
+                sub.namespace.clone(),
+                sub.deployment.clone(),
+                sub.config_key.clone(),
+                sub.consul_key.clone(),
+            )
+            .await
+        {
+            error!(
+                "kubernetes deployment watcher error: failed to watch deployment: {}",
+                err
+            );
+            continue;
+        }
+        if let Err(err) = app_state
+            .key_manager
+            .set_consistency_mode(
+                sub.namespace,
+                sub.deployment,
+                sub.config_key,
+                sub.consistency,
+            )
+            .await
+        {
+            error!(
+                "kubernetes deployment watcher error: failed to watch deployment: {}",
+                err
+            );
+        }
+    }
 }
 
 async fn subscriptions_from_deployment(deployment: &Deployment) -> Vec<FullSubscription> {
@@ -115,11 +344,34 @@ async fn subscriptions_from_deployment(deployment: &Deployment) -> Vec<FullSubsc
         let key = found_key.replace("k8s-consul-mutator.io/key-", "");
 
         let found_key_value = deployment.annotations().get(found_key.as_str()).unwrap();
+        let consul_key = match canonical_key(found_key_value) {
+            Ok(consul_key) => consul_key,
+            Err(err) => {
+                warn!(
+                    "kubernetes deployment watcher error: {}/{}: {err}",
+                    deployment.namespace().unwrap_or_default(),
+                    deployment.name_any()
+                );
+                continue;
+            }
+        };
+        let consistency = match consistency_annotation(deployment.annotations(), &key) {
+            Ok(consistency) => consistency,
+            Err(err) => {
+                warn!(
+                    "kubernetes deployment watcher error: {}/{}: {err}",
+                    deployment.namespace().unwrap_or_default(),
+                    deployment.name_any()
+                );
+                None
+            }
+        };
         results.push(FullSubscription {
             namespace: deployment.namespace().unwrap(),
             deployment: deployment.name_any().clone(),
             config_key: key,
-            consul_key: found_key_value.clone(),
+            consul_key,
+            consistency,
         });
     }
 
@@ -1,7 +1,8 @@
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use parking_lot::Mutex;
 use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::vec;
 
 use anyhow::anyhow;
@@ -16,6 +17,75 @@ pub struct Subscription {
     pub config_key: String,
 }
 
+/// The current checksum of a consul key and when it last changed.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct ChecksumRecord {
+    pub checksum: String,
+    pub changed: DateTime<Utc>,
+}
+
+/// The number of checksum changes that are kept for each key.
+pub const CHECKSUM_HISTORY_LIMIT: usize = 20;
+
+/// A change of the checksum of a consul key and the workloads it was
+/// dispatched to, as `namespace/deployment`.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct ChecksumChange {
+    pub changed: DateTime<Utc>,
+    pub modify_index: u64,
+    pub previous: Option<String>,
+    pub checksum: String,
+    pub workloads: Vec<String>,
+}
+
+/// The sync state of a consul key, as last seen by its watcher.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct KeyStatus {
+    pub found: bool,
+    pub modify_index: Option<u64>,
+    pub last_read: DateTime<Utc>,
+    /// The error of the last read. This is cleared when a read succeeds.
+    pub last_error: Option<String>,
+}
+
+impl KeyStatus {
+    /// Returns the status after a successful read.
+    pub fn found(modify_index: u64, last_read: DateTime<Utc>) -> Self {
+        Self {
+            found: true,
+            modify_index: Some(modify_index),
+            last_read,
+            last_error: None,
+        }
+    }
+
+    /// Returns the status after a failed read. When `found` isn't known, it is
+    /// kept from the previous status along with the modify index.
+    pub fn failed(
+        previous: Option<KeyStatus>,
+        found: Option<bool>,
+        error: String,
+        last_read: DateTime<Utc>,
+    ) -> Self {
+        let (previous_found, modify_index) = previous
+            .map(|previous| (previous.found, previous.modify_index))
+            .unwrap_or_default();
+        Self {
+            found: found.unwrap_or(previous_found),
+            modify_index,
+            last_read,
+            last_error: Some(error),
+        }
+    }
+
+    /// Returns true if the statuses differ in more than when they were read.
+    pub fn differs(&self, other: &KeyStatus) -> bool {
+        self.found != other.found
+            || self.modify_index != other.modify_index
+            || self.last_error != other.last_error
+    }
+}
+
 /// KeyManager is an interface for managing subscriptions to consul keys.
 #[async_trait]
 pub trait KeyManager: Sync + Send {
@@ -53,6 +123,26 @@ pub trait KeyManager: Sync + Send {
     /// Gets the value of a key.
     async fn get(&self, key: String) -> Result<Option<String>>;
 
+    /// Gets the value of a key and the time that it last changed.
+    async fn get_record(&self, key: String) -> Result<Option<ChecksumRecord>>;
+
+    /// Sets the sync status of a key. Returns true if the status differs from
+    /// the previous status in more than when it was read.
+    async fn set_status(&self, key: String, status: KeyStatus) -> Result<bool>;
+
+    /// Gets the sync status of a key.
+    async fn get_status(&self, key: String) -> Result<Option<KeyStatus>>;
+
+    /// Records a change of the checksum of a key. Only the last
+    /// `CHECKSUM_HISTORY_LIMIT` changes of each key are kept.
+    async fn record_change(&self, key: String, change: ChecksumChange) -> Result<()>;
+
+    /// Gets the recorded checksum changes of every key, oldest first.
+    async fn history(&self) -> Result<Vec<(String, ChecksumChange)>>;
+
+    /// Gets all subscriptions and the consul keys that they point to.
+    async fn subscriptions(&self) -> Result<Vec<(Subscription, String)>>;
+
     /// Gets all subscriptions for a deployment.
     async fn subscriptions_for_deployment(
         &self,
@@ -64,6 +154,19 @@ pub trait KeyManager: Sync + Send {
 
     async fn consul_key_subscriber_count(&self, consul_key: String) -> Result<usize>;
 
+    /// Sets the consistency mode that a subscription asks its consul key to
+    /// be read with. `None` uses `CONSUL_CONSISTENCY_MODE`.
+    async fn set_consistency_mode(
+        &self,
+        namespace: String,
+        deployment: String,
+        config_key: String,
+        mode: Option<String>,
+    ) -> Result<()>;
+
+    /// Returns the consistency mode of each subscription to a consul key.
+    async fn consistency_modes(&self, consul_key: String) -> Result<Vec<Option<String>>>;
+
     async fn consul_keys(&self) -> Result<Vec<String>>;
 
     async fn deployment_annotations(
@@ -109,6 +212,30 @@ impl KeyManager for NullKeyManager {
         Ok(None)
     }
 
+    async fn get_record(&self, _key: String) -> Result<Option<ChecksumRecord>> {
+        Ok(None)
+    }
+
+    async fn set_status(&self, _key: String, _status: KeyStatus) -> Result<bool> {
+        Ok(false)
+    }
+
+    async fn get_status(&self, _key: String) -> Result<Option<KeyStatus>> {
+        Ok(None)
+    }
+
+    async fn record_change(&self, _key: String, _change: ChecksumChange) -> Result<()> {
+        Ok(())
+    }
+
+    async fn history(&self) -> Result<Vec<(String, ChecksumChange)>> {
+        Ok(vec![])
+    }
+
+    async fn subscriptions(&self) -> Result<Vec<(Subscription, String)>> {
+        Ok(vec![])
+    }
+
     async fn subscriptions_for_deployment(
         &self,
         _namespace: String,
@@ -125,6 +252,20 @@ impl KeyManager for NullKeyManager {
         Ok(0)
     }
 
+    async fn set_consistency_mode(
+        &self,
+        _namespace: String,
+        _deployment: String,
+        _config_key: String,
+        _mode: Option<String>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    async fn consistency_modes(&self, _consul_key: String) -> Result<Vec<Option<String>>> {
+        Ok(vec![])
+    }
+
     async fn consul_keys(&self) -> Result<Vec<String>> {
         Ok(vec![])
     }
@@ -140,8 +281,28 @@ impl KeyManager for NullKeyManager {
 
 #[derive(Default)]
 struct InnerMemoryKeyManager {
-    checksums: HashMap<String, String>,
+    checksums: HashMap<String, ChecksumRecord>,
+    statuses: HashMap<String, KeyStatus>,
+    history: HashMap<String, VecDeque<ChecksumChange>>,
     subscriptions: HashMap<Subscription, String>,
+    consistency_modes: HashMap<Subscription, String>,
+}
+
+impl InnerMemoryKeyManager {
+    /// Removes the statuses and history of consul keys that no longer have
+    /// subscribers, and the consistency modes of removed subscriptions, so
+    /// that they don't grow as deployments come and go.
+    fn prune(&mut self) {
+        let subscriptions = &self.subscriptions;
+        self.consistency_modes
+            .retain(|subscription, _| subscriptions.contains_key(subscription));
+
+        let subscribed: HashSet<&String> = self.subscriptions.values().collect();
+        self.statuses
+            .retain(|consul_key, _| subscribed.contains(consul_key));
+        self.history
+            .retain(|consul_key, _| subscribed.contains(consul_key));
+    }
 }
 
 #[derive(Default)]
@@ -190,6 +351,7 @@ impl KeyManager for MemoryKeyManager {
         let count = inner.subscriptions.len();
         inner.subscriptions.retain(|k, _| k.namespace != namespace);
         let modified_count = inner.subscriptions.len();
+        inner.prune();
 
         Ok(count - modified_count)
     }
@@ -201,8 +363,9 @@ impl KeyManager for MemoryKeyManager {
         let count = inner.subscriptions.len();
         inner
             .subscriptions
-            .retain(|k, _| k.namespace != namespace && k.deployment != deployment);
+            .retain(|k, _| k.namespace != namespace || k.deployment != deployment);
         let modified_count = inner.subscriptions.len();
+        inner.prune();
 
         Ok(count - modified_count)
     }
@@ -211,7 +374,20 @@ impl KeyManager for MemoryKeyManager {
         let inner_lock = self.inner.lock();
         let mut inner = inner_lock.borrow_mut();
 
-        inner.checksums.insert(consul_key, checksum);
+        // Only bump the changed timestamp when the checksum is different.
+        if let Some(existing) = inner.checksums.get(&consul_key) {
+            if existing.checksum == checksum {
+                return Ok(());
+            }
+        }
+
+        inner.checksums.insert(
+            consul_key,
+            ChecksumRecord {
+                checksum,
+                changed: Utc::now(),
+            },
+        );
 
         Ok(())
     }
@@ -221,11 +397,80 @@ impl KeyManager for MemoryKeyManager {
         let inner = inner_lock.borrow();
 
         match inner.checksums.get(&consul_key) {
-            Some(val_ref) => Ok(Some(val_ref.to_owned())),
+            Some(val_ref) => Ok(Some(val_ref.checksum.to_owned())),
             None => Ok(None),
         }
     }
 
+    async fn get_record(&self, consul_key: String) -> Result<Option<ChecksumRecord>> {
+        let inner_lock = self.inner.lock();
+        let inner = inner_lock.borrow();
+
+        Ok(inner.checksums.get(&consul_key).cloned())
+    }
+
+    async fn set_status(&self, consul_key: String, status: KeyStatus) -> Result<bool> {
+        let inner_lock = self.inner.lock();
+        let mut inner = inner_lock.borrow_mut();
+
+        let changed = match inner.statuses.get(&consul_key) {
+            Some(existing) => existing.differs(&status),
+            None => true,
+        };
+        inner.statuses.insert(consul_key, status);
+
+        Ok(changed)
+    }
+
+    async fn get_status(&self, consul_key: String) -> Result<Option<KeyStatus>> {
+        let inner_lock = self.inner.lock();
+        let inner = inner_lock.borrow();
+
+        Ok(inner.statuses.get(&consul_key).cloned())
+    }
+
+    async fn record_change(&self, consul_key: String, change: ChecksumChange) -> Result<()> {
+        let inner_lock = self.inner.lock();
+        let mut inner = inner_lock.borrow_mut();
+
+        let changes = inner.history.entry(consul_key).or_default();
+        changes.push_back(change);
+        while changes.len() > CHECKSUM_HISTORY_LIMIT {
+            changes.pop_front();
+        }
+
+        Ok(())
+    }
+
+    async fn history(&self) -> Result<Vec<(String, ChecksumChange)>> {
+        let inner_lock = self.inner.lock();
+        let inner = inner_lock.borrow();
+
+        let mut results: Vec<(String, ChecksumChange)> = inner
+            .history
+            .iter()
+            .flat_map(|(consul_key, changes)| {
+                changes
+                    .iter()
+                    .map(|change| (consul_key.clone(), change.clone()))
+            })
+            .collect();
+        results.sort_by_key(|(_, change)| change.changed);
+
+        Ok(results)
+    }
+
+    async fn subscriptions(&self) -> Result<Vec<(Subscription, String)>> {
+        let inner_lock = self.inner.lock();
+        let inner = inner_lock.borrow();
+
+        Ok(inner
+            .subscriptions
+            .iter()
+            .map(|(subscription, consul_key)| (subscription.clone(), consul_key.clone()))
+            .collect())
+    }
+
     async fn subscriptions_for_deployment(
         &self,
         namespace: String,
@@ -271,6 +516,41 @@ impl KeyManager for MemoryKeyManager {
             .count())
     }
 
+    async fn set_consistency_mode(
+        &self,
+        namespace: String,
+        deployment: String,
+        config_key: String,
+        mode: Option<String>,
+    ) -> Result<()> {
+        let inner_lock = self.inner.lock();
+        let mut inner = inner_lock.borrow_mut();
+
+        let subscription = Subscription {
+            namespace,
+            deployment,
+            config_key,
+        };
+        match mode {
+            Some(mode) => inner.consistency_modes.insert(subscription, mode),
+            None => inner.consistency_modes.remove(&subscription),
+        };
+
+        Ok(())
+    }
+
+    async fn consistency_modes(&self, consul_key: String) -> Result<Vec<Option<String>>> {
+        let inner_lock = self.inner.lock();
+        let inner = inner_lock.borrow_mut();
+
+        Ok(inner
+            .subscriptions
+            .iter()
+            .filter(|(_, value)| value == &&consul_key)
+            .map(|(subscription, _)| inner.consistency_modes.get(subscription).cloned())
+            .collect())
+    }
+
     async fn consul_keys(&self) -> Result<Vec<String>> {
         let inner_lock = self.inner.lock();
         let inner = inner_lock.borrow_mut();
@@ -294,7 +574,7 @@ impl KeyManager for MemoryKeyManager {
         for subscription in inner.subscriptions.iter() {
             if subscription.0.namespace == namespace && subscription.0.deployment == deployment {
                 if let Some(value) = inner.checksums.get(subscription.1) {
-                    results.insert(subscription.0.config_key.clone(), value.clone());
+                    results.insert(subscription.0.config_key.clone(), value.checksum.clone());
                 }
             }
         }
@@ -303,6 +583,15 @@ impl KeyManager for MemoryKeyManager {
     }
 }
 
+/// Returns the key manager types that `get_key_manager` supports.
+pub fn supported_key_manager_types() -> Vec<&'static str> {
+    vec![
+        #[cfg(debug_assertions)]
+        "null",
+        "memory",
+    ]
+}
+
 pub fn get_key_manager(key_manager_type: &str) -> Box<dyn KeyManager> {
     match key_manager_type {
         #[cfg(debug_assertions)]
@@ -390,6 +679,109 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn memory_key_manager_checksum_record() {
+        let key_manager = Box::new(MemoryKeyManager::default()) as Box<dyn KeyManager>;
+        key_manager
+            .set("config".to_string(), "md5-a".to_string())
+            .await
+            .expect("set should succeed");
+        let first = key_manager
+            .get_record("config".to_string())
+            .await
+            .expect("get_record should succeed")
+            .expect("record should exist");
+        assert_eq!(first.checksum, "md5-a");
+
+        key_manager
+            .set("config".to_string(), "md5-a".to_string())
+            .await
+            .expect("set should succeed");
+        let second = key_manager
+            .get_record("config".to_string())
+            .await
+            .expect("get_record should succeed")
+            .expect("record should exist");
+        assert_eq!(first, second);
+
+        key_manager
+            .set("config".to_string(), "md5-b".to_string())
+            .await
+            .expect("set should succeed");
+        assert_eq!(
+            key_manager
+                .get("config".to_string())
+                .await
+                .expect("get should succeed"),
+            Some("md5-b".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_key_manager_status() {
+        let key_manager = Box::new(MemoryKeyManager::default()) as Box<dyn KeyManager>;
+        let now = Utc::now();
+
+        assert!(key_manager
+            .set_status("config".to_string(), KeyStatus::found(7, now))
+            .await
+            .expect("set_status should succeed"));
+        // Reading the same index again is not a change.
+        assert!(!key_manager
+            .set_status(
+                "config".to_string(),
+                KeyStatus::found(7, now + chrono::Duration::seconds(1))
+            )
+            .await
+            .expect("set_status should succeed"));
+
+        let previous = key_manager
+            .get_status("config".to_string())
+            .await
+            .expect("get_status should succeed");
+        let failed = KeyStatus::failed(previous, None, "timeout".to_string(), now);
+        assert!(failed.found);
+        assert_eq!(failed.modify_index, Some(7));
+        assert!(key_manager
+            .set_status("config".to_string(), failed)
+            .await
+            .expect("set_status should succeed"));
+
+        let missing = KeyStatus::failed(None, Some(false), "missing".to_string(), now);
+        assert!(!missing.found);
+        assert_eq!(missing.modify_index, None);
+    }
+
+    #[tokio::test]
+    async fn memory_key_manager_history() {
+        let key_manager = Box::new(MemoryKeyManager::default()) as Box<dyn KeyManager>;
+        let now = Utc::now();
+
+        for i in 0..(CHECKSUM_HISTORY_LIMIT + 5) {
+            key_manager
+                .record_change(
+                    "config".to_string(),
+                    ChecksumChange {
+                        changed: now + chrono::Duration::seconds(i as i64),
+                        modify_index: i as u64,
+                        previous: None,
+                        checksum: format!("md5-{i}"),
+                        workloads: vec!["default/app".to_string()],
+                    },
+                )
+                .await
+                .expect("record_change should succeed");
+        }
+
+        let history = key_manager.history().await.expect("history should succeed");
+        assert_eq!(history.len(), CHECKSUM_HISTORY_LIMIT);
+        assert_eq!(history[0].1.modify_index, 5);
+        assert_eq!(
+            history.last().map(|(_, change)| change.modify_index),
+            Some((CHECKSUM_HISTORY_LIMIT + 4) as u64)
+        );
+    }
+
     #[tokio::test]
     async fn memory_key_manager_unwatch_deployment() {
         let key_manager = Box::new(MemoryKeyManager::default()) as Box<dyn KeyManager>;
@@ -427,6 +819,70 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn memory_key_manager_prunes_unsubscribed_keys() {
+        let key_manager = Box::new(MemoryKeyManager::default()) as Box<dyn KeyManager>;
+        let now = Utc::now();
+
+        for deployment in ["app-foo", "app-bar"] {
+            key_manager
+                .watch(
+                    "default".to_string(),
+                    deployment.to_string(),
+                    "config".to_string(),
+                    format!("{deployment}/config"),
+                )
+                .await
+                .expect("watch should succeed");
+            key_manager
+                .set_status(format!("{deployment}/config"), KeyStatus::found(7, now))
+                .await
+                .expect("set_status should succeed");
+            key_manager
+                .record_change(
+                    format!("{deployment}/config"),
+                    ChecksumChange {
+                        changed: now,
+                        modify_index: 7,
+                        previous: None,
+                        checksum: "md5-a".to_string(),
+                        workloads: vec![format!("default/{deployment}")],
+                    },
+                )
+                .await
+                .expect("record_change should succeed");
+        }
+
+        key_manager
+            .unwatch_deployment("default".to_string(), "app-foo".to_string())
+            .await
+            .expect("unwatch should succeed");
+
+        assert!(key_manager
+            .get_status("app-foo/config".to_string())
+            .await
+            .expect("get_status should succeed")
+            .is_none());
+        assert!(key_manager
+            .get_status("app-bar/config".to_string())
+            .await
+            .expect("get_status should succeed")
+            .is_some());
+        let history = key_manager.history().await.expect("history should succeed");
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].0, "app-bar/config");
+
+        key_manager
+            .unwatch_namespace("default".to_string())
+            .await
+            .expect("unwatch should succeed");
+        assert!(key_manager
+            .history()
+            .await
+            .expect("history should succeed")
+            .is_empty());
+    }
+
     #[tokio::test]
     async fn memory_key_manager_unwatch_namespace() {
         let key_manager = Box::new(MemoryKeyManager::default()) as Box<dyn KeyManager>;
@@ -490,4 +946,58 @@ mod tests {
             1
         );
     }
+
+    #[tokio::test]
+    async fn memory_key_manager_consistency_modes() {
+        let key_manager = Box::new(MemoryKeyManager::default()) as Box<dyn KeyManager>;
+        for deployment in ["app-foo", "app-bar"] {
+            key_manager
+                .watch(
+                    "default".to_string(),
+                    deployment.to_string(),
+                    "config".to_string(),
+                    "config".to_string(),
+                )
+                .await
+                .expect("watch should succeed");
+        }
+        key_manager
+            .set_consistency_mode(
+                "default".to_string(),
+                "app-foo".to_string(),
+                "config".to_string(),
+                Some("stale".to_string()),
+            )
+            .await
+            .expect("set_consistency_mode should succeed");
+
+        let mut modes = key_manager
+            .consistency_modes("config".to_string())
+            .await
+            .expect("consistency_modes should succeed");
+        modes.sort();
+        assert_eq!(modes, vec![None, Some("stale".to_string())]);
+
+        // The mode is forgotten with its subscription.
+        key_manager
+            .unwatch_deployment("default".to_string(), "app-foo".to_string())
+            .await
+            .expect("unwatch should succeed");
+        key_manager
+            .watch(
+                "default".to_string(),
+                "app-foo".to_string(),
+                "config".to_string(),
+                "config".to_string(),
+            )
+            .await
+            .expect("watch should succeed");
+        assert_eq!(
+            key_manager
+                .consistency_modes("config".to_string())
+                .await
+                .expect("consistency_modes should succeed"),
+            vec![None, None]
+        );
+    }
 }
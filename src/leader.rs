@@ -0,0 +1,427 @@
+use anyhow::anyhow;
+use chrono::{Duration, Utc};
+use k8s_openapi::{
+    api::coordination::v1::{Lease, LeaseSpec},
+    apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta},
+};
+use kube::{
+    api::{Api, PostParams},
+    Client,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::time::{sleep, timeout};
+use tokio_tasker::Stopper;
+use tracing::{debug, error, info, warn};
+
+use crate::error::Result;
+use crate::state::AppState;
+
+/// Tracks whether this replica currently holds the leader lease. When leader
+/// election is disabled, the replica is always the leader.
+pub struct Leadership {
+    leader: AtomicBool,
+}
+
+impl Leadership {
+    pub fn new(leader: bool) -> Self {
+        Self {
+            leader: AtomicBool::new(leader),
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.leader.load(Ordering::SeqCst)
+    }
+
+    fn set_leader(&self, leader: bool) -> bool {
+        self.leader.swap(leader, Ordering::SeqCst)
+    }
+}
+
+/// This is the main loop that acquires and renews the leader lease. Only the
+/// leader runs consul key watchers and applies deployment updates, but every
+/// replica serves admission requests.
+///
+/// When stopped, the leader releases the lease so that another replica can
+/// take over without waiting for the lease to expire.
+pub async fn leader_election_loop(app_state: AppState, stopper: Stopper) -> Result<()> {
+    let settings = app_state.settings();
+    let client = Client::try_default().await.map_err(anyhow::Error::msg)?;
+    let api: Api<Lease> = Api::namespaced(client, &settings.leader_election_namespace);
+
+    let lease_name = settings.leader_election_lease_name.clone();
+    let identity = settings.leader_election_identity.clone();
+    let lease_duration = settings.leader_election_lease_duration.as_secs() as i32;
+    let retry_duration = Duration::seconds((lease_duration / 3).max(1) as i64);
+    let renew_deadline = renew_deadline(lease_duration);
+
+    info!("leader election started: {lease_name} {identity}");
+
+    let mut last_renewed = Utc::now();
+
+    while !stopper.is_stopped() {
+        let now = Utc::now();
+
+        // A renewal that hangs counts as failed so that the renew deadline is
+        // checked in time.
+        let attempt = timeout(
+            retry_duration.to_std().unwrap(),
+            try_acquire_or_renew(&api, &lease_name, &identity, lease_duration),
+        )
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("renewing the lease timed out")));
+
+        match attempt {
+            Ok(true) => {
+                last_renewed = now;
+                if !app_state.leadership.set_leader(true) {
+                    info!("leader election acquired lease: {lease_name} {identity}");
+                }
+            }
+            Ok(false) => {
+                if app_state.leadership.set_leader(false) {
+                    warn!("leader election lost lease: {lease_name} {identity}");
+                }
+            }
+            Err(err) => {
+                error!("leader election error: {err}");
+                // Give up leadership before the lease can expire for other
+                // replicas, so that two replicas are never leaders at once.
+                if app_state.leadership.is_leader() && Utc::now() - last_renewed >= renew_deadline {
+                    app_state.leadership.set_leader(false);
+                    warn!("leader election renew deadline passed: {lease_name} {identity}");
+                }
+            }
+        }
+
+        tokio::select! {
+            _ = sleep(retry_duration.to_std().unwrap()) => {},
+            _ = stopper.clone() => {},
+        }
+    }
+
+    if app_state.leadership.set_leader(false) {
+        if let Err(err) = release(&api, &lease_name, &identity).await {
+            error!("leader election error: failed to release lease: {err}");
+        } else {
+            info!("leader election released lease: {lease_name} {identity}");
+        }
+    }
+
+    info!("leader election stopped");
+
+    Ok(())
+}
+
+/// Returns how long the leader keeps leadership without renewing the lease:
+/// two thirds of the lease duration, like the renew deadline of client-go.
+fn renew_deadline(lease_duration: i32) -> Duration {
+    Duration::milliseconds(lease_duration as i64 * 2000 / 3)
+}
+
+/// Creates, renews, or takes over an expired lease. Returns true if the lease
+/// is held by the identity.
+async fn try_acquire_or_renew(
+    api: &Api<Lease>,
+    lease_name: &str,
+    identity: &str,
+    lease_duration: i32,
+) -> Result<bool> {
+    let now = Utc::now();
+
+    let lease = match api.get_opt(lease_name).await? {
+        Some(lease) => lease,
+        None => {
+            let lease = Lease {
+                metadata: ObjectMeta {
+                    name: Some(lease_name.to_string()),
+                    ..Default::default()
+                },
+                spec: Some(LeaseSpec {
+                    holder_identity: Some(identity.to_string()),
+                    lease_duration_seconds: Some(lease_duration),
+                    acquire_time: Some(MicroTime(now)),
+                    renew_time: Some(MicroTime(now)),
+                    lease_transitions: Some(0),
+                }),
+            };
+            return match api.create(&PostParams::default(), &lease).await {
+                Ok(_) => Ok(true),
+                Err(kube::Error::Api(err)) if err.code == 409 => Ok(false),
+                Err(err) => Err(err.into()),
+            };
+        }
+    };
+
+    let mut spec = lease.spec.clone().unwrap_or_default();
+    let held = spec.holder_identity.as_deref() == Some(identity);
+
+    if !held {
+        let expired = match (&spec.holder_identity, &spec.renew_time) {
+            (Some(holder), Some(MicroTime(renew_time))) if !holder.is_empty() => {
+                let duration = spec.lease_duration_seconds.unwrap_or(lease_duration);
+                *renew_time + Duration::seconds(duration as i64) < now
+            }
+            _ => true,
+        };
+        if !expired {
+            debug!(
+                "leader election lease held by {:?}",
+                spec.holder_identity.unwrap_or_default()
+            );
+            return Ok(false);
+        }
+        spec.holder_identity = Some(identity.to_string());
+        spec.acquire_time = Some(MicroTime(now));
+        spec.lease_transitions = Some(spec.lease_transitions.unwrap_or(0) + 1);
+    }
+
+    spec.lease_duration_seconds = Some(lease_duration);
+    spec.renew_time = Some(MicroTime(now));
+
+    // The resource version of the lease that was read is kept so that the
+    // replace fails if another replica changed the lease in the meantime.
+    let updated = Lease {
+        metadata: lease.metadata.clone(),
+        spec: Some(spec),
+    };
+    match api
+        .replace(lease_name, &PostParams::default(), &updated)
+        .await
+    {
+        Ok(_) => Ok(true),
+        Err(kube::Error::Api(err)) if err.code == 409 => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Releases a lease held by the identity by clearing the holder and expiring
+/// it immediately.
+async fn release(api: &Api<Lease>, lease_name: &str, identity: &str) -> Result<()> {
+    let lease = match api.get_opt(lease_name).await? {
+        Some(lease) => lease,
+        None => return Ok(()),
+    };
+
+    let mut spec = lease.spec.clone().unwrap_or_default();
+    if spec.holder_identity.as_deref() != Some(identity) {
+        return Ok(());
+    }
+
+    spec.holder_identity = None;
+    spec.lease_duration_seconds = Some(1);
+    spec.renew_time = Some(MicroTime(Utc::now()));
+
+    api.replace(
+        lease_name,
+        &PostParams::default(),
+        &Lease {
+            metadata: lease.metadata.clone(),
+            spec: Some(spec),
+        },
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        extract::State,
+        http::StatusCode,
+        response::{IntoResponse, Response},
+        routing::get,
+        Json, Router,
+    };
+    use parking_lot::Mutex;
+    use serde_json::json;
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::Arc;
+
+    /// The lease that the API stand-in serves, and whether it answers writes
+    /// with a conflict.
+    #[derive(Clone, Default)]
+    struct StandIn {
+        lease: Arc<Mutex<Option<Lease>>>,
+        conflict: Arc<AtomicBool>,
+    }
+
+    fn status(code: StatusCode, reason: &str) -> Response {
+        (
+            code,
+            Json(json!({
+                "apiVersion": "v1",
+                "kind": "Status",
+                "metadata": {},
+                "status": "Failure",
+                "message": reason,
+                "reason": reason,
+                "code": code.as_u16(),
+            })),
+        )
+            .into_response()
+    }
+
+    async fn get_lease(State(stand_in): State<StandIn>) -> Response {
+        match stand_in.lease.lock().clone() {
+            Some(lease) => Json(lease).into_response(),
+            None => status(StatusCode::NOT_FOUND, "NotFound"),
+        }
+    }
+
+    async fn create_lease(State(stand_in): State<StandIn>, Json(lease): Json<Lease>) -> Response {
+        let mut stored = stand_in.lease.lock();
+        if stored.is_some() {
+            return status(StatusCode::CONFLICT, "AlreadyExists");
+        }
+        *stored = Some(lease.clone());
+        (StatusCode::CREATED, Json(lease)).into_response()
+    }
+
+    async fn replace_lease(State(stand_in): State<StandIn>, Json(lease): Json<Lease>) -> Response {
+        if stand_in.conflict.load(Ordering::SeqCst) {
+            return status(StatusCode::CONFLICT, "Conflict");
+        }
+        *stand_in.lease.lock() = Some(lease.clone());
+        Json(lease).into_response()
+    }
+
+    /// Starts a stand-in for the kubernetes API that only serves the leases
+    /// of the default namespace.
+    fn api_stand_in(stand_in: StandIn) -> Api<Lease> {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind should succeed");
+        let addr: SocketAddr = listener.local_addr().expect("local addr should exist");
+
+        let app = Router::new()
+            .route(
+                "/apis/coordination.k8s.io/v1/namespaces/default/leases",
+                axum::routing::post(create_lease),
+            )
+            .route(
+                "/apis/coordination.k8s.io/v1/namespaces/default/leases/:name",
+                get(get_lease).put(replace_lease),
+            )
+            .with_state(stand_in);
+
+        tokio::spawn(async move {
+            axum::Server::from_tcp(listener)
+                .expect("server should start")
+                .serve(app.into_make_service())
+                .await
+                .expect("server should run");
+        });
+
+        let config = kube::Config::new(
+            format!("http://{addr}")
+                .parse()
+                .expect("url should be valid"),
+        );
+        let client = Client::try_from(config).expect("client should be created");
+        Api::namespaced(client, "default")
+    }
+
+    fn lease(holder: &str, renewed: chrono::DateTime<Utc>, transitions: i32) -> Lease {
+        Lease {
+            metadata: ObjectMeta {
+                name: Some("leader".to_string()),
+                ..Default::default()
+            },
+            spec: Some(LeaseSpec {
+                holder_identity: Some(holder.to_string()),
+                lease_duration_seconds: Some(15),
+                acquire_time: Some(MicroTime(renewed)),
+                renew_time: Some(MicroTime(renewed)),
+                lease_transitions: Some(transitions),
+            }),
+        }
+    }
+
+    fn stored_spec(stand_in: &StandIn) -> LeaseSpec {
+        stand_in
+            .lease
+            .lock()
+            .clone()
+            .and_then(|lease| lease.spec)
+            .expect("lease should be stored")
+    }
+
+    #[tokio::test]
+    async fn creates_missing_lease() {
+        let stand_in = StandIn::default();
+        let api = api_stand_in(stand_in.clone());
+
+        assert!(try_acquire_or_renew(&api, "leader", "a", 15).await.unwrap());
+        let spec = stored_spec(&stand_in);
+        assert_eq!(spec.holder_identity.as_deref(), Some("a"));
+        assert_eq!(spec.lease_transitions, Some(0));
+    }
+
+    #[tokio::test]
+    async fn renews_held_lease() {
+        let renewed = Utc::now() - Duration::seconds(5);
+        let stand_in = StandIn::default();
+        *stand_in.lease.lock() = Some(lease("a", renewed, 1));
+        let api = api_stand_in(stand_in.clone());
+
+        assert!(try_acquire_or_renew(&api, "leader", "a", 15).await.unwrap());
+        let spec = stored_spec(&stand_in);
+        assert_eq!(spec.holder_identity.as_deref(), Some("a"));
+        assert!(spec.renew_time.unwrap().0 > renewed);
+        assert_eq!(spec.lease_transitions, Some(1));
+    }
+
+    #[tokio::test]
+    async fn keeps_unexpired_lease_of_another_holder() {
+        let stand_in = StandIn::default();
+        *stand_in.lease.lock() = Some(lease("b", Utc::now(), 1));
+        let api = api_stand_in(stand_in.clone());
+
+        assert!(!try_acquire_or_renew(&api, "leader", "a", 15).await.unwrap());
+        assert_eq!(stored_spec(&stand_in).holder_identity.as_deref(), Some("b"));
+    }
+
+    #[tokio::test]
+    async fn takes_over_expired_lease() {
+        let stand_in = StandIn::default();
+        *stand_in.lease.lock() = Some(lease("b", Utc::now() - Duration::seconds(60), 2));
+        let api = api_stand_in(stand_in.clone());
+
+        assert!(try_acquire_or_renew(&api, "leader", "a", 15).await.unwrap());
+        let spec = stored_spec(&stand_in);
+        assert_eq!(spec.holder_identity.as_deref(), Some("a"));
+        assert_eq!(spec.lease_transitions, Some(3));
+    }
+
+    #[tokio::test]
+    async fn conflicting_renewal_is_not_held() {
+        let stand_in = StandIn::default();
+        *stand_in.lease.lock() = Some(lease("a", Utc::now(), 1));
+        stand_in.conflict.store(true, Ordering::SeqCst);
+        let api = api_stand_in(stand_in.clone());
+
+        assert!(!try_acquire_or_renew(&api, "leader", "a", 15).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn releases_only_own_lease() {
+        let stand_in = StandIn::default();
+        *stand_in.lease.lock() = Some(lease("b", Utc::now(), 1));
+        let api = api_stand_in(stand_in.clone());
+
+        release(&api, "leader", "a").await.unwrap();
+        assert_eq!(stored_spec(&stand_in).holder_identity.as_deref(), Some("b"));
+
+        release(&api, "leader", "b").await.unwrap();
+        let spec = stored_spec(&stand_in);
+        assert_eq!(spec.holder_identity, None);
+        assert_eq!(spec.lease_duration_seconds, Some(1));
+    }
+
+    #[test]
+    fn renew_deadline_is_before_expiry() {
+        assert_eq!(renew_deadline(15), Duration::seconds(10));
+        assert!(renew_deadline(15) + Duration::seconds(5) <= Duration::seconds(15));
+    }
+}
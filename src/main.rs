@@ -1,56 +1,103 @@
+use anyhow::anyhow;
 use axum_server::{tls_rustls::RustlsConfig, Handle};
-use consulrs::client::ConsulClientSettingsBuilder;
 use std::net::SocketAddr;
 use std::{borrow::BorrowMut, sync::Arc};
 use tokio::signal;
 use tokio::sync::{broadcast, mpsc};
 use tokio_tasker::Tasker;
 use tracing::{error, info, warn};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod address;
 mod api;
+mod auth;
 mod checksum;
+mod cli;
 mod config;
 mod consul;
 mod deployment_updater;
+mod duration;
 mod error;
+mod events;
 mod k8s;
 mod key_manager;
+mod leader;
+mod manifests;
+mod reload;
+mod scope;
+mod shard;
 mod state;
+mod telemetry;
+mod webhook;
 
-use api::build_router;
+use api::{build_admin_router, build_admission_router, build_router};
 use error::Result;
 
 use crate::{
+    auth::get_authenticator,
     checksum::get_checksummer,
-    config::SettingsBuilder,
-    consul::watch_dispatcher,
+    cli::{Args, Command},
+    config::load_settings,
+    consul::{watch_dispatcher, ConsulClients},
     deployment_updater::deployment_update_loop,
     k8s::deployment_watch,
     key_manager::get_key_manager,
+    leader::leader_election_loop,
+    reload::{certificate_reload_loop, consul_token_reload_loop},
+    scope::Scope,
+    shard::shard_membership_loop,
     state::{ConsulWatch, DeploymentUpdate},
+    webhook::{provision, webhook_certificate_loop},
 };
 
+/// The container image used by `manifests` when `--image` isn't given.
+const DEFAULT_IMAGE: &str = "ghcr.io/ngerakines/k8s-consul-mutator-rs";
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::new(
-            std::env::var("RUST_LOG")
-                .unwrap_or_else(|_| "k8s_consul_mutator_rs=debug,tower_http=debug".into()),
-        ))
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    let args = match Args::from_env() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(2);
+        }
+    };
 
-    #[cfg(debug_assertions)]
-    warn!("Debug assertions enabled");
+    let settings = match load_settings(args.config_file.as_deref()) {
+        Ok(settings) => settings,
+        Err(errors) => {
+            eprintln!("invalid configuration:");
+            for error in errors {
+                eprintln!("  {error}");
+            }
+            std::process::exit(1);
+        }
+    };
 
-    let settings_builder = SettingsBuilder::default();
-    let settings = settings_builder.build().unwrap();
+    if args.command == Command::DumpConfig {
+        print!("{}", toml::to_string(&settings.redacted())?);
+        return Ok(());
+    }
 
-    if !settings.is_secure_enabled() && !settings.is_insecure_enabled() {
-        panic!("one or both of PORT and SECURE_PORT must be set to a non-zero value");
+    if args.command == Command::Manifests {
+        let ca_bundle = match args.ca_bundle.as_deref() {
+            Some(path) => Some(std::fs::read_to_string(path)?),
+            None => None,
+        };
+        let image = args
+            .image
+            .unwrap_or_else(|| format!("{DEFAULT_IMAGE}:{}", env!("CARGO_PKG_VERSION")));
+        print!(
+            "{}",
+            manifests::render(&settings, &image, ca_bundle.as_deref())?
+        );
+        return Ok(());
     }
 
+    telemetry::init(&settings)?;
+
+    #[cfg(debug_assertions)]
+    warn!("Debug assertions enabled");
+
     if !settings.set_deployment_annotations
         && !settings.set_deployment_spec_annotations
         && !settings.set_deployment_timestamp
@@ -63,7 +110,18 @@ async fn main() -> Result<()> {
 
     let checksummer = get_checksummer(&settings.checksum_type);
 
-    let consul_config_builder = ConsulClientSettingsBuilder::default();
+    let authenticator = get_authenticator(&settings.api_auth, &settings.admin_token)
+        .await
+        .map_err(|err| anyhow!("API_AUTH authenticator failed: {err}"))?;
+
+    // Invalid selectors have already been reported by the validation.
+    let scope = Scope::new(&settings)?;
+
+    if settings.api_auth == "none" {
+        warn!("API_AUTH is set to none. The debug and admin routes are available to anyone that can reach them.");
+    }
+
+    let consul_clients = ConsulClients::new(&settings)?;
 
     let tasker = Tasker::new();
 
@@ -92,15 +150,43 @@ async fn main() -> Result<()> {
 
         let state_tasker = tasker.clone();
         let shared_state = state::AppState(Arc::new(state::InnerState::new(
-            settings_builder.build().unwrap(),
+            settings.clone(),
             key_manager,
-            consul_config_builder.build().unwrap(),
+            consul_clients,
             state_tasker.clone(),
             updater_tx.clone(),
             watch_dispatcher_tx.clone(),
             checksummer,
+            authenticator,
+            scope,
         )));
 
+        if settings.leader_election {
+            let leader_election_stopper = tasker.stopper();
+            let leader_election_state = shared_state.clone();
+
+            tasker.spawn(async move {
+                if let Err(err) =
+                    leader_election_loop(leader_election_state, leader_election_stopper).await
+                {
+                    error!("leader election failed: {}", err);
+                }
+            });
+        }
+
+        if settings.sharding {
+            let shard_membership_stopper = tasker.stopper();
+            let shard_membership_state = shared_state.clone();
+
+            tasker.spawn(async move {
+                if let Err(err) =
+                    shard_membership_loop(shard_membership_state, shard_membership_stopper).await
+                {
+                    error!("shard membership failed: {}", err);
+                }
+            });
+        }
+
         {
             let update_loop_stopper = tasker.stopper();
             let update_loop_shared_state = shared_state.clone();
@@ -144,14 +230,136 @@ async fn main() -> Result<()> {
             });
         }
 
-        let app = build_router(shared_state.clone());
+        if shared_state.consul.has_token_files() {
+            let consul_token_reload_stopper = tasker.stopper();
+            let consul_token_reload_state = shared_state.clone();
+
+            tasker.spawn(consul_token_reload_loop(
+                consul_token_reload_state,
+                consul_token_reload_stopper,
+            ));
+        }
+
+        #[cfg(unix)]
+        {
+            let settings_reload_stopper = tasker.stopper();
+            let settings_reload_state = shared_state.clone();
+            let config_file = args.config_file.clone();
+
+            tasker.spawn(async move {
+                if let Err(err) = reload::settings_reload_loop(
+                    settings_reload_state,
+                    config_file,
+                    settings_reload_stopper,
+                )
+                .await
+                {
+                    error!("settings reload failed: {}", err);
+                }
+            });
+        }
+
+        // The secure and admin listeners share the TLS configuration so that
+        // reloading the certificate applies to both.
+        let tls_config = if settings.self_managed_tls {
+            let certificate = provision(&settings)
+                .await
+                .map_err(|err| anyhow!("webhook certificate provisioning failed: {err}"))?;
+            let tls_config = RustlsConfig::from_pem(
+                certificate.certificate.clone().into_bytes(),
+                certificate.key.clone().into_bytes(),
+            )
+            .await
+            .map_err(|err| anyhow!("webhook certificate is invalid: {err}"))?;
+
+            let webhook_certificate_stopper = tasker.stopper();
+            let webhook_certificate_state = shared_state.clone();
+
+            tasker.spawn(webhook_certificate_loop(
+                webhook_certificate_state,
+                tls_config.clone(),
+                certificate,
+                webhook_certificate_stopper,
+            ));
+
+            Some(tls_config)
+        } else if settings.has_certificate_files() {
+            Some(
+                RustlsConfig::from_pem_file(
+                    settings.certificate.clone(),
+                    settings.certificate_key.clone(),
+                )
+                .await
+                .unwrap(),
+            )
+        } else {
+            None
+        };
+
+        if let Some(tls_config) = tls_config.clone() {
+            if settings.has_certificate_files() && !settings.certificate_reload_interval.is_zero() {
+                let certificate_reload_stopper = tasker.stopper();
+                let certificate_reload_state = shared_state.clone();
+
+                tasker.spawn(certificate_reload_loop(
+                    certificate_reload_state,
+                    tls_config,
+                    certificate_reload_stopper,
+                ));
+            }
+        }
+
+        // When the admin listener is enabled, the debug and admin routes are
+        // only served by it and the other listeners only serve admission routes.
+        let app = if settings.is_admin_listener_enabled() {
+            build_admission_router(shared_state.clone())
+        } else {
+            build_router(shared_state.clone())
+        };
+
+        if settings.is_admin_listener_enabled() {
+            info!("admin server starting");
+
+            let addr = SocketAddr::from(([0, 0, 0, 0], settings.admin_port));
+
+            let handle = Handle::new();
+
+            let mut admin_notify = shutdown_tx.subscribe();
+            let shutdown_handler = handle.clone();
+            tokio::spawn(async move {
+                info!("admin server waiting on shutdown");
+                admin_notify.recv().await.unwrap();
+                handle.shutdown();
+                info!("admin server shutdown");
+            });
+
+            let admin_app = build_admin_router(shared_state.clone());
+            if let Some(tls_config) = tls_config.clone() {
+                tasker.spawn(async move {
+                    axum_server::bind_rustls(addr, tls_config)
+                        .handle(shutdown_handler)
+                        .serve(admin_app.into_make_service())
+                        .await
+                        .unwrap();
+                });
+            } else {
+                tasker.spawn(async move {
+                    axum_server::bind(addr)
+                        .handle(shutdown_handler)
+                        .serve(admin_app.into_make_service())
+                        .await
+                        .unwrap();
+                });
+            }
+        }
 
         if settings.is_insecure_enabled() {
             let mut insecure_notify = shutdown_tx.subscribe();
             let insecure_app = app.clone();
+            let insecure_port = settings.port;
             tasker.spawn(async move {
                 info!("insecure server starting");
-                axum::Server::bind(&format!("0.0.0.0:{}", settings.port).parse().unwrap())
+                axum::Server::bind(&format!("0.0.0.0:{}", insecure_port).parse().unwrap())
                     .serve(insecure_app.into_make_service())
                     .with_graceful_shutdown(async move {
                         info!("insecure server waiting on shutdown");
@@ -166,10 +374,7 @@ async fn main() -> Result<()> {
         if settings.is_secure_enabled() {
             info!("secure server starting");
 
-            let tls_config =
-                RustlsConfig::from_pem_file(settings.certificate, settings.certificate_key)
-                    .await
-                    .unwrap();
+            let tls_config = tls_config.clone().unwrap();
 
             let addr = SocketAddr::from(([0, 0, 0, 0], settings.secure_port));
 
@@ -214,5 +419,7 @@ async fn main() -> Result<()> {
 
     info!("shutdown complete");
 
+    telemetry::shutdown();
+
     Ok(())
 }
@@ -0,0 +1,734 @@
+use k8s_openapi::{
+    api::{
+        apps::v1::{Deployment, DeploymentSpec},
+        core::v1::{
+            Container, ContainerPort, EnvVar, EnvVarSource, HTTPGetAction, KeyToPath, PodSpec,
+            PodTemplateSpec, Probe, SecretKeySelector, SecretVolumeSource, Service, ServiceAccount,
+            ServicePort, ServiceSpec, Volume, VolumeMount,
+        },
+        rbac::v1::{
+            ClusterRole, ClusterRoleBinding, PolicyRule, Role, RoleBinding, RoleRef, Subject,
+        },
+    },
+    apimachinery::pkg::{
+        apis::meta::v1::{LabelSelector, ObjectMeta},
+        util::intstr::IntOrString,
+    },
+};
+use serde::Serialize;
+use std::{collections::BTreeMap, env, path::Path};
+
+use crate::checksum::supported_checksum_types;
+use crate::config::{consul_cluster_setting, Settings, SettingsBuilder};
+use crate::error::Result;
+use crate::webhook::webhook_configuration;
+
+/// The name of the secret that holds `ADMIN_TOKEN`, when it is used.
+const ADMIN_TOKEN_SECRET: &str = "k8s-consul-mutator-rs-admin";
+
+/// The name of the secret that holds the consul CA, client certificate, and
+/// token files, when they are used, by setting name.
+const CONSUL_SECRET: &str = "k8s-consul-mutator-rs-consul";
+
+/// Settings that are never rendered as environment variables because the
+/// defaults inside of the pod are correct, or because they are secret.
+const SKIPPED_SETTINGS: &[&str] = &[
+    "admin_token",
+    "leader_election_identity",
+    "shard_identity",
+    "webhook_service_namespace",
+];
+
+/// Renders the kubernetes resources needed to run with the settings as a
+/// multi-document YAML string. The service account, roles, and webhook
+/// configuration only grant and request what the settings use.
+pub fn render(settings: &Settings, image: &str, ca_bundle: Option<&str>) -> Result<String> {
+    let mut documents = vec![
+        to_document(&service_account(settings))?,
+        to_document(&cluster_role(settings))?,
+        to_document(&cluster_role_binding(settings))?,
+    ];
+
+    for (namespace, rules) in namespaced_rules(settings) {
+        let (role, binding) = role(settings, &namespace, rules);
+        documents.push(to_document(&role)?);
+        documents.push(to_document(&binding)?);
+    }
+
+    documents.push(to_document(&deployment(settings, image)?)?);
+    documents.push(to_document(&service(settings))?);
+
+    // The self-managed webhook configuration is created at start instead.
+    if !settings.self_managed_tls {
+        documents.push(to_document(&webhook_configuration(settings, ca_bundle)?)?);
+    }
+
+    Ok(format!(
+        "# Generated by k8s-consul-mutator-rs {} with features: {}\n{}",
+        settings.version.trim(),
+        enabled_features().join(", "),
+        documents.join("")
+    ))
+}
+
+fn to_document<T: Serialize>(resource: &T) -> Result<String> {
+    Ok(format!("---\n{}", serde_yaml::to_string(resource)?))
+}
+
+fn enabled_features() -> Vec<String> {
+    let mut features: Vec<String> = supported_checksum_types()
+        .into_iter()
+        .map(|checksum_type| checksum_type.to_string())
+        .collect();
+    for (enabled, feature) in [
+        (cfg!(feature = "v1_26"), "v1_26"),
+        (cfg!(feature = "v1_25"), "v1_25"),
+        (cfg!(feature = "v1_24"), "v1_24"),
+        (cfg!(feature = "v1_23"), "v1_23"),
+        (cfg!(feature = "v1_22"), "v1_22"),
+        (cfg!(feature = "v1_21"), "v1_21"),
+        (cfg!(feature = "v1_20"), "v1_20"),
+    ] {
+        if enabled {
+            features.push(feature.to_string());
+        }
+    }
+    features
+}
+
+/// Returns the metadata shared by every resource. Resources are named after
+/// the webhook service.
+fn metadata(settings: &Settings, namespace: Option<&str>) -> ObjectMeta {
+    ObjectMeta {
+        name: Some(settings.webhook_service_name.clone()),
+        namespace: namespace.map(|namespace| namespace.to_string()),
+        labels: Some(labels()),
+        ..Default::default()
+    }
+}
+
+fn labels() -> BTreeMap<String, String> {
+    BTreeMap::from([("app".to_string(), "k8s-consul-mutator-rs".to_string())])
+}
+
+fn rule(api_group: &str, resources: &[&str], verbs: &[&str]) -> PolicyRule {
+    PolicyRule {
+        api_groups: Some(vec![api_group.to_string()]),
+        resources: Some(resources.iter().map(|r| r.to_string()).collect()),
+        verbs: verbs.iter().map(|v| v.to_string()).collect(),
+        ..Default::default()
+    }
+}
+
+/// Returns the rules that can't be limited to a namespace.
+fn cluster_rules(settings: &Settings) -> Vec<PolicyRule> {
+    let mut rules = vec![];
+
+    if settings.namespaces.is_empty() {
+        rules.extend(workload_rules());
+    }
+    // Sharded replicas watch namespaces to find the ones that they own.
+    if settings.sharding && settings.namespaces.is_empty() {
+        rules.push(rule("", &["namespaces"], &["get", "list", "watch"]));
+    } else if !settings.namespace_label_selector.is_empty() {
+        rules.push(rule("", &["namespaces"], &["get"]));
+    }
+    if settings.api_auth == "kubernetes" {
+        rules.push(rule(
+            "authentication.k8s.io",
+            &["tokenreviews"],
+            &["create"],
+        ));
+        rules.push(rule(
+            "authorization.k8s.io",
+            &["subjectaccessreviews"],
+            &["create"],
+        ));
+    }
+    if settings.self_managed_tls {
+        rules.push(rule(
+            "admissionregistration.k8s.io",
+            &["mutatingwebhookconfigurations"],
+            &["get", "create", "patch"],
+        ));
+    }
+
+    rules
+}
+
+/// Returns the rules needed to manage deployments and to record events on
+/// them.
+fn workload_rules() -> Vec<PolicyRule> {
+    vec![
+        rule("apps", &["deployments"], &["get", "list", "watch", "patch"]),
+        rule("events.k8s.io", &["events"], &["create"]),
+    ]
+}
+
+/// Returns the rules that are limited to a namespace, by namespace.
+fn namespaced_rules(settings: &Settings) -> BTreeMap<String, Vec<PolicyRule>> {
+    let mut rules: BTreeMap<String, Vec<PolicyRule>> = BTreeMap::new();
+
+    for namespace in settings.namespaces.iter() {
+        rules
+            .entry(namespace.clone())
+            .or_default()
+            .extend(workload_rules());
+    }
+    if settings.leader_election {
+        rules
+            .entry(settings.leader_election_namespace.clone())
+            .or_default()
+            .push(rule(
+                "coordination.k8s.io",
+                &["leases"],
+                &["get", "create", "update"],
+            ));
+    }
+    if settings.sharding {
+        rules
+            .entry(settings.shard_namespace.clone())
+            .or_default()
+            .push(rule(
+                "coordination.k8s.io",
+                &["leases"],
+                &["get", "list", "create", "update", "delete"],
+            ));
+    }
+    if settings.self_managed_tls {
+        rules
+            .entry(settings.webhook_service_namespace.clone())
+            .or_default()
+            .push(rule("", &["secrets"], &["get", "create", "update"]));
+    }
+
+    rules
+}
+
+fn service_account(settings: &Settings) -> ServiceAccount {
+    ServiceAccount {
+        metadata: metadata(settings, Some(&settings.webhook_service_namespace)),
+        ..Default::default()
+    }
+}
+
+fn subject(settings: &Settings) -> Subject {
+    Subject {
+        kind: "ServiceAccount".to_string(),
+        name: settings.webhook_service_name.clone(),
+        namespace: Some(settings.webhook_service_namespace.clone()),
+        ..Default::default()
+    }
+}
+
+fn cluster_role(settings: &Settings) -> ClusterRole {
+    ClusterRole {
+        metadata: metadata(settings, None),
+        rules: Some(cluster_rules(settings)),
+        ..Default::default()
+    }
+}
+
+fn cluster_role_binding(settings: &Settings) -> ClusterRoleBinding {
+    ClusterRoleBinding {
+        metadata: metadata(settings, None),
+        role_ref: RoleRef {
+            api_group: "rbac.authorization.k8s.io".to_string(),
+            kind: "ClusterRole".to_string(),
+            name: settings.webhook_service_name.clone(),
+        },
+        subjects: Some(vec![subject(settings)]),
+    }
+}
+
+fn role(settings: &Settings, namespace: &str, rules: Vec<PolicyRule>) -> (Role, RoleBinding) {
+    let role = Role {
+        metadata: metadata(settings, Some(namespace)),
+        rules: Some(rules),
+    };
+    let binding = RoleBinding {
+        metadata: metadata(settings, Some(namespace)),
+        role_ref: RoleRef {
+            api_group: "rbac.authorization.k8s.io".to_string(),
+            kind: "Role".to_string(),
+            name: settings.webhook_service_name.clone(),
+        },
+        subjects: Some(vec![subject(settings)]),
+    };
+    (role, binding)
+}
+
+/// Returns the environment variables for the settings that differ from their
+/// defaults.
+fn environment(settings: &Settings) -> Result<Vec<EnvVar>> {
+    let defaults = SettingsBuilder::default().build()?;
+    let values = match serde_json::to_value(settings)? {
+        serde_json::Value::Object(values) => values,
+        _ => serde_json::Map::new(),
+    };
+
+    let mut environment = vec![];
+    for name in defaults.changed(settings) {
+        if SKIPPED_SETTINGS.contains(&name.as_str()) {
+            continue;
+        }
+        // The lease namespaces default to the namespace of the pod.
+        if (name == "leader_election_namespace"
+            && settings.leader_election_namespace == settings.webhook_service_namespace)
+            || (name == "shard_namespace"
+                && settings.shard_namespace == settings.webhook_service_namespace)
+        {
+            continue;
+        }
+        let value = match values.get(&name) {
+            Some(serde_json::Value::String(value)) => value.clone(),
+            Some(serde_json::Value::Array(items)) => items
+                .iter()
+                .filter_map(|item| item.as_str())
+                .collect::<Vec<&str>>()
+                .join(","),
+            Some(value) => value.to_string(),
+            None => continue,
+        };
+        environment.push(EnvVar {
+            name: name.to_uppercase(),
+            value: Some(value),
+            ..Default::default()
+        });
+    }
+
+    if !settings.admin_token.is_empty() {
+        environment.push(EnvVar {
+            name: "ADMIN_TOKEN".to_string(),
+            value_from: Some(EnvVarSource {
+                secret_key_ref: Some(SecretKeySelector {
+                    name: Some(ADMIN_TOKEN_SECRET.to_string()),
+                    key: "token".to_string(),
+                    optional: None,
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+    }
+
+    if let Ok(address) = env::var("CONSUL_HTTP_ADDR") {
+        environment.push(EnvVar {
+            name: "CONSUL_HTTP_ADDR".to_string(),
+            value: Some(address),
+            ..Default::default()
+        });
+    }
+
+    Ok(environment)
+}
+
+/// Returns the volumes and mounts for the certificate files. The certificate
+/// and key are read from the webhook secret. When both files are in the same
+/// directory the secret is mounted as a directory so that renewed
+/// certificates are picked up by the certificate reloader.
+fn certificate_volumes(settings: &Settings) -> (Vec<Volume>, Vec<VolumeMount>) {
+    if !settings.has_certificate_files() {
+        return (vec![], vec![]);
+    }
+
+    let certificate = Path::new(&settings.certificate);
+    let certificate_key = Path::new(&settings.certificate_key);
+    let file_name = |path: &Path| {
+        path.file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default()
+    };
+
+    let shared_directory =
+        certificate.parent() == certificate_key.parent() && certificate.parent().is_some();
+
+    let volume = Volume {
+        name: "tls".to_string(),
+        secret: Some(SecretVolumeSource {
+            secret_name: Some(settings.webhook_secret_name.clone()),
+            items: shared_directory.then(|| {
+                vec![
+                    KeyToPath {
+                        key: "tls.crt".to_string(),
+                        path: file_name(certificate),
+                        mode: None,
+                    },
+                    KeyToPath {
+                        key: "tls.key".to_string(),
+                        path: file_name(certificate_key),
+                        mode: None,
+                    },
+                ]
+            }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+
+    let mounts = if shared_directory {
+        vec![VolumeMount {
+            name: "tls".to_string(),
+            mount_path: certificate
+                .parent()
+                .map(|parent| parent.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            read_only: Some(true),
+            ..Default::default()
+        }]
+    } else {
+        vec![
+            VolumeMount {
+                name: "tls".to_string(),
+                mount_path: settings.certificate.clone(),
+                sub_path: Some("tls.crt".to_string()),
+                read_only: Some(true),
+                ..Default::default()
+            },
+            VolumeMount {
+                name: "tls".to_string(),
+                mount_path: settings.certificate_key.clone(),
+                sub_path: Some("tls.key".to_string()),
+                read_only: Some(true),
+                ..Default::default()
+            },
+        ]
+    };
+
+    (vec![volume], mounts)
+}
+
+/// Returns the consul files of the default and named clusters that are set,
+/// as pairs of setting name and path.
+fn consul_files(settings: &Settings) -> Vec<(String, String)> {
+    let mut endpoints = vec![("consul_".to_string(), settings.consul_endpoint())];
+    for cluster in settings.consul_clusters.iter() {
+        endpoints.push((
+            consul_cluster_setting(cluster, ""),
+            settings.consul_cluster_endpoint(cluster),
+        ));
+    }
+
+    let mut files = vec![];
+    for (prefix, endpoint) in endpoints {
+        for (setting, path) in [
+            ("http_token_file", endpoint.token_file),
+            ("cacert", endpoint.ca_cert),
+            ("client_cert", endpoint.client_cert),
+            ("client_key", endpoint.client_key),
+        ] {
+            if let Some(path) = path {
+                files.push((format!("{prefix}{setting}"), path));
+            }
+        }
+    }
+    files
+}
+
+/// Returns the volumes and mounts for the consul files, which are read from
+/// the consul secret. Files are mounted by directory so that a rotated token
+/// file is picked up. Files in the root directory or in a directory that is
+/// already mounted are mounted one at a time instead.
+fn consul_volumes(settings: &Settings, mounted: &[VolumeMount]) -> (Vec<Volume>, Vec<VolumeMount>) {
+    let mut directories: BTreeMap<String, BTreeMap<String, String>> = BTreeMap::new();
+    let mut files: BTreeMap<String, String> = BTreeMap::new();
+    for (setting, path) in consul_files(settings) {
+        let file = Path::new(&path);
+        let directory = file
+            .parent()
+            .map(|parent| parent.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let file_name = file
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if directory.is_empty()
+            || directory == "/"
+            || mounted.iter().any(|mount| mount.mount_path == directory)
+        {
+            files.entry(path).or_insert(setting);
+        } else {
+            directories
+                .entry(directory)
+                .or_default()
+                .entry(file_name)
+                .or_insert(setting);
+        }
+    }
+
+    let volume = |name: &str, items: Vec<KeyToPath>| Volume {
+        name: name.to_string(),
+        secret: Some(SecretVolumeSource {
+            secret_name: Some(CONSUL_SECRET.to_string()),
+            items: Some(items),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+
+    let mut volumes = vec![];
+    let mut mounts = vec![];
+    for (index, (directory, items)) in directories.into_iter().enumerate() {
+        let name = format!("consul-{index}");
+        let items = items
+            .into_iter()
+            .map(|(path, key)| KeyToPath {
+                key,
+                path,
+                mode: None,
+            })
+            .collect();
+        volumes.push(volume(&name, items));
+        mounts.push(VolumeMount {
+            name,
+            mount_path: directory,
+            read_only: Some(true),
+            ..Default::default()
+        });
+    }
+    if !files.is_empty() {
+        let items = files
+            .values()
+            .map(|key| KeyToPath {
+                key: key.clone(),
+                path: key.clone(),
+                mode: None,
+            })
+            .collect();
+        volumes.push(volume("consul-files", items));
+        for (path, key) in files {
+            mounts.push(VolumeMount {
+                name: "consul-files".to_string(),
+                mount_path: path,
+                sub_path: Some(key),
+                read_only: Some(true),
+                ..Default::default()
+            });
+        }
+    }
+
+    (volumes, mounts)
+}
+
+fn container_ports(settings: &Settings) -> Vec<(&'static str, u16)> {
+    let mut ports = vec![];
+    if settings.is_insecure_enabled() {
+        ports.push(("http", settings.port));
+    }
+    if settings.is_secure_enabled() {
+        ports.push(("https", settings.secure_port));
+    }
+    if settings.is_admin_listener_enabled() {
+        ports.push(("admin", settings.admin_port));
+    }
+    ports
+}
+
+fn deployment(settings: &Settings, image: &str) -> Result<Deployment> {
+    let (mut volumes, mut volume_mounts) = certificate_volumes(settings);
+    let (consul_volumes, consul_mounts) = consul_volumes(settings, &volume_mounts);
+    volumes.extend(consul_volumes);
+    volume_mounts.extend(consul_mounts);
+
+    let probe_port = if settings.is_insecure_enabled() {
+        ("http", "HTTP")
+    } else {
+        ("https", "HTTPS")
+    };
+    let probe = |failure_threshold: i32| Probe {
+        http_get: Some(HTTPGetAction {
+            path: Some("/".to_string()),
+            port: IntOrString::String(probe_port.0.to_string()),
+            scheme: Some(probe_port.1.to_string()),
+            ..Default::default()
+        }),
+        timeout_seconds: Some(10),
+        period_seconds: Some(10),
+        failure_threshold: Some(failure_threshold),
+        ..Default::default()
+    };
+
+    Ok(Deployment {
+        metadata: metadata(settings, Some(&settings.webhook_service_namespace)),
+        spec: Some(DeploymentSpec {
+            replicas: Some(if settings.leader_election || settings.sharding {
+                2
+            } else {
+                1
+            }),
+            revision_history_limit: Some(2),
+            selector: LabelSelector {
+                match_labels: Some(labels()),
+                ..Default::default()
+            },
+            template: PodTemplateSpec {
+                metadata: Some(ObjectMeta {
+                    labels: Some(labels()),
+                    ..Default::default()
+                }),
+                spec: Some(PodSpec {
+                    service_account_name: Some(settings.webhook_service_name.clone()),
+                    termination_grace_period_seconds: Some(90),
+                    containers: vec![Container {
+                        name: "app".to_string(),
+                        image: Some(image.to_string()),
+                        env: Some(environment(settings)?),
+                        ports: Some(
+                            container_ports(settings)
+                                .into_iter()
+                                .map(|(name, port)| ContainerPort {
+                                    name: Some(name.to_string()),
+                                    container_port: port as i32,
+                                    protocol: Some("TCP".to_string()),
+                                    ..Default::default()
+                                })
+                                .collect(),
+                        ),
+                        startup_probe: Some(probe(30)),
+                        liveness_probe: Some(probe(6)),
+                        readiness_probe: Some(probe(3)),
+                        volume_mounts: Some(volume_mounts),
+                        ..Default::default()
+                    }],
+                    volumes: Some(volumes),
+                    ..Default::default()
+                }),
+            },
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+}
+
+fn service(settings: &Settings) -> Service {
+    let ports = container_ports(settings)
+        .into_iter()
+        .map(|(name, port)| ServicePort {
+            name: Some(name.to_string()),
+            port: match name {
+                "http" => 80,
+                "https" => 443,
+                _ => port as i32,
+            },
+            target_port: Some(IntOrString::String(name.to_string())),
+            protocol: Some("TCP".to_string()),
+            ..Default::default()
+        })
+        .collect();
+
+    Service {
+        metadata: metadata(settings, Some(&settings.webhook_service_namespace)),
+        spec: Some(ServiceSpec {
+            type_: Some("ClusterIP".to_string()),
+            ports: Some(ports),
+            selector: Some(labels()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_least_privilege() {
+        let settings = SettingsBuilder::default()
+            .webhook_service_namespace("mutator")
+            .namespaces(vec!["team-a".to_string()])
+            .update_debounce(std::time::Duration::from_secs(90))
+            .build()
+            .unwrap();
+
+        let rendered = render(&settings, "app:1", None).unwrap();
+        assert!(rendered.contains("kind: MutatingWebhookConfiguration"));
+        assert!(rendered.contains("- deployments"));
+        assert!(!rendered.contains("leases"));
+        assert!(!rendered.contains("tokenreviews"));
+
+        // Deployments are only granted in the managed namespace.
+        assert!(cluster_rules(&settings).is_empty());
+        assert_eq!(
+            namespaced_rules(&settings).keys().collect::<Vec<_>>(),
+            vec!["team-a"]
+        );
+
+        let environment = environment(&settings).unwrap();
+        let names: Vec<&str> = environment.iter().map(|e| e.name.as_str()).collect();
+        assert!(names.contains(&"UPDATE_DEBOUNCE"));
+        assert!(names.contains(&"NAMESPACES"));
+        assert!(!names.contains(&"WEBHOOK_SERVICE_NAMESPACE"));
+    }
+
+    #[test]
+    fn render_self_managed() {
+        let settings = SettingsBuilder::default()
+            .webhook_service_namespace("mutator")
+            .self_managed_tls(true)
+            .leader_election(true)
+            .leader_election_namespace("mutator")
+            .build()
+            .unwrap();
+
+        let rendered = render(&settings, "app:1", None).unwrap();
+        assert!(!rendered.contains("kind: MutatingWebhookConfiguration"));
+        assert!(rendered.contains("mutatingwebhookconfigurations"));
+
+        let rules = namespaced_rules(&settings);
+        assert_eq!(rules.get("mutator").map(|rules| rules.len()), Some(2));
+    }
+
+    #[test]
+    fn render_consul_files() {
+        let settings = SettingsBuilder::default()
+            .certificate("/etc/consul/tls.crt")
+            .certificate_key("/etc/consul/tls.key")
+            .consul_http_token_file("/var/run/consul/token")
+            .consul_cacert("/etc/consul/ca.pem")
+            .consul_clusters(vec!["dc2".to_string()])
+            .consul_cluster_settings(BTreeMap::from([
+                (
+                    "consul_cluster_dc2_http_addr".to_string(),
+                    "https://consul.dc2:8501".to_string(),
+                ),
+                (
+                    "consul_cluster_dc2_http_token_file".to_string(),
+                    "/var/run/consul/dc2-token".to_string(),
+                ),
+            ]))
+            .build()
+            .unwrap();
+
+        let (_, certificate_mounts) = certificate_volumes(&settings);
+        let (volumes, mounts) = consul_volumes(&settings, &certificate_mounts);
+        assert_eq!(volumes.len(), 2);
+
+        // Both tokens share a directory mount so that rotations are seen.
+        let tokens = volumes[0].secret.as_ref().unwrap();
+        assert_eq!(tokens.secret_name.as_deref(), Some(CONSUL_SECRET));
+        let items: Vec<(&str, &str)> = tokens
+            .items
+            .iter()
+            .flatten()
+            .map(|item| (item.key.as_str(), item.path.as_str()))
+            .collect();
+        assert_eq!(
+            items,
+            vec![
+                ("consul_cluster_dc2_http_token_file", "dc2-token"),
+                ("consul_http_token_file", "token"),
+            ]
+        );
+        assert_eq!(mounts[0].mount_path, "/var/run/consul");
+        assert_eq!(mounts[0].sub_path, None);
+
+        // The certificate directory is already mounted, so the CA is mounted
+        // as a single file.
+        assert_eq!(mounts[1].mount_path, "/etc/consul/ca.pem");
+        assert_eq!(mounts[1].sub_path.as_deref(), Some("consul_cacert"));
+
+        let rendered = render(&settings, "app:1", None).unwrap();
+        assert!(rendered.contains(CONSUL_SECRET));
+    }
+}
@@ -0,0 +1,148 @@
+use axum_server::tls_rustls::RustlsConfig;
+use std::{
+    fs,
+    time::{Duration, SystemTime},
+};
+use tokio::time::sleep;
+use tokio_tasker::Stopper;
+use tracing::{error, info, warn};
+
+use crate::config::load_settings;
+use crate::error::Result;
+use crate::state::AppState;
+
+/// This is the main loop that reloads the TLS certificate and key when either
+/// file changes. The files are polled because they are commonly mounted from
+/// secrets, which are updated with symlink swaps.
+///
+/// When the files can't be loaded, for example because only one of them has
+/// been written so far, the previous certificate is kept and the reload is
+/// retried on the next poll.
+pub async fn certificate_reload_loop(
+    app_state: AppState,
+    tls_config: RustlsConfig,
+    stopper: Stopper,
+) {
+    let settings = app_state.settings();
+    let certificate = settings.certificate.clone();
+    let certificate_key = settings.certificate_key.clone();
+    let interval = settings.certificate_reload_interval;
+
+    info!("certificate reloader started: {certificate} {certificate_key}");
+
+    let mut last_modified = modified(&certificate, &certificate_key);
+
+    while !stopper.is_stopped() {
+        tokio::select! {
+            _ = sleep(interval) => {},
+            _ = stopper.clone() => break,
+        }
+
+        let current = modified(&certificate, &certificate_key);
+        if current.is_none() || current == last_modified {
+            continue;
+        }
+
+        match tls_config
+            .reload_from_pem_file(&certificate, &certificate_key)
+            .await
+        {
+            Ok(_) => {
+                info!("certificate reloader reloaded certificate: {certificate}");
+                last_modified = current;
+            }
+            Err(err) => error!("certificate reloader error: {certificate}: {err}"),
+        }
+    }
+
+    info!("certificate reloader stopped");
+}
+
+/// Returns the most recent modification time of the files.
+fn modified(certificate: &str, certificate_key: &str) -> Option<SystemTime> {
+    let certificate = fs::metadata(certificate).and_then(|m| m.modified()).ok()?;
+    let certificate_key = fs::metadata(certificate_key)
+        .and_then(|m| m.modified())
+        .ok()?;
+    Some(certificate.max(certificate_key))
+}
+
+/// How often the consul token file is read to find a rotated token.
+const CONSUL_TOKEN_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// This is the main loop that reloads the consul ACL tokens when a token file
+/// changes. Watchers also reload the token when consul denies a read, so this
+/// mostly lets a rotated token be used before the old one is revoked.
+pub async fn consul_token_reload_loop(app_state: AppState, stopper: Stopper) {
+    info!("consul token reloader started");
+
+    while !stopper.is_stopped() {
+        tokio::select! {
+            _ = sleep(CONSUL_TOKEN_RELOAD_INTERVAL) => {},
+            _ = stopper.clone() => break,
+        }
+
+        for (_, client) in app_state.consul.all() {
+            let token_file = match client.token_file() {
+                Some(token_file) => token_file,
+                None => continue,
+            };
+            match client.reload_token() {
+                Ok(true) => info!("consul token reloader reloaded token: {token_file}"),
+                Ok(false) => {}
+                Err(err) => error!("consul token reloader error: {token_file}: {err}"),
+            }
+        }
+    }
+
+    info!("consul token reloader stopped");
+}
+
+/// This is the main loop that reloads settings when SIGHUP is received. Only
+/// `RELOADABLE_SETTINGS` are applied, and watchers are not restarted. Changes
+/// to other settings are logged and require a restart.
+#[cfg(unix)]
+pub async fn settings_reload_loop(
+    app_state: AppState,
+    config_file: Option<String>,
+    stopper: Stopper,
+) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    info!("settings reloader started");
+
+    while !stopper.is_stopped() {
+        tokio::select! {
+            _ = hangup.recv() => {},
+            _ = stopper.clone() => break,
+        }
+
+        info!("settings reloader received SIGHUP");
+
+        let loaded = match load_settings(config_file.as_deref()) {
+            Ok(loaded) => loaded,
+            Err(errors) => {
+                for err in errors {
+                    error!("settings reloader error: {err}");
+                }
+                continue;
+            }
+        };
+
+        match app_state.reload_settings(&loaded).await {
+            Ok((applied, ignored)) => {
+                info!("settings reloader applied: {applied:?}");
+                if !ignored.is_empty() {
+                    warn!("settings reloader ignored changes that require a restart: {ignored:?}");
+                }
+            }
+            Err(err) => error!("settings reloader error: {err}"),
+        }
+    }
+
+    info!("settings reloader stopped");
+
+    Ok(())
+}
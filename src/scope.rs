@@ -0,0 +1,350 @@
+use anyhow::anyhow;
+use chrono::{DateTime, Duration, Utc};
+use k8s_openapi::{
+    api::core::v1::Namespace, apimachinery::pkg::apis::meta::v1::LabelSelectorRequirement,
+};
+use kube::{api::Api, Client, ResourceExt};
+use parking_lot::RwLock;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+use tokio::sync::{watch, OnceCell};
+use tracing::warn;
+
+use crate::config::Settings;
+use crate::error::Result;
+
+/// How long the labels of a namespace are cached for.
+const NAMESPACE_LABELS_TTL: i64 = 60;
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+enum Requirement {
+    Equals(String, String),
+    NotEquals(String, String),
+    In(String, Vec<String>),
+    NotIn(String, Vec<String>),
+    Exists(String),
+    DoesNotExist(String),
+}
+
+impl Requirement {
+    fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        match self {
+            Requirement::Equals(key, value) => labels.get(key) == Some(value),
+            Requirement::NotEquals(key, value) => labels.get(key) != Some(value),
+            Requirement::In(key, values) => match labels.get(key) {
+                Some(value) => values.contains(value),
+                None => false,
+            },
+            Requirement::NotIn(key, values) => match labels.get(key) {
+                Some(value) => !values.contains(value),
+                None => true,
+            },
+            Requirement::Exists(key) => labels.contains_key(key),
+            Requirement::DoesNotExist(key) => !labels.contains_key(key),
+        }
+    }
+}
+
+/// A kubernetes label selector using the same syntax as `kubectl -l`, e.g.
+/// `app=web,tier!=cache,env in (prod,staging),!legacy`.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct LabelSelector {
+    raw: String,
+    requirements: Vec<Requirement>,
+}
+
+impl LabelSelector {
+    pub fn parse(raw: &str) -> Result<Self> {
+        let mut requirements = vec![];
+
+        for part in split_requirements(raw) {
+            let part = part.trim();
+            if part.is_empty() {
+                continue;
+            }
+
+            let requirement = if let Some(key) = part.strip_prefix('!') {
+                Requirement::DoesNotExist(valid_key(key.trim(), raw)?)
+            } else if let Some((key, values)) = split_set(part, " notin ") {
+                Requirement::NotIn(valid_key(key, raw)?, parse_values(values, raw)?)
+            } else if let Some((key, values)) = split_set(part, " in ") {
+                Requirement::In(valid_key(key, raw)?, parse_values(values, raw)?)
+            } else if let Some((key, value)) = part.split_once("!=") {
+                Requirement::NotEquals(valid_key(key.trim(), raw)?, value.trim().to_string())
+            } else if let Some((key, value)) = part.split_once("==") {
+                Requirement::Equals(valid_key(key.trim(), raw)?, value.trim().to_string())
+            } else if let Some((key, value)) = part.split_once('=') {
+                Requirement::Equals(valid_key(key.trim(), raw)?, value.trim().to_string())
+            } else {
+                Requirement::Exists(valid_key(part, raw)?)
+            };
+            requirements.push(requirement);
+        }
+
+        Ok(Self {
+            raw: raw.trim().to_string(),
+            requirements,
+        })
+    }
+
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        self.requirements.iter().all(|r| r.matches(labels))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.raw
+    }
+
+    /// Returns the selector as kubernetes label selector requirements, which
+    /// is the form used by webhook configurations.
+    pub fn to_requirements(&self) -> Vec<LabelSelectorRequirement> {
+        self.requirements
+            .iter()
+            .map(|requirement| {
+                let (key, operator, values) = match requirement {
+                    Requirement::Equals(key, value) => (key, "In", Some(vec![value.clone()])),
+                    Requirement::NotEquals(key, value) => (key, "NotIn", Some(vec![value.clone()])),
+                    Requirement::In(key, values) => (key, "In", Some(values.clone())),
+                    Requirement::NotIn(key, values) => (key, "NotIn", Some(values.clone())),
+                    Requirement::Exists(key) => (key, "Exists", None),
+                    Requirement::DoesNotExist(key) => (key, "DoesNotExist", None),
+                };
+                LabelSelectorRequirement {
+                    key: key.clone(),
+                    operator: operator.to_string(),
+                    values,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Splits a selector on commas that are not inside of a set of values.
+fn split_requirements(raw: &str) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in raw.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(&raw[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&raw[start..]);
+    parts
+}
+
+fn split_set<'a>(part: &'a str, operator: &str) -> Option<(&'a str, &'a str)> {
+    part.split_once(operator)
+        .map(|(key, values)| (key.trim(), values.trim()))
+}
+
+fn parse_values(values: &str, raw: &str) -> Result<Vec<String>> {
+    let values = values
+        .strip_prefix('(')
+        .and_then(|v| v.strip_suffix(')'))
+        .ok_or_else(|| anyhow!("invalid label selector: {raw}"))?;
+    Ok(values
+        .split(',')
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect())
+}
+
+fn valid_key(key: &str, raw: &str) -> Result<String> {
+    if key.is_empty() || key.contains(|c: char| c.is_whitespace() || "()!=,".contains(c)) {
+        return Err(anyhow!("invalid label selector: {raw}"));
+    }
+    Ok(key.to_string())
+}
+
+/// The allow and deny lists and label selectors that decide which workloads
+/// are managed.
+#[derive(Eq, PartialEq, Debug, Clone, Default)]
+pub struct ScopeRules {
+    pub namespaces: Vec<String>,
+    pub excluded_namespaces: Vec<String>,
+    pub label_selector: Option<LabelSelector>,
+    pub namespace_label_selector: Option<LabelSelector>,
+}
+
+impl ScopeRules {
+    pub fn new(settings: &Settings) -> Result<Self> {
+        let parse = |raw: &str| -> Result<Option<LabelSelector>> {
+            if raw.trim().is_empty() {
+                return Ok(None);
+            }
+            Ok(Some(LabelSelector::parse(raw)?))
+        };
+
+        Ok(Self {
+            namespaces: settings.namespaces.clone(),
+            excluded_namespaces: settings.excluded_namespaces.clone(),
+            label_selector: parse(&settings.label_selector)?,
+            namespace_label_selector: parse(&settings.namespace_label_selector)?,
+        })
+    }
+
+    /// Returns true if the namespace is allowed by the allow and deny lists.
+    pub fn namespace_allowed(&self, namespace: &str) -> bool {
+        if !self.namespaces.is_empty() && !self.namespaces.iter().any(|n| n == namespace) {
+            return false;
+        }
+        !self.excluded_namespaces.iter().any(|n| n == namespace)
+    }
+}
+
+/// Scope decides which workloads are managed, based on namespace allow and
+/// deny lists, a label selector for workloads, and a label selector for the
+/// namespaces of workloads. The rules can be replaced while running.
+pub struct Scope {
+    rules: RwLock<Arc<ScopeRules>>,
+    rules_tx: watch::Sender<Arc<ScopeRules>>,
+    client: OnceCell<Client>,
+    namespace_labels: RwLock<HashMap<String, (BTreeMap<String, String>, DateTime<Utc>)>>,
+}
+
+impl Scope {
+    pub fn new(settings: &Settings) -> Result<Self> {
+        let rules = Arc::new(ScopeRules::new(settings)?);
+        let (rules_tx, _) = watch::channel(rules.clone());
+        Ok(Self {
+            rules: RwLock::new(rules),
+            rules_tx,
+            client: OnceCell::new(),
+            namespace_labels: RwLock::new(HashMap::new()),
+        })
+    }
+
+    pub fn rules(&self) -> Arc<ScopeRules> {
+        self.rules.read().clone()
+    }
+
+    /// Replaces the rules with the rules of the settings. Returns true if the
+    /// rules changed.
+    pub fn update(&self, settings: &Settings) -> Result<bool> {
+        let rules = ScopeRules::new(settings)?;
+        if *self.rules() == rules {
+            return Ok(false);
+        }
+        let rules = Arc::new(rules);
+        *self.rules.write() = rules.clone();
+        self.rules_tx.send_replace(rules);
+        Ok(true)
+    }
+
+    /// Returns a receiver that is notified when the rules change.
+    pub fn subscribe(&self) -> watch::Receiver<Arc<ScopeRules>> {
+        self.rules_tx.subscribe()
+    }
+
+    /// Returns true if a workload with the labels in the namespace is managed.
+    /// When the labels of the namespace can't be read, the workload is not
+    /// managed.
+    pub async fn matches(&self, namespace: &str, labels: &BTreeMap<String, String>) -> bool {
+        let rules = self.rules();
+
+        if !rules.namespace_allowed(namespace) {
+            return false;
+        }
+
+        if let Some(selector) = &rules.label_selector {
+            if !selector.matches(labels) {
+                return false;
+            }
+        }
+
+        if let Some(selector) = &rules.namespace_label_selector {
+            return match self.namespace_labels(namespace).await {
+                Ok(namespace_labels) => selector.matches(&namespace_labels),
+                Err(err) => {
+                    warn!("scope error: failed to get namespace labels: {namespace}: {err}");
+                    false
+                }
+            };
+        }
+
+        true
+    }
+
+    async fn namespace_labels(&self, namespace: &str) -> Result<BTreeMap<String, String>> {
+        let now = Utc::now();
+
+        if let Some((labels, fetched)) = self.namespace_labels.read().get(namespace) {
+            if now - *fetched < Duration::seconds(NAMESPACE_LABELS_TTL) {
+                return Ok(labels.clone());
+            }
+        }
+
+        let client = self.client.get_or_try_init(Client::try_default).await?;
+        let api: Api<Namespace> = Api::all(client.clone());
+        let labels = api.get(namespace).await?.labels().clone();
+
+        self.namespace_labels
+            .write()
+            .insert(namespace.to_string(), (labels.clone(), now));
+
+        Ok(labels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn label_selector_equality() {
+        let selector =
+            LabelSelector::parse("app=web, tier!=cache,env==prod").expect("selector should parse");
+        assert!(selector.matches(&labels(&[("app", "web"), ("env", "prod")])));
+        assert!(!selector.matches(&labels(&[
+            ("app", "web"),
+            ("env", "prod"),
+            ("tier", "cache")
+        ])));
+        assert!(!selector.matches(&labels(&[("app", "api"), ("env", "prod")])));
+    }
+
+    #[test]
+    fn label_selector_sets() {
+        let selector =
+            LabelSelector::parse("env in (prod, staging),team notin (legacy),managed,!skip")
+                .expect("selector should parse");
+        assert!(selector.matches(&labels(&[("env", "staging"), ("managed", "")])));
+        assert!(!selector.matches(&labels(&[("env", "dev"), ("managed", "")])));
+        assert!(!selector.matches(&labels(&[
+            ("env", "prod"),
+            ("managed", ""),
+            ("team", "legacy")
+        ])));
+        assert!(!selector.matches(&labels(&[("env", "prod")])));
+        assert!(!selector.matches(&labels(&[
+            ("env", "prod"),
+            ("managed", ""),
+            ("skip", "true")
+        ])));
+    }
+
+    #[test]
+    fn label_selector_invalid() {
+        assert!(LabelSelector::parse("env in prod").is_err());
+        assert!(LabelSelector::parse("=prod").is_err());
+        assert!(LabelSelector::parse("")
+            .expect("selector should parse")
+            .matches(&labels(&[])));
+    }
+}
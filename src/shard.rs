@@ -0,0 +1,344 @@
+use chrono::{Duration, Utc};
+use k8s_openapi::{
+    api::coordination::v1::{Lease, LeaseSpec},
+    apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta},
+};
+use kube::{
+    api::{Api, DeleteParams, ListParams, PostParams},
+    Client,
+};
+use parking_lot::RwLock;
+use std::collections::{BTreeMap, HashSet};
+use tokio::{sync::watch, time::sleep};
+use tokio_tasker::Stopper;
+use tracing::{error, info, warn};
+
+use crate::error::Result;
+use crate::state::AppState;
+
+/// The number of points that each member has on the hash ring.
+const VIRTUAL_NODES: usize = 64;
+
+/// The label used to find the membership leases of a shard group.
+const SHARD_GROUP_LABEL: &str = "k8s-consul-mutator.io/shard-group";
+
+/// A consistent hash ring of replica identities. Namespaces are assigned to
+/// the first member point on the ring at or after the hash of the namespace.
+#[derive(Debug, Clone, Default)]
+pub struct ShardRing {
+    members: Vec<String>,
+    points: Vec<(u64, usize)>,
+}
+
+impl ShardRing {
+    pub fn new(members: &[String]) -> Self {
+        let mut members = members.to_vec();
+        members.sort();
+        members.dedup();
+
+        let mut points = Vec::with_capacity(members.len() * VIRTUAL_NODES);
+        for (index, member) in members.iter().enumerate() {
+            for node in 0..VIRTUAL_NODES {
+                points.push((fnv1a(format!("{member}-{node}").as_bytes()), index));
+            }
+        }
+        points.sort();
+
+        Self { members, points }
+    }
+
+    pub fn members(&self) -> &[String] {
+        &self.members
+    }
+
+    /// Returns the member that owns the namespace.
+    pub fn owner(&self, namespace: &str) -> Option<&str> {
+        if self.points.is_empty() {
+            return None;
+        }
+        let hash = fnv1a(namespace.as_bytes());
+        let position = self.points.partition_point(|(point, _)| *point < hash);
+        let (_, index) = self.points[position % self.points.len()];
+        Some(self.members[index].as_str())
+    }
+}
+
+/// A 64-bit FNV-1a hash. This is used instead of the standard library hasher
+/// because every replica must agree on the hash of a namespace.
+fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Tracks the namespaces that this replica is responsible for. When sharding
+/// is disabled, the replica is responsible for every namespace.
+pub struct Sharding {
+    enabled: bool,
+    identity: String,
+    ring: RwLock<ShardRing>,
+    members_tx: watch::Sender<Vec<String>>,
+}
+
+impl Sharding {
+    pub fn new(enabled: bool, identity: String) -> Self {
+        let (members_tx, _) = watch::channel(vec![]);
+        Self {
+            enabled,
+            identity,
+            ring: RwLock::new(ShardRing::default()),
+            members_tx,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Returns true if this replica is responsible for the namespace.
+    pub fn owns(&self, namespace: &str) -> bool {
+        if !self.enabled {
+            return true;
+        }
+        self.ring.read().owner(namespace) == Some(self.identity.as_str())
+    }
+
+    pub fn members(&self) -> Vec<String> {
+        self.ring.read().members().to_vec()
+    }
+
+    /// Returns a receiver that is notified when the shard membership changes.
+    pub fn subscribe(&self) -> watch::Receiver<Vec<String>> {
+        self.members_tx.subscribe()
+    }
+
+    fn set_members(&self, members: &[String]) -> bool {
+        let ring = ShardRing::new(members);
+        if ring.members() == self.ring.read().members() {
+            return false;
+        }
+        let members = ring.members().to_vec();
+        *self.ring.write() = ring;
+        self.members_tx.send_replace(members);
+        true
+    }
+}
+
+/// This is the main loop that maintains the membership lease of this replica
+/// and rebalances namespaces when the membership of the shard group changes.
+///
+/// When namespaces move to another replica, their subscriptions are removed
+/// and the key watchers that are no longer needed idle out. Namespaces that
+/// move to this replica are picked up by the deployment watcher, which starts
+/// and stops the watchers of namespaces that moved on membership changes.
+pub async fn shard_membership_loop(app_state: AppState, stopper: Stopper) -> Result<()> {
+    let settings = app_state.settings();
+    let client = Client::try_default().await.map_err(anyhow::Error::msg)?;
+    let api: Api<Lease> = Api::namespaced(client, &settings.shard_namespace);
+
+    let group = settings.shard_group.clone();
+    let identity = settings.shard_identity.clone();
+    let lease_name = format!("{group}-{identity}");
+    let lease_duration = settings.shard_lease_duration.as_secs() as i32;
+    let retry_duration = Duration::seconds((lease_duration / 3).max(1) as i64);
+
+    info!("shard membership started: {group} {identity}");
+
+    while !stopper.is_stopped() {
+        if let Err(err) =
+            renew_membership(&api, &group, &lease_name, &identity, lease_duration).await
+        {
+            error!("shard membership error: {err}");
+        }
+
+        match live_members(&api, &group).await {
+            Ok(mut members) => {
+                // This replica is always a member of its own view of the group.
+                if !members.contains(&identity) {
+                    members.push(identity.clone());
+                }
+                if app_state.sharding.set_members(&members) {
+                    info!(
+                        "shard membership changed: {:?}",
+                        app_state.sharding.members()
+                    );
+                    if let Err(err) = rebalance(&app_state).await {
+                        error!("shard membership error: failed to rebalance: {err}");
+                    }
+                }
+            }
+            Err(err) => error!("shard membership error: {err}"),
+        }
+
+        tokio::select! {
+            _ = sleep(retry_duration.to_std().unwrap()) => {},
+            _ = stopper.clone() => {},
+        }
+    }
+
+    // Removing the membership lease lets the other replicas take over the
+    // namespaces of this replica immediately.
+    if let Err(err) = api.delete(&lease_name, &DeleteParams::default()).await {
+        warn!("shard membership error: failed to delete lease: {err}");
+    }
+
+    info!("shard membership stopped");
+
+    Ok(())
+}
+
+async fn renew_membership(
+    api: &Api<Lease>,
+    group: &str,
+    lease_name: &str,
+    identity: &str,
+    lease_duration: i32,
+) -> Result<()> {
+    let now = Utc::now();
+    let spec = LeaseSpec {
+        holder_identity: Some(identity.to_string()),
+        lease_duration_seconds: Some(lease_duration),
+        renew_time: Some(MicroTime(now)),
+        ..Default::default()
+    };
+
+    match api.get_opt(lease_name).await? {
+        Some(lease) => {
+            let mut spec = LeaseSpec {
+                acquire_time: lease.spec.as_ref().and_then(|s| s.acquire_time.clone()),
+                ..spec
+            };
+            if spec.acquire_time.is_none() {
+                spec.acquire_time = Some(MicroTime(now));
+            }
+            api.replace(
+                lease_name,
+                &PostParams::default(),
+                &Lease {
+                    metadata: lease.metadata.clone(),
+                    spec: Some(spec),
+                },
+            )
+            .await?;
+        }
+        None => {
+            api.create(
+                &PostParams::default(),
+                &Lease {
+                    metadata: ObjectMeta {
+                        name: Some(lease_name.to_string()),
+                        labels: Some(BTreeMap::from([(
+                            SHARD_GROUP_LABEL.to_string(),
+                            group.to_string(),
+                        )])),
+                        ..Default::default()
+                    },
+                    spec: Some(LeaseSpec {
+                        acquire_time: Some(MicroTime(now)),
+                        ..spec
+                    }),
+                },
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the identities of the members of the group that have renewed their
+/// lease within its duration.
+async fn live_members(api: &Api<Lease>, group: &str) -> Result<Vec<String>> {
+    let now = Utc::now();
+    let leases = api
+        .list(&ListParams::default().labels(&format!("{SHARD_GROUP_LABEL}={group}")))
+        .await?;
+
+    let mut members = vec![];
+    for lease in leases {
+        let spec = match lease.spec {
+            Some(spec) => spec,
+            None => continue,
+        };
+        if let (Some(holder), Some(MicroTime(renew_time)), Some(duration)) = (
+            spec.holder_identity,
+            spec.renew_time,
+            spec.lease_duration_seconds,
+        ) {
+            if renew_time + Duration::seconds(duration as i64) > now {
+                members.push(holder);
+            }
+        }
+    }
+
+    Ok(members)
+}
+
+/// Removes the subscriptions of namespaces that this replica no longer owns.
+async fn rebalance(app_state: &AppState) -> Result<()> {
+    let namespaces: HashSet<String> = app_state
+        .key_manager
+        .subscriptions()
+        .await?
+        .into_iter()
+        .map(|(subscription, _)| subscription.namespace)
+        .collect();
+
+    for namespace in namespaces {
+        if !app_state.sharding.owns(&namespace) {
+            let removed = app_state
+                .key_manager
+                .unwatch_namespace(namespace.clone())
+                .await?;
+            info!("shard membership released namespace: {namespace} {removed}");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn shard_ring_is_deterministic() {
+        let first = ShardRing::new(&members(&["a", "b", "c"]));
+        let second = ShardRing::new(&members(&["c", "a", "b"]));
+
+        for namespace in ["default", "demo", "kube-system", "team-a", "team-b"] {
+            assert_eq!(first.owner(namespace), second.owner(namespace));
+        }
+        assert_eq!(ShardRing::default().owner("default"), None);
+    }
+
+    #[test]
+    fn shard_ring_moves_few_namespaces() {
+        let namespaces: Vec<String> = (0..1000).map(|i| format!("namespace-{i}")).collect();
+
+        let before = ShardRing::new(&members(&["a", "b", "c"]));
+        let after = ShardRing::new(&members(&["a", "b", "c", "d"]));
+
+        let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+        let mut moved = 0;
+        for namespace in namespaces.iter() {
+            let owner = after.owner(namespace).expect("owner should exist");
+            *counts.entry(owner).or_default() += 1;
+            if before.owner(namespace) != Some(owner) {
+                // Namespaces only move to the new member.
+                assert_eq!(owner, "d");
+                moved += 1;
+            }
+        }
+
+        assert!(moved > 0 && moved < 500, "moved {moved}");
+        assert_eq!(counts.len(), 4);
+    }
+}
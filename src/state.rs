@@ -1,11 +1,27 @@
+use std::collections::{BTreeMap, HashSet};
 use std::ops::Deref;
 use std::sync::Arc;
 
-use crate::{checksum::Checksummer, config::Settings, key_manager::KeyManager};
+use crate::{
+    auth::Authenticator,
+    checksum::Checksummer,
+    config::{Settings, RELOADABLE_SETTINGS},
+    consul::ConsulClients,
+    error::Result,
+    events::Events,
+    key_manager::KeyManager,
+    leader::Leadership,
+    scope::Scope,
+    shard::Sharding,
+    telemetry::SpanLinks,
+};
 use chrono::{DateTime, Utc};
-use consulrs::client::ConsulClientSettings;
-use tokio::sync::mpsc::Sender;
+use k8s_openapi::api::apps::v1::Deployment;
+use kube::{Api, Client, ResourceExt};
+use parking_lot::{Mutex, RwLock};
+use tokio::sync::{mpsc::Sender, OnceCell};
 use tokio_tasker::Tasker;
+use tracing::{info, warn};
 
 /// A subscription is a namespaced resource for a key.
 #[derive(Hash, Eq, PartialEq, Debug, Clone)]
@@ -13,6 +29,9 @@ pub struct DeploymentUpdate {
     pub namespace: String,
     pub deployment: String,
     pub occurred: DateTime<Utc>,
+    /// Only the status annotation is written, without changing checksums or
+    /// timestamps.
+    pub status_only: bool,
 }
 
 #[derive(Hash, Eq, PartialEq, Debug, Clone)]
@@ -21,39 +40,185 @@ pub enum ConsulWatch {
     Destroy(String, DateTime<Utc>),
 }
 
+/// A snapshot of the work being done by the background loops, published for
+/// introspection.
+#[derive(Default)]
+pub struct WorkStatus {
+    running_watchers: Mutex<HashSet<String>>,
+    pending_watches: Mutex<Vec<ConsulWatch>>,
+    pending_updates: Mutex<Vec<DeploymentUpdate>>,
+}
+
+impl WorkStatus {
+    pub fn set_watches(&self, running: &HashSet<String>, pending: &HashSet<ConsulWatch>) {
+        *self.running_watchers.lock() = running.clone();
+        *self.pending_watches.lock() = pending.iter().cloned().collect();
+    }
+
+    pub fn set_updates(&self, pending: &HashSet<DeploymentUpdate>) {
+        *self.pending_updates.lock() = pending.iter().cloned().collect();
+    }
+
+    pub fn running_watchers(&self) -> HashSet<String> {
+        self.running_watchers.lock().clone()
+    }
+
+    pub fn pending_watches(&self) -> Vec<ConsulWatch> {
+        self.pending_watches.lock().clone()
+    }
+
+    pub fn pending_updates(&self) -> Vec<DeploymentUpdate> {
+        self.pending_updates.lock().clone()
+    }
+}
+
 #[derive(Clone)]
 pub struct AppState(pub Arc<InnerState>);
 
 pub struct InnerState {
-    pub settings: Settings,
+    settings: RwLock<Arc<Settings>>,
     pub key_manager: Box<dyn KeyManager>,
-    pub consul_settings: ConsulClientSettings,
+    pub consul: ConsulClients,
     pub tasker: Tasker,
     pub deployment_update_tx: Sender<DeploymentUpdate>,
     pub consul_manager_tx: Sender<ConsulWatch>,
     pub checksummer: Box<dyn Checksummer>,
+    pub authenticator: Box<dyn Authenticator>,
+    pub work_status: WorkStatus,
+    pub leadership: Leadership,
+    pub sharding: Sharding,
+    pub scope: Scope,
+    pub events: Events,
+    pub span_links: SpanLinks,
+    kube: OnceCell<Client>,
 }
 
 impl InnerState {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         settings: Settings,
         key_manager: Box<dyn KeyManager>,
-        consul_settings: ConsulClientSettings,
+        consul: ConsulClients,
         tasker: Tasker,
         deployment_update_tx: Sender<DeploymentUpdate>,
         consul_manager_tx: Sender<ConsulWatch>,
         checksummer: Box<dyn Checksummer>,
+        authenticator: Box<dyn Authenticator>,
+        scope: Scope,
     ) -> Self {
+        let leadership = Leadership::new(!settings.leader_election);
+        let sharding = Sharding::new(settings.sharding, settings.shard_identity.clone());
+        let events = Events::new(&settings.leader_election_identity);
         Self {
-            settings,
+            settings: RwLock::new(Arc::new(settings)),
             key_manager,
-            consul_settings,
+            consul,
             tasker,
             deployment_update_tx,
             consul_manager_tx,
             checksummer,
+            authenticator,
+            work_status: WorkStatus::default(),
+            leadership,
+            sharding,
+            scope,
+            events,
+            span_links: SpanLinks::default(),
+            kube: OnceCell::new(),
         }
     }
+
+    /// Returns the kubernetes client that is shared by request handlers. It is
+    /// created on first use.
+    pub async fn kube_client(&self) -> Result<Client> {
+        Ok(self
+            .kube
+            .get_or_try_init(Client::try_default)
+            .await?
+            .clone())
+    }
+
+    /// Returns the current settings. Loops that support reloading read the
+    /// settings on each iteration.
+    pub fn settings(&self) -> Arc<Settings> {
+        self.settings.read().clone()
+    }
+
+    /// Applies the reloadable settings of `loaded`. Returns the names of the
+    /// settings that were applied and the names of the settings that changed
+    /// but require a restart.
+    pub async fn reload_settings(&self, loaded: &Settings) -> Result<(Vec<String>, Vec<String>)> {
+        let current = self.settings();
+        let updated = current.with_reloadable(loaded);
+
+        let scope_changed = self.scope.update(&updated)?;
+        *self.settings.write() = Arc::new(updated);
+
+        if scope_changed {
+            self.release_out_of_scope().await?;
+        }
+
+        Ok(current
+            .changed(loaded)
+            .into_iter()
+            .partition(|name| RELOADABLE_SETTINGS.contains(&name.as_str())))
+    }
+
+    /// Removes the subscriptions of deployments that the scope no longer
+    /// matches, as shard rebalancing does for namespaces that moved away. The
+    /// key watchers that are no longer needed idle out. Returns the number of
+    /// removed subscriptions.
+    async fn release_out_of_scope(&self) -> Result<usize> {
+        let deployments: HashSet<(String, String)> = self
+            .key_manager
+            .subscriptions()
+            .await?
+            .into_iter()
+            .map(|(subscription, _)| (subscription.namespace, subscription.deployment))
+            .collect();
+
+        let mut removed = 0;
+        for (namespace, deployment) in deployments {
+            let in_scope = match self.deployment_labels(&namespace, &deployment).await {
+                Ok(Some(labels)) => self.scope.matches(&namespace, &labels).await,
+                Ok(None) => false,
+                Err(err) => {
+                    warn!(
+                        "settings reloader error: failed to get deployment labels: {namespace}/{deployment}: {err}"
+                    );
+                    continue;
+                }
+            };
+            if in_scope {
+                continue;
+            }
+            let count = self
+                .key_manager
+                .unwatch_deployment(namespace.clone(), deployment.clone())
+                .await?;
+            info!("settings reloader released deployment: {namespace}/{deployment} {count}");
+            removed += count;
+        }
+
+        Ok(removed)
+    }
+
+    /// Returns the labels of a deployment, or `None` if it doesn't exist. The
+    /// labels are only read when the scope has a label selector.
+    async fn deployment_labels(
+        &self,
+        namespace: &str,
+        deployment: &str,
+    ) -> Result<Option<BTreeMap<String, String>>> {
+        if self.scope.rules().label_selector.is_none() {
+            return Ok(Some(BTreeMap::new()));
+        }
+        let api: Api<Deployment> = Api::namespaced(self.kube_client().await?, namespace);
+        Ok(api
+            .get_opt(deployment)
+            .await?
+            .map(|deployment| deployment.labels().clone()))
+    }
 }
 
 impl Deref for AppState {
@@ -63,3 +228,88 @@ impl Deref for AppState {
         &self.0
     }
 }
+
+/// Creates the state of a test, with the receivers of its deployment update
+/// and consul watch channels. When a kubernetes client is given, it is used
+/// instead of the default client.
+#[cfg(test)]
+pub async fn test_state(
+    settings: Settings,
+    kube: Option<Client>,
+) -> (
+    AppState,
+    tokio::sync::mpsc::Receiver<DeploymentUpdate>,
+    tokio::sync::mpsc::Receiver<ConsulWatch>,
+) {
+    let (updater_tx, updater_rx) = tokio::sync::mpsc::channel(100);
+    let (watch_tx, watch_rx) = tokio::sync::mpsc::channel(100);
+
+    let inner = InnerState::new(
+        settings.clone(),
+        crate::key_manager::get_key_manager(&settings.key_manager_type),
+        ConsulClients::new(&settings).expect("consul clients should be created"),
+        Tasker::new(),
+        updater_tx,
+        watch_tx,
+        crate::checksum::get_checksummer(&settings.checksum_type),
+        crate::auth::get_authenticator(&settings.api_auth, &settings.admin_token)
+            .await
+            .expect("authenticator should be created"),
+        Scope::new(&settings).expect("scope should be created"),
+    );
+    if let Some(kube) = kube {
+        inner
+            .kube
+            .set(kube)
+            .expect("kubernetes client should not be set");
+    }
+
+    (AppState(Arc::new(inner)), updater_rx, watch_rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SettingsBuilder;
+
+    #[tokio::test]
+    async fn reload_releases_deployments_out_of_scope() {
+        let settings = SettingsBuilder::default()
+            .api_auth("none")
+            .build()
+            .expect("settings should build");
+        let (state, _updates, _watches) = test_state(settings.clone(), None).await;
+        for (namespace, deployment) in [("team-a", "app"), ("team-b", "app"), ("team-b", "api")] {
+            state
+                .key_manager
+                .watch(
+                    namespace.to_string(),
+                    deployment.to_string(),
+                    "config".to_string(),
+                    format!("{namespace}/{deployment}/config"),
+                )
+                .await
+                .expect("watch should succeed");
+        }
+
+        let loaded = SettingsBuilder::default()
+            .api_auth("none")
+            .excluded_namespaces(vec!["team-b".to_string()])
+            .build()
+            .expect("settings should build");
+        let (applied, ignored) = state
+            .reload_settings(&loaded)
+            .await
+            .expect("reload should succeed");
+        assert_eq!(applied, vec!["excluded_namespaces".to_string()]);
+        assert!(ignored.is_empty());
+
+        let subscriptions = state
+            .key_manager
+            .subscriptions()
+            .await
+            .expect("subscriptions should succeed");
+        assert_eq!(subscriptions.len(), 1);
+        assert_eq!(subscriptions[0].0.namespace, "team-a");
+    }
+}
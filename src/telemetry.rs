@@ -0,0 +1,255 @@
+use opentelemetry::{
+    sdk::{trace, Resource},
+    trace::{SpanContext, TraceContextExt},
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use tracing::{Span, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{
+    fmt::MakeWriter, layer::SubscriberExt, registry::LookupSpan, util::SubscriberInitExt,
+    EnvFilter, Layer,
+};
+
+use crate::config::Settings;
+use crate::error::Result;
+
+/// Sets up logging in the configured format and, when an OTLP endpoint is
+/// configured, the export of spans to it.
+pub fn init(settings: &Settings) -> Result<()> {
+    let filter = EnvFilter::new(
+        std::env::var("RUST_LOG")
+            .unwrap_or_else(|_| "k8s_consul_mutator_rs=debug,tower_http=debug".into()),
+    );
+
+    let (json, text) = if settings.log_format == "json" {
+        (Some(json_layer(std::io::stdout)), None)
+    } else {
+        (None, Some(tracing_subscriber::fmt::layer()))
+    };
+
+    let otlp = if settings.otel_exporter_otlp_endpoint.is_empty() {
+        None
+    } else {
+        Some(tracing_opentelemetry::layer().with_tracer(otlp_tracer(settings)?))
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(json)
+        .with(text)
+        .with(otlp)
+        .try_init()?;
+
+    Ok(())
+}
+
+/// Returns a tracer that exports spans in batches to the OTLP endpoint with
+/// gRPC.
+fn otlp_tracer(settings: &Settings) -> Result<trace::Tracer> {
+    Ok(opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(settings.otel_exporter_otlp_endpoint.clone()),
+        )
+        .with_trace_config(trace::config().with_resource(Resource::new(vec![
+            KeyValue::new("service.name", settings.otel_service_name.clone()),
+            KeyValue::new("service.version", settings.version.trim().to_string()),
+        ])))
+        .install_batch(opentelemetry::runtime::Tokio)?)
+}
+
+/// Returns the layer that writes logs as JSON lines. The fields of events,
+/// such as `consul_key` and `deployment`, are top level properties of a line.
+pub fn json_layer<S, W>(writer: W) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt::layer()
+        .json()
+        .flatten_event(true)
+        .with_current_span(true)
+        .with_span_list(false)
+        .with_writer(writer)
+}
+
+/// Flushes the spans that have not been exported yet.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Spans that later work should be linked to. Work that is caused by a span,
+/// like the consul read for a key that an admission request subscribed to or
+/// the deployment patch for a checksum that a consul read changed, happens
+/// later in another task. The span of that work is linked to the stored span.
+///
+/// Only the OpenTelemetry context of a span is stored, so storing it doesn't
+/// keep the span open. Nothing is stored when spans aren't exported.
+#[derive(Default)]
+pub struct SpanLinks {
+    contexts: Mutex<HashMap<String, SpanContext>>,
+}
+
+impl SpanLinks {
+    /// Stores the span as the cause of the next work on the key.
+    pub fn record(&self, key: String, span: &Span) {
+        let context = span.context().span().span_context().clone();
+        if !context.is_valid() {
+            return;
+        }
+        self.contexts.lock().insert(key, context);
+    }
+
+    /// Links the span to the span that was stored for the key, if any.
+    pub fn follow(&self, span: &Span, key: &str) {
+        if let Some(cause) = self.contexts.lock().remove(key) {
+            span.add_link(cause);
+        }
+    }
+}
+
+pub fn consul_key_link(consul_key: &str) -> String {
+    format!("consul/{consul_key}")
+}
+
+pub fn deployment_link(namespace: &str, deployment: &str) -> String {
+    format!("deployment/{namespace}/{deployment}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SettingsBuilder;
+    use opentelemetry_proto::tonic::{
+        collector::trace::v1::{
+            trace_service_server::{TraceService, TraceServiceServer},
+            ExportTraceServiceRequest, ExportTraceServiceResponse,
+        },
+        trace::v1::Span as ExportedSpan,
+    };
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tracing::info_span;
+
+    /// A stand-in for an OTLP collector that keeps the exported spans.
+    #[derive(Clone, Default)]
+    struct Collector {
+        spans: Arc<Mutex<Vec<ExportedSpan>>>,
+    }
+
+    #[tonic::async_trait]
+    impl TraceService for Collector {
+        async fn export(
+            &self,
+            request: tonic::Request<ExportTraceServiceRequest>,
+        ) -> std::result::Result<tonic::Response<ExportTraceServiceResponse>, tonic::Status>
+        {
+            let mut spans = self.spans.lock();
+            for resource_spans in request.into_inner().resource_spans {
+                for scope_spans in resource_spans.scope_spans {
+                    spans.extend(scope_spans.spans);
+                }
+            }
+            Ok(tonic::Response::new(ExportTraceServiceResponse::default()))
+        }
+    }
+
+    /// Starts the collector stand-in on a local port and returns its
+    /// endpoint.
+    async fn collector_stand_in(collector: Collector) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind should succeed");
+        let addr = listener.local_addr().expect("local addr should exist");
+        let incoming = futures::stream::unfold(listener, |listener| async move {
+            let accepted = listener.accept().await.map(|(stream, _)| stream);
+            Some((accepted, listener))
+        });
+        tokio::spawn(async move {
+            tonic::transport::Server::builder()
+                .add_service(TraceServiceServer::new(collector))
+                .serve_with_incoming(incoming)
+                .await
+                .expect("collector should run");
+        });
+        format!("http://{addr}")
+    }
+
+    fn exported<'a>(spans: &'a [ExportedSpan], name: &str) -> &'a ExportedSpan {
+        spans
+            .iter()
+            .find(|span| span.name == name)
+            .unwrap_or_else(|| panic!("the {name} span should be exported"))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exported_spans_are_linked() {
+        let collector = Collector::default();
+        let settings = SettingsBuilder::default()
+            .otel_exporter_otlp_endpoint(collector_stand_in(collector.clone()).await)
+            .build()
+            .expect("settings should build");
+        let tracer = otlp_tracer(&settings).expect("pipeline should install");
+        let provider = tracer.provider().expect("tracer should have a provider");
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+        let links = SpanLinks::default();
+        tracing::subscriber::with_default(subscriber, || {
+            // The admission request subscribes a deployment to a key.
+            let admission = info_span!("admission");
+            links.record(consul_key_link("app/config"), &admission);
+            drop(admission);
+
+            // The first read of the key follows from the admission request,
+            // and the deployment update follows from the read that changed
+            // the checksum.
+            let read = info_span!("consul_read");
+            links.follow(&read, &consul_key_link("app/config"));
+            links.record(deployment_link("demo", "app"), &read);
+            drop(read);
+
+            let update = info_span!("update_deployment");
+            links.follow(&update, &deployment_link("demo", "app"));
+            drop(update);
+
+            // Links are only followed once.
+            let unlinked = info_span!("consul_read_again");
+            links.follow(&unlinked, &consul_key_link("app/config"));
+        });
+
+        // Flushing waits for the batch exporter, so it can't block the
+        // runtime.
+        tokio::task::spawn_blocking(move || provider.force_flush())
+            .await
+            .expect("flush should finish");
+
+        let spans = collector.spans.lock().clone();
+        let admission = exported(&spans, "admission");
+        let read = exported(&spans, "consul_read");
+        let update = exported(&spans, "update_deployment");
+
+        assert_eq!(read.links.len(), 1);
+        assert_eq!(read.links[0].trace_id, admission.trace_id);
+        assert_eq!(read.links[0].span_id, admission.span_id);
+        assert_eq!(update.links.len(), 1);
+        assert_eq!(update.links[0].trace_id, read.trace_id);
+        assert_eq!(update.links[0].span_id, read.span_id);
+        assert!(exported(&spans, "consul_read_again").links.is_empty());
+    }
+
+    #[test]
+    fn span_links_without_exporter() {
+        // Without an OpenTelemetry layer, spans have no valid context and
+        // nothing is stored.
+        let links = SpanLinks::default();
+        let span = info_span!("admission");
+        links.record(consul_key_link("app/config"), &span);
+        assert!(links.contexts.lock().is_empty());
+    }
+}
@@ -0,0 +1,520 @@
+use anyhow::anyhow;
+use axum_server::tls_rustls::RustlsConfig;
+use chrono::{DateTime, Duration, Utc};
+use k8s_openapi::{
+    api::{
+        admissionregistration::v1::{
+            MutatingWebhook, MutatingWebhookConfiguration, RuleWithOperations, ServiceReference,
+            WebhookClientConfig,
+        },
+        core::v1::Secret,
+    },
+    apimachinery::pkg::apis::meta::v1::{LabelSelector, LabelSelectorRequirement, ObjectMeta},
+    ByteString,
+};
+use kube::{
+    api::{Api, Patch, PatchParams, PostParams},
+    Client,
+};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyUsagePurpose,
+};
+use std::collections::BTreeMap;
+use time::OffsetDateTime;
+use tokio::time::sleep;
+use tokio_tasker::Stopper;
+use tracing::{error, info};
+
+use crate::config::Settings;
+use crate::error::Result;
+use crate::scope::ScopeRules;
+use crate::state::AppState;
+
+const NOT_AFTER_ANNOTATION: &str = "k8s-consul-mutator.io/not-after";
+const DNS_NAMES_ANNOTATION: &str = "k8s-consul-mutator.io/dns-names";
+
+/// Certificates are valid from slightly before they are created to allow for
+/// clock skew between the process and the API server.
+const CLOCK_SKEW: i64 = 5 * 60;
+
+/// The longest time to wait between checking the webhook certificate.
+const MAX_CHECK_INTERVAL: i64 = 60 * 60;
+
+/// A serving certificate and the CA bundle that webhook clients use to
+/// verify it.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct WebhookCertificate {
+    pub ca_bundle: String,
+    pub ca: String,
+    pub certificate: String,
+    pub key: String,
+    pub not_after: DateTime<Utc>,
+    pub dns_names: Vec<String>,
+}
+
+impl WebhookCertificate {
+    /// Generates a new CA and a serving certificate signed by it. When the
+    /// CA of the previous certificate is given, it is kept in the CA bundle
+    /// so that replicas still serving the previous certificate are trusted
+    /// until they reload.
+    fn generate(
+        dns_names: Vec<String>,
+        validity: Duration,
+        now: DateTime<Utc>,
+        previous_ca: Option<String>,
+    ) -> Result<Self> {
+        let not_before = to_offset_date_time(now - Duration::seconds(CLOCK_SKEW))?;
+        let not_after = now + validity;
+
+        let mut ca_params = CertificateParams::default();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params
+            .distinguished_name
+            .push(DnType::CommonName, "k8s-consul-mutator-rs-ca");
+        ca_params.key_usages = vec![
+            KeyUsagePurpose::KeyCertSign,
+            KeyUsagePurpose::CrlSign,
+            KeyUsagePurpose::DigitalSignature,
+        ];
+        ca_params.not_before = not_before;
+        ca_params.not_after = to_offset_date_time(not_after)?;
+        let ca = Certificate::from_params(ca_params)?;
+
+        let mut params = CertificateParams::new(dns_names.clone());
+        params
+            .distinguished_name
+            .push(DnType::CommonName, dns_names[0].clone());
+        params.key_usages = vec![
+            KeyUsagePurpose::DigitalSignature,
+            KeyUsagePurpose::KeyEncipherment,
+        ];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        params.not_before = not_before;
+        params.not_after = to_offset_date_time(not_after)?;
+        let certificate = Certificate::from_params(params)?;
+
+        let ca_pem = ca.serialize_pem()?;
+        let ca_bundle = match previous_ca {
+            Some(previous_ca) => format!("{ca_pem}{previous_ca}"),
+            None => ca_pem.clone(),
+        };
+
+        Ok(Self {
+            ca_bundle,
+            ca: ca_pem,
+            certificate: certificate.serialize_pem_with_signer(&ca)?,
+            key: certificate.serialize_private_key_pem(),
+            not_after,
+            dns_names,
+        })
+    }
+
+    fn from_secret(secret: &Secret) -> Option<Self> {
+        let data = secret.data.as_ref()?;
+        let get = |key: &str| -> Option<String> {
+            data.get(key)
+                .and_then(|value| String::from_utf8(value.0.clone()).ok())
+        };
+        let annotations = secret.metadata.annotations.as_ref()?;
+
+        Some(Self {
+            ca_bundle: get("ca-bundle.crt")?,
+            ca: get("ca.crt")?,
+            certificate: get("tls.crt")?,
+            key: get("tls.key")?,
+            not_after: DateTime::parse_from_rfc3339(annotations.get(NOT_AFTER_ANNOTATION)?)
+                .ok()?
+                .with_timezone(&Utc),
+            dns_names: annotations
+                .get(DNS_NAMES_ANNOTATION)?
+                .split(',')
+                .map(|name| name.to_string())
+                .collect(),
+        })
+    }
+
+    fn to_secret(&self, metadata: ObjectMeta) -> Secret {
+        let data = [
+            ("ca-bundle.crt", &self.ca_bundle),
+            ("ca.crt", &self.ca),
+            ("tls.crt", &self.certificate),
+            ("tls.key", &self.key),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), ByteString(value.clone().into_bytes())))
+        .collect();
+
+        let mut annotations = metadata.annotations.clone().unwrap_or_default();
+        annotations.insert(
+            NOT_AFTER_ANNOTATION.to_string(),
+            self.not_after.to_rfc3339(),
+        );
+        annotations.insert(DNS_NAMES_ANNOTATION.to_string(), self.dns_names.join(","));
+
+        Secret {
+            metadata: ObjectMeta {
+                annotations: Some(annotations),
+                ..metadata
+            },
+            data: Some(data),
+            type_: Some("kubernetes.io/tls".to_string()),
+            ..Default::default()
+        }
+    }
+}
+
+fn to_offset_date_time(value: DateTime<Utc>) -> Result<OffsetDateTime> {
+    OffsetDateTime::from_unix_timestamp(value.timestamp()).map_err(anyhow::Error::msg)
+}
+
+/// Returns the names that the webhook service can be reached by.
+pub fn dns_names(settings: &Settings) -> Vec<String> {
+    let service = &settings.webhook_service_name;
+    let namespace = &settings.webhook_service_namespace;
+    vec![
+        format!("{service}.{namespace}.svc"),
+        format!("{service}.{namespace}.svc.cluster.local"),
+        format!("{service}.{namespace}"),
+        service.to_string(),
+    ]
+}
+
+/// Returns the certificate stored in the webhook secret, creating or rotating
+/// it when it is missing, expiring, or for different names. When several
+/// replicas race to rotate the certificate, the first write wins and the
+/// other replicas use the stored certificate.
+async fn ensure_certificate(api: &Api<Secret>, settings: &Settings) -> Result<WebhookCertificate> {
+    let now = Utc::now();
+    let dns_names = dns_names(settings);
+    let renew_before = Duration::from_std(settings.webhook_certificate_renew_before)?;
+
+    let existing = api.get_opt(&settings.webhook_secret_name).await?;
+    let stored = existing.as_ref().and_then(WebhookCertificate::from_secret);
+
+    if let Some(stored) = &stored {
+        if stored.dns_names == dns_names && stored.not_after - renew_before > now {
+            return Ok(stored.clone());
+        }
+    }
+
+    let previous_ca = stored
+        .filter(|stored| stored.not_after > now)
+        .map(|stored| stored.ca);
+    let generated = WebhookCertificate::generate(
+        dns_names,
+        Duration::from_std(settings.webhook_certificate_validity)?,
+        now,
+        previous_ca,
+    )?;
+
+    let result = match existing {
+        Some(secret) => {
+            let secret = generated.to_secret(secret.metadata);
+            api.replace(
+                &settings.webhook_secret_name,
+                &PostParams::default(),
+                &secret,
+            )
+            .await
+        }
+        None => {
+            let secret = generated.to_secret(ObjectMeta {
+                name: Some(settings.webhook_secret_name.clone()),
+                labels: Some(BTreeMap::from([(
+                    "app".to_string(),
+                    "k8s-consul-mutator-rs".to_string(),
+                )])),
+                ..Default::default()
+            });
+            api.create(&PostParams::default(), &secret).await
+        }
+    };
+
+    match result {
+        Ok(_) => {
+            info!(
+                "webhook certificate created: {} {}",
+                settings.webhook_secret_name, generated.not_after
+            );
+            Ok(generated)
+        }
+        Err(kube::Error::Api(err)) if err.code == 409 => {
+            let secret = api.get(&settings.webhook_secret_name).await?;
+            WebhookCertificate::from_secret(&secret).ok_or_else(|| {
+                anyhow!(
+                    "webhook secret is invalid: {}",
+                    settings.webhook_secret_name
+                )
+            })
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Builds the webhook configuration. The namespace and object selectors are
+/// derived from the scope so that the API server only sends admission
+/// requests for managed workloads.
+pub fn webhook_configuration(
+    settings: &Settings,
+    ca_bundle: Option<&str>,
+) -> Result<MutatingWebhookConfiguration> {
+    let rules = ScopeRules::new(settings)?;
+
+    let mut namespace_requirements = vec![];
+    if !rules.namespaces.is_empty() {
+        namespace_requirements.push(LabelSelectorRequirement {
+            key: "kubernetes.io/metadata.name".to_string(),
+            operator: "In".to_string(),
+            values: Some(rules.namespaces.clone()),
+        });
+    }
+    if !rules.excluded_namespaces.is_empty() {
+        namespace_requirements.push(LabelSelectorRequirement {
+            key: "kubernetes.io/metadata.name".to_string(),
+            operator: "NotIn".to_string(),
+            values: Some(rules.excluded_namespaces.clone()),
+        });
+    }
+    if let Some(selector) = &rules.namespace_label_selector {
+        namespace_requirements.extend(selector.to_requirements());
+    }
+
+    let object_requirements = rules
+        .label_selector
+        .as_ref()
+        .map(|selector| selector.to_requirements())
+        .unwrap_or_default();
+
+    let failure_policy = match settings.webhook_failure_policy.as_str() {
+        "fail" => "Fail",
+        _ => "Ignore",
+    };
+
+    Ok(MutatingWebhookConfiguration {
+        metadata: ObjectMeta {
+            name: Some(settings.webhook_name.clone()),
+            labels: Some(BTreeMap::from([(
+                "app".to_string(),
+                "k8s-consul-mutator-rs".to_string(),
+            )])),
+            ..Default::default()
+        },
+        webhooks: Some(vec![MutatingWebhook {
+            name: dns_names(settings)[0].clone(),
+            admission_review_versions: vec!["v1".to_string()],
+            side_effects: "None".to_string(),
+            failure_policy: Some(failure_policy.to_string()),
+            client_config: WebhookClientConfig {
+                ca_bundle: ca_bundle.map(|ca_bundle| ByteString(ca_bundle.as_bytes().to_vec())),
+                service: Some(ServiceReference {
+                    name: settings.webhook_service_name.clone(),
+                    namespace: settings.webhook_service_namespace.clone(),
+                    path: Some("/mutate".to_string()),
+                    port: Some(443),
+                }),
+                url: None,
+            },
+            rules: Some(vec![RuleWithOperations {
+                api_groups: Some(vec!["apps".to_string()]),
+                api_versions: Some(vec!["v1".to_string()]),
+                operations: Some(vec!["CREATE".to_string()]),
+                resources: Some(vec!["deployments".to_string()]),
+                scope: Some("Namespaced".to_string()),
+            }]),
+            namespace_selector: Some(LabelSelector {
+                match_expressions: Some(namespace_requirements),
+                match_labels: None,
+            }),
+            object_selector: Some(LabelSelector {
+                match_expressions: Some(object_requirements),
+                match_labels: None,
+            }),
+            ..Default::default()
+        }]),
+    })
+}
+
+/// Creates or updates the webhook configuration with server-side apply.
+async fn register_webhook(client: Client, settings: &Settings, ca_bundle: &str) -> Result<()> {
+    let api: Api<MutatingWebhookConfiguration> = Api::all(client);
+    let configuration = webhook_configuration(settings, Some(ca_bundle))?;
+    api.patch(
+        &settings.webhook_name,
+        &PatchParams::apply("k8s-consul-mutator").force(),
+        &Patch::Apply(&configuration),
+    )
+    .await?;
+    info!(
+        "webhook configuration registered: {}",
+        settings.webhook_name
+    );
+    Ok(())
+}
+
+/// Ensures that the webhook certificate exists and that the webhook
+/// configuration trusts it. This is called at start, before the secure
+/// listener is started.
+pub async fn provision(settings: &Settings) -> Result<WebhookCertificate> {
+    let client = Client::try_default().await.map_err(anyhow::Error::msg)?;
+    let api: Api<Secret> = Api::namespaced(client.clone(), &settings.webhook_service_namespace);
+
+    let certificate = ensure_certificate(&api, settings).await?;
+    register_webhook(client, settings, &certificate.ca_bundle).await?;
+
+    Ok(certificate)
+}
+
+/// This is the main loop that rotates the webhook certificate before it
+/// expires. Certificates rotated by other replicas are picked up from the
+/// secret, and the serving certificate is reloaded without a restart. The
+/// webhook configuration is also registered again when the scope changes.
+pub async fn webhook_certificate_loop(
+    app_state: AppState,
+    tls_config: RustlsConfig,
+    mut current: WebhookCertificate,
+    stopper: Stopper,
+) {
+    let settings = app_state.settings();
+    let client = match Client::try_default().await {
+        Ok(client) => client,
+        Err(err) => {
+            error!("webhook certificate error: {err}");
+            return;
+        }
+    };
+    let api: Api<Secret> = Api::namespaced(client.clone(), &settings.webhook_service_namespace);
+
+    let check_interval = (Duration::from_std(settings.webhook_certificate_renew_before).unwrap()
+        / 4)
+    .min(Duration::seconds(MAX_CHECK_INTERVAL));
+
+    let mut rules_rx = app_state.scope.subscribe();
+
+    info!("webhook certificate rotation started");
+
+    while !stopper.is_stopped() {
+        tokio::select! {
+            _ = sleep(check_interval.to_std().unwrap()) => {},
+            // The selectors of the webhook configuration follow the scope, so
+            // the configuration is registered again when the scope is reloaded.
+            _ = rules_rx.changed() => {
+                if let Err(err) =
+                    register_webhook(client.clone(), &app_state.settings(), &current.ca_bundle).await
+                {
+                    error!("webhook certificate error: {err}");
+                }
+                continue;
+            },
+            _ = stopper.clone() => break,
+        }
+
+        let certificate = match ensure_certificate(&api, &settings).await {
+            Ok(certificate) => certificate,
+            Err(err) => {
+                error!("webhook certificate error: {err}");
+                continue;
+            }
+        };
+        if certificate == current {
+            continue;
+        }
+
+        if certificate.ca_bundle != current.ca_bundle {
+            if let Err(err) = register_webhook(
+                client.clone(),
+                &app_state.settings(),
+                &certificate.ca_bundle,
+            )
+            .await
+            {
+                error!("webhook certificate error: {err}");
+                continue;
+            }
+        }
+
+        if let Err(err) = tls_config
+            .reload_from_pem(
+                certificate.certificate.clone().into_bytes(),
+                certificate.key.clone().into_bytes(),
+            )
+            .await
+        {
+            error!("webhook certificate error: {err}");
+            continue;
+        }
+
+        info!(
+            "webhook certificate rotated: {} {}",
+            settings.webhook_secret_name, certificate.not_after
+        );
+        current = certificate;
+    }
+
+    info!("webhook certificate rotation stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SettingsBuilder;
+
+    #[test]
+    fn webhook_certificate_secret_round_trip() {
+        let settings = SettingsBuilder::default()
+            .webhook_service_namespace("mutator")
+            .build()
+            .unwrap();
+        let names = dns_names(&settings);
+        assert_eq!(names[0], "k8s-consul-mutator-rs.mutator.svc");
+
+        let first =
+            WebhookCertificate::generate(names.clone(), Duration::days(1), Utc::now(), None)
+                .unwrap();
+        assert_eq!(first.ca_bundle, first.ca);
+
+        let secret = first.to_secret(ObjectMeta::default());
+        assert_eq!(WebhookCertificate::from_secret(&secret).unwrap(), first);
+
+        let second = WebhookCertificate::generate(
+            names,
+            Duration::days(1),
+            Utc::now(),
+            Some(first.ca.clone()),
+        )
+        .unwrap();
+        assert!(second.ca_bundle.starts_with(&second.ca));
+        assert!(second.ca_bundle.ends_with(&first.ca));
+    }
+
+    #[test]
+    fn webhook_configuration_selectors() {
+        let settings = SettingsBuilder::default()
+            .excluded_namespaces(vec!["kube-system".to_string()])
+            .namespace_label_selector("k8s-consul-mutator-rs=enabled")
+            .label_selector("tier!=cache")
+            .build()
+            .unwrap();
+
+        let configuration = webhook_configuration(&settings, Some("ca")).unwrap();
+        let webhook = &configuration.webhooks.unwrap()[0];
+
+        let namespace_requirements = webhook
+            .namespace_selector
+            .as_ref()
+            .and_then(|s| s.match_expressions.clone())
+            .unwrap();
+        assert_eq!(namespace_requirements.len(), 2);
+        assert_eq!(namespace_requirements[0].operator, "NotIn");
+        assert_eq!(namespace_requirements[1].key, "k8s-consul-mutator-rs");
+        assert_eq!(namespace_requirements[1].operator, "In");
+
+        let object_requirements = webhook
+            .object_selector
+            .as_ref()
+            .and_then(|s| s.match_expressions.clone())
+            .unwrap();
+        assert_eq!(object_requirements[0].operator, "NotIn");
+        assert_eq!(webhook.failure_policy.as_deref(), Some("Ignore"));
+    }
+}